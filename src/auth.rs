@@ -0,0 +1,183 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use hyper::HeaderMap;
+use hyper::header::AUTHORIZATION;
+use subtle::ConstantTimeEq;
+
+use crate::config::WebAuth;
+
+/// Decides whether a web request may access content
+pub trait AuthProvider: Send + Sync {
+    /// Check a request's headers for valid credentials
+    fn authorize(&self, headers: &HeaderMap) -> bool;
+
+    /// The `WWW-Authenticate` challenge sent along with a `401`
+    fn challenge(&self) -> &'static str;
+}
+
+/// Lets everyone in
+pub struct NoAuth;
+
+impl AuthProvider for NoAuth {
+    fn authorize(&self, _headers: &HeaderMap) -> bool {
+        true
+    }
+
+    fn challenge(&self) -> &'static str {
+        ""
+    }
+}
+
+/// HTTP Basic authentication with a single user
+pub struct BasicAuth {
+    credentials: String,
+}
+
+impl BasicAuth {
+    pub fn new(user: &str, password: &str) -> Self {
+        Self {
+            credentials: base64::encode(format!("{}:{}", user, password)),
+        }
+    }
+}
+
+impl AuthProvider for BasicAuth {
+    fn authorize(&self, headers: &HeaderMap) -> bool {
+        match authorization(headers, "Basic ") {
+            Some(given) => constant_time_eq(given, &self.credentials),
+            None => false,
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        "Basic realm=\"mirra\", charset=\"UTF-8\""
+    }
+}
+
+/// Bearer tokens from a static list
+pub struct BearerAuth {
+    tokens: Vec<String>,
+}
+
+impl BearerAuth {
+    pub fn new(tokens: Vec<String>) -> Self {
+        Self {
+            tokens,
+        }
+    }
+}
+
+impl AuthProvider for BearerAuth {
+    fn authorize(&self, headers: &HeaderMap) -> bool {
+        match authorization(headers, "Bearer ") {
+            // Check every token, so the timing doesn't reveal which one matched
+            Some(given) => self.tokens.iter()
+                .fold(false, |found, token| constant_time_eq(given, token) | found),
+            None => false,
+        }
+    }
+
+    fn challenge(&self) -> &'static str {
+        "Bearer realm=\"mirra\""
+    }
+}
+
+/// Accepts the credentials of either of two providers
+pub struct EitherAuth {
+    first: Box<dyn AuthProvider>,
+    second: Box<dyn AuthProvider>,
+}
+
+impl EitherAuth {
+    pub fn new(first: Box<dyn AuthProvider>, second: Box<dyn AuthProvider>) -> Self {
+        Self {
+            first,
+            second,
+        }
+    }
+}
+
+impl AuthProvider for EitherAuth {
+    fn authorize(&self, headers: &HeaderMap) -> bool {
+        self.first.authorize(headers) || self.second.authorize(headers)
+    }
+
+    fn challenge(&self) -> &'static str {
+        self.first.challenge()
+    }
+}
+
+/// Create the provider configured by [auth]
+pub fn make_provider(auth: &Option<WebAuth>) -> Box<dyn AuthProvider> {
+    match auth {
+        None => Box::new(NoAuth),
+        Some(WebAuth::Basic { user, password }) => Box::new(BasicAuth::new(user, password)),
+        Some(WebAuth::Bearer { tokens }) => Box::new(BearerAuth::new(tokens.clone())),
+    }
+}
+
+/// Create the provider for a share with its own [auth], which also lets in whoever has the [global] credentials
+pub fn make_share_provider(auth: &Option<WebAuth>, global: &Option<WebAuth>) -> Box<dyn AuthProvider> {
+    match global {
+        None => make_provider(auth),
+        Some(_) => Box::new(EitherAuth::new(make_provider(auth), make_provider(global))),
+    }
+}
+
+/// Get the credentials from an `Authorization` header using [scheme]
+fn authorization<'a>(headers: &'a HeaderMap, scheme: &str) -> Option<&'a str> {
+    headers.get(AUTHORIZATION)?
+        .to_str().ok()?
+        .strip_prefix(scheme)
+        .map(str::trim)
+}
+
+/// Compare two strings without leaking where they differ
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::HeaderMap;
+    use hyper::header::AUTHORIZATION;
+
+    use super::{AuthProvider, BasicAuth, BearerAuth, EitherAuth};
+
+    fn headers(authorization: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, authorization.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn bearer_accepts_any_listed_token() {
+        let auth = BearerAuth::new(vec!["token1".to_string(), "token2".to_string()]);
+        assert!(auth.authorize(&headers("Bearer token1")));
+        assert!(auth.authorize(&headers("Bearer token2")));
+    }
+
+    #[test]
+    fn bearer_rejects_anything_else() {
+        let auth = BearerAuth::new(vec!["token1".to_string()]);
+        assert!(!auth.authorize(&headers("Bearer token2")));
+        assert!(!auth.authorize(&headers("Bearer token")));
+        assert!(!auth.authorize(&headers("Bearer token12")));
+        assert!(!auth.authorize(&headers("Basic token1")));
+        assert!(!auth.authorize(&HeaderMap::new()));
+        // No tokens means nobody gets in
+        assert!(!BearerAuth::new(Vec::new()).authorize(&headers("Bearer ")));
+    }
+
+    #[test]
+    fn either_accepts_both_credentials() {
+        let auth = EitherAuth::new(Box::new(BasicAuth::new("me", "secret")), Box::new(BearerAuth::new(vec!["token".to_string()])));
+        assert!(auth.authorize(&headers(&format!("Basic {}", base64::encode("me:secret")))));
+        assert!(auth.authorize(&headers("Bearer token")));
+        assert!(!auth.authorize(&headers("Bearer secret")));
+    }
+}
@@ -5,30 +5,89 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use std::collections::HashMap;
-use std::env;
 use std::io::{Error, ErrorKind, Result};
-use std::path::{Path, PathBuf};
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::{Arc, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use tokio::fs;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::lookup_host;
+use tokio::io::AsyncWriteExt;
+use log::{info, warn};
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use tokio::sync::watch;
 use toml::Value;
 use toml::value::Table;
+use toml_edit::{Document, Item};
 
-use crate::util::{simple_input, simple_input_default};
+use crate::mux::{DEFAULT_CONNECT_RETRIES, DEFAULT_CONNECT_TIMEOUT, set_connect_limits};
+use crate::util::{DEFAULT_BUFFER_SIZE, expand_glob, expand_path, format_address, HashAlgo, is_glob, MAX_BUFFER_SIZE, MIN_BUFFER_SIZE, mirra_dir, set_buffer_size, Shutdown, shutdown_requested, simple_input, simple_input_default};
 
-#[derive(Debug)]
-/// Registers a root-only path to be synced over the network with nodes
+#[derive(Debug, Clone)]
+/// Registers root-only paths to be synced over the network with nodes
 pub struct RootShare {
-    pub path: String,
+    /// Directories whose contents are merged into the module, files may only be in one of them
+    pub paths: Vec<String>,
+    /// Base URL of a CDN that file downloads are redirected to
+    pub cdn_base: Option<String>,
+    /// How many web downloads may run at the same time
+    pub max_downloads: Option<usize>,
+    /// Authentication required to access this share on the web
+    pub auth: Option<WebAuth>,
+    /// Whether the web server lists and serves this share
+    pub web: bool,
+    /// Files larger than this many bytes aren't synced
+    pub max_file_size: Option<u64>,
+    /// Whether gzipped copies of compressible files are kept next to them, to serve them on the web
+    pub precompress: bool,
+    /// What the module contains, shown on the web
+    pub description: Option<String>,
+    /// The glob this share was expanded from, if its path in Mirra.toml is one
+    pub glob: Option<ShareGlob>,
 }
 
 #[derive(Debug, Clone)]
+/// A share whose path is a glob, which is expanded into one share per matching directory
+pub struct ShareGlob {
+    /// The Mirra.toml entry the glob is in
+    pub entry: String,
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 /// Registers a node that syncs from [address]:[port] into [path]
 pub struct RootSync {
     pub address: String,
     pub port: u16,
     pub path: String,
+    /// File containing the only hashes the node accepts, one per line
+    pub allowlist: Option<String>,
+    /// Whether the web server lists and serves this sync
+    pub web: bool,
+    /// How fast files may be received over this sync's connection
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// How many files are received at once during full syncs
+    pub sync_concurrency: usize,
+    /// Files larger than this many bytes are refused
+    pub max_file_size: Option<u64>,
+    /// Whether the module may be pushed by a root that connects to this mirra
+    pub accept_push: bool,
+    /// Whether received files are made read-only and local changes to the module are undone
+    pub read_only: bool,
+    /// What the module contains, shown on the web
+    pub description: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+/// Authentication the web server requires before serving anything
+pub enum WebAuth {
+    /// HTTP Basic authentication with a single user
+    Basic { user: String, password: String },
+    /// Bearer tokens, any of which grants access
+    Bearer { tokens: Vec<String> },
 }
 
 #[derive(Debug)]
@@ -43,24 +102,172 @@ pub enum Root {
 pub struct Config {
     pub name: String,
     pub port: u16,
+    /// Address the sync server binds to, dual-stack if unset
+    pub bind: Option<IpAddr>,
+    /// Address the web server binds to, all IPv4 interfaces if unset
+    pub web_bind: Option<IpAddr>,
+    /// Whether the web server is served on the sync server's port instead of its own
+    pub shared_port: bool,
+    /// Address `/metrics` is served on, `127.0.0.1:9107` if unset
+    pub metrics_bind: Option<SocketAddr>,
+    /// Size of newly generated keys in bits
+    pub key_bits: Option<usize>,
+    /// Algorithm used to hash files before sending them
+    pub hash_algo: HashAlgo,
+    /// Connections the sync server handles at once, unlimited if unset
+    pub max_connections: Option<usize>,
+    /// New connections the sync server accepts from a single address per minute, unlimited if unset
+    pub max_connections_per_minute: Option<u32>,
+    /// Seconds a node may go without answering heartbeats before its connection is closed
+    pub idle_timeout: Option<u64>,
+    /// Seconds connecting to a remote mirra may take, [DEFAULT_CONNECT_TIMEOUT] if unset
+    pub connect_timeout: Option<u64>,
+    /// How often connecting to a remote mirra is tried again before giving up, [DEFAULT_CONNECT_RETRIES] if unset
+    pub connect_retries: Option<u32>,
+    /// Bytes files are read and written in at once, [DEFAULT_BUFFER_SIZE] if unset
+    pub buffer_size: Option<usize>,
+    pub web_auth: Option<WebAuth>,
+    /// Page layout the web server uses instead of the embedded one
+    pub web_template: Option<String>,
+    /// Stylesheet the web server uses instead of the embedded one
+    pub web_style: Option<String>,
+    /// Where the web server logs requests, either `stdout` or a file, nowhere if unset
+    pub access_log: Option<String>,
+    /// Directory relative share and sync paths are inside of, the working directory if unset
+    pub base_dir: Option<String>,
     pub shares: HashMap<String, RootShare>,
     pub syncs: HashMap<String, RootSync>,
 }
 
-/// Create a .mirra directory and .mirra/Mirra.toml file if they don't exist
-pub async fn setup_config(into: PathBuf) -> Result<Config> {
+/// Receiving end of the config, which is updated whenever Mirra.toml changes
+pub type ConfigWatch = watch::Receiver<Arc<Config>>;
+
+/// Get the paths of [module] if it is shared or synced, shares take precedence
+pub fn module_paths(config: &Config, module: &str) -> Option<Vec<String>> {
+    if let Some(share) = config.shares.get(module) {
+        Some(share.paths.clone())
+    } else {
+        config.syncs.get(module).map(|sync| vec![sync.path.clone()])
+    }
+}
+
+/// Put the paths of [root] inside of [base_dir] unless they are absolute
+fn in_base_dir(base_dir: &Option<String>, root: Option<Root>) -> Option<Root> {
+    let base = match base_dir {
+        Some(base) => Path::new(base),
+        None => return root,
+    };
+    let resolve = |path: String| if Path::new(&path).is_relative() {
+        base.join(path).to_string_lossy().to_string()
+    } else {
+        path
+    };
+    match root? {
+        Root::Share(share) => Some(Root::Share(RootShare { paths: share.paths.into_iter().map(resolve).collect(), ..share })),
+        Root::Sync(sync) => Some(Root::Sync(RootSync { path: resolve(sync.path), ..sync })),
+    }
+}
+
+/// Undo [in_base_dir] for [path], so it's written back the way it was given
+fn outside_base_dir(base_dir: &Option<String>, path: String) -> String {
+    match base_dir.as_ref().and_then(|base| Path::new(&path).strip_prefix(base).ok()) {
+        Some(relative) => relative.to_string_lossy().to_string(),
+        None => path,
+    }
+}
+
+/// Get the largest file [module] syncs, a synced module that is passed on keeps the limit it was received with
+pub fn module_max_file_size(config: &Config, module: &str) -> Option<u64> {
+    if let Some(share) = config.shares.get(module) {
+        share.max_file_size
+    } else {
+        config.syncs.get(module).and_then(|sync| sync.max_file_size)
+    }
+}
+
+/// Describe every share whose path isn't a directory
+fn missing_share_paths(config: &Config) -> Vec<String> {
+    config.shares.iter()
+        .flat_map(|(module, share)| share.paths.iter().map(move |path| (module, path)))
+        .filter(|(_, path)| !Path::new(path).is_dir())
+        .map(|(module, path)| format!("The path of share {} doesn't exist or isn't a directory: {}", module, path))
+        .collect()
+}
+
+/// Warn about shares whose path isn't a directory, nodes asking for them only get an error
+pub fn check_share_paths(config: &Config) {
+    for warning in missing_share_paths(config) {
+        warn!("{}", warning);
+    }
+}
+
+/// Print every problem with Mirra.toml, returning whether it can be loaded
+/// Missing share paths, sync addresses that can't be resolved and missing web templates are only warned about
+pub async fn check_config() -> Result<bool> {
+    let mirra_file = mirra_dir().join("Mirra.toml");
+    if !mirra_file.exists() {
+        return Err(Error::new(ErrorKind::NotFound, format!("There is no {}", mirra_file.display())));
+    }
+
+    let mut errors = Vec::new();
+    let config = parse_config(&fs::read_to_string(&mirra_file).await?, &mut errors).await;
+
+    let mut warnings = missing_share_paths(&config);
+    for (module, sync) in &config.syncs {
+        let addr = format_address(&sync.address, sync.port);
+        let resolved = lookup_host(&addr).await.map(|mut addrs| addrs.next().is_some());
+        if !resolved.unwrap_or(false) {
+            warnings.push(format!("The address of sync {} can't be resolved: {}", module, addr));
+        }
+    }
+    for (key, path) in [("web_template", &config.web_template), ("web_style", &config.web_style)] {
+        if let Some(path) = path.as_ref().filter(|path| !Path::new(path).is_file()) {
+            warnings.push(format!("The {} doesn't exist, the embedded one is used instead: {}", key, path));
+        }
+    }
+
+    // Printed directly, so the output reads like a checklist
+    for error in &errors {
+        println!("error: {}", error);
+    }
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    println!("Mirra.toml has {} error(s) and {} warning(s)", errors.len(), warnings.len());
+    Ok(errors.is_empty())
+}
+
+/// Create the [mirra_dir] and the Mirra.toml in it if they don't exist
+pub async fn setup_config() -> Result<Config> {
     // Get basic info from user
     let name: String = simple_input("mirra name?")?;
     let port: u16 = simple_input_default("mirra port?", 6007)?;
 
     // Create config dir if it doesn't exist
-    if !into.join(".mirra").exists() {
-        fs::create_dir(into.join(".mirra")).await?;
+    if !mirra_dir().exists() {
+        fs::create_dir_all(mirra_dir()).await?;
     }
 
     let config = Config {
         name,
         port,
+        bind: None,
+        web_bind: None,
+        shared_port: false,
+        metrics_bind: None,
+        key_bits: None,
+        hash_algo: HashAlgo::Blake3,
+        max_connections: None,
+        max_connections_per_minute: None,
+        idle_timeout: None,
+        connect_timeout: None,
+        connect_retries: None,
+        buffer_size: None,
+        web_auth: None,
+        web_template: None,
+        web_style: None,
+        access_log: None,
+        base_dir: None,
         shares: HashMap::new(),
         syncs: HashMap::new(),
     };
@@ -70,142 +277,721 @@ pub async fn setup_config(into: PathBuf) -> Result<Config> {
     toml_data.insert("name".to_string(), config.name.clone().into());
     toml_data.insert("port".to_string(), toml::Value::Integer(config.port as i64));
 
-    // [setup_config] is only called when .mirra/Mirra.toml doesn't exist so this is save
+    // [setup_config] is only called when Mirra.toml doesn't exist so this is save
     // Save TOML config data to disk
-    let mut config_file = File::create(into.join(".mirra/Mirra.toml")).await?;
+    let mut config_file = File::create(mirra_dir().join("Mirra.toml")).await?;
     config_file.write_all(toml::to_string(&toml_data).unwrap().as_bytes()).await?;
 
     Ok(config)
 }
 
-/// Parse a TOML table from a Mirra.toml config file
-async fn parse_table(table: &Table, name: String) -> Result<Root> {
+/// The name of [key] in [section] the way it is written in Mirra.toml, top-level keys have an empty [section]
+fn key_name(section: &str, key: &str) -> String {
+    if section.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", section, key)
+    }
+}
+
+/// [kind] with its indefinite article, e.g. "an integer"
+fn with_article(kind: &str) -> String {
+    if kind.starts_with(['a', 'e', 'i', 'o', 'u']) {
+        format!("an {}", kind)
+    } else {
+        format!("a {}", kind)
+    }
+}
+
+/// Get [key] from [table] if it is set, noting an error in [errors] unless it is of type [expected]
+fn get_typed<'a>(table: &'a Table, section: &str, key: &str, expected: &str, errors: &mut Vec<String>) -> Option<&'a Value> {
+    let value = table.get(key)?;
+    if value.type_str() == expected {
+        Some(value)
+    } else {
+        errors.push(format!("{} must be {}, not {}", key_name(section, key), with_article(expected), with_article(value.type_str())));
+        None
+    }
+}
+
+/// Get the port [key] from [table] if it is set, noting an error in [errors] unless it is a valid port
+fn get_port(table: &Table, section: &str, key: &str, errors: &mut Vec<String>) -> Option<u16> {
+    let port = get_typed(table, section, key, "integer", errors)?.as_integer().unwrap();
+    let res = u16::try_from(port).ok();
+    if res.is_none() {
+        errors.push(format!("{} must be between 0 and 65535, not {}", key_name(section, key), port));
+    }
+    res
+}
+
+/// Get the address [key] from [table] if it is set, noting an error in [errors] unless it is an IP address
+fn get_ip(table: &Table, section: &str, key: &str, errors: &mut Vec<String>) -> Option<IpAddr> {
+    let addr = get_typed(table, section, key, "string", errors)?.as_str().unwrap();
+    let res = addr.parse::<IpAddr>().ok();
+    if res.is_none() {
+        errors.push(format!("{} must be an IP address, not '{}'", key_name(section, key), addr));
+    }
+    res
+}
+
+/// Get the IP address and port [key] from [table] if it is set
+fn get_socket_addr(table: &Table, section: &str, key: &str, errors: &mut Vec<String>) -> Option<SocketAddr> {
+    let addr = get_typed(table, section, key, "string", errors)?.as_str().unwrap();
+    let res = addr.parse::<SocketAddr>().ok();
+    if res.is_none() {
+        errors.push(format!("{} must be an IP address and a port, not '{}'", key_name(section, key), addr));
+    }
+    res
+}
+
+/// Get the path [key] from [table] if it is set, with `~` and environment variables expanded
+fn get_path(table: &Table, section: &str, key: &str, errors: &mut Vec<String>) -> Option<String> {
+    let path = get_typed(table, section, key, "string", errors)?.as_str().unwrap();
+    match expand_path(path) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            errors.push(format!("{}: {}", key_name(section, key), e));
+            None
+        }
+    }
+}
+
+/// Get the paths [key] from [table] if it is set, either a single path or a list of them
+fn get_paths(table: &Table, section: &str, key: &str, errors: &mut Vec<String>) -> Option<Vec<String>> {
+    let value = table.get(key)?;
+    let paths: Vec<&Value> = match value.as_array() {
+        Some(paths) if !paths.is_empty() => paths.iter().collect(),
+        _ => vec![value],
+    };
+    if paths.iter().any(|path| !path.is_str()) {
+        errors.push(format!("{} must be a path or a list of paths", key_name(section, key)));
+        return None;
+    }
+
+    let mut res = Vec::with_capacity(paths.len());
+    for path in paths {
+        match expand_path(path.as_str().unwrap()) {
+            Ok(path) => res.push(path),
+            Err(e) => errors.push(format!("{}: {}", key_name(section, key), e)),
+        }
+    }
+    Some(res)
+}
+
+/// Parse a TOML table from a Mirra.toml config file, noting every problem with it in [errors]
+async fn parse_table(table: &Table, name: String, errors: &mut Vec<String>) -> Option<Root> {
+    let before = errors.len();
     // Syncs need an address and a port but not a path
     if table.contains_key("address") && table.contains_key("port") {
         // Get values
-        let address = table.get("address").unwrap();
-        let port = table.get("port").unwrap();
-        let p = table.get("path");
-
-        // Check value validity
-        if !address.is_str() || !port.is_integer() || (p.is_some() && !p.unwrap().is_str()) {
-            Err(Error::new(ErrorKind::InvalidData, "Config file is corrupted"))
-        } else {
-            // Glorified custom unwrap_or
-            let path: String = if p.is_some() {
-                p.unwrap().as_str().unwrap().to_string()
-            } else {
-                name
-            };
-            // Return sync object
-            Ok(Root::Sync(RootSync {
-                address: address.as_str().unwrap().to_string(),
-                port: port.as_integer().unwrap() as u16,
-                path,
-            }))
+        let address = get_typed(table, &name, "address", "string", errors);
+        let port = get_port(table, &name, "port", errors);
+        let path = get_path(table, &name, "path", errors);
+        let allowlist = get_path(table, &name, "allowlist", errors);
+        let web = get_typed(table, &name, "web", "boolean", errors);
+        let max_download = get_typed(table, &name, "max_download_bytes_per_sec", "integer", errors);
+        let concurrency = get_typed(table, &name, "sync_concurrency", "integer", errors);
+        let max_file_size = get_typed(table, &name, "max_file_size", "integer", errors);
+        let accept_push = get_typed(table, &name, "accept_push", "boolean", errors);
+        let read_only = get_typed(table, &name, "read_only", "boolean", errors);
+        let description = get_typed(table, &name, "description", "string", errors);
+
+        // Any of them was invalid
+        if errors.len() != before {
+            return None;
         }
+        // Return sync object
+        Some(Root::Sync(RootSync {
+            address: address.unwrap().as_str().unwrap().to_string(),
+            port: port.unwrap(),
+            path: path.unwrap_or(name),
+            allowlist,
+            web: web.map(|w| w.as_bool().unwrap()).unwrap_or(true),
+            max_download_bytes_per_sec: max_download.map(|m| m.as_integer().unwrap().max(1) as u64),
+            sync_concurrency: concurrency.map(|c| c.as_integer().unwrap().max(1) as usize).unwrap_or(1),
+            max_file_size: max_file_size.map(|m| m.as_integer().unwrap().max(0) as u64),
+            accept_push: accept_push.map(|a| a.as_bool().unwrap()).unwrap_or(false),
+            read_only: read_only.map(|r| r.as_bool().unwrap()).unwrap_or(false),
+            description: description.map(|d| d.as_str().unwrap().to_string()),
+        }))
     // Shares need a path for now
     } else if table.contains_key("path") {
-        // Get value
-        let path = table.get("path").unwrap();
-
-        // Check value validity
-        if !path.is_str() {
-            Err(Error::new(ErrorKind::InvalidData, "Config file is corrupted"))
-        } else {
-            // Return share object
-            Ok(Root::Share(RootShare {
-                path: path.as_str().unwrap().to_string()
-            }))
+        // Get values
+        let paths = get_paths(table, &name, "path", errors);
+        let cdn_base = get_typed(table, &name, "cdn_base", "string", errors);
+        let max_downloads = get_typed(table, &name, "max_downloads", "integer", errors);
+        let auth = get_typed(table, &name, "auth", "table", errors)
+            .and_then(|auth| parse_web_auth(auth.as_table().unwrap(), &key_name(&name, "auth"), errors));
+        let web = get_typed(table, &name, "web", "boolean", errors);
+        let max_file_size = get_typed(table, &name, "max_file_size", "integer", errors);
+        let precompress = get_typed(table, &name, "precompress", "boolean", errors);
+        let description = get_typed(table, &name, "description", "string", errors);
+
+        // Any of them was invalid
+        if errors.len() != before {
+            return None;
         }
+        // Return share object
+        Some(Root::Share(RootShare {
+            paths: paths.unwrap(),
+            cdn_base: cdn_base.map(|c| c.as_str().unwrap().to_string()),
+            max_downloads: max_downloads.map(|m| m.as_integer().unwrap().max(1) as usize),
+            auth,
+            web: web.map(|w| w.as_bool().unwrap()).unwrap_or(true),
+            max_file_size: max_file_size.map(|m| m.as_integer().unwrap().max(0) as u64),
+            precompress: precompress.map(|p| p.as_bool().unwrap()).unwrap_or(false),
+            description: description.map(|d| d.as_str().unwrap().to_string()),
+            glob: None,
+        }))
     // Tables that contain none of these, e.g. empty tables are invalid
     } else {
-        Err(Error::new(ErrorKind::InvalidData, "Config file is corrupted"))
+        errors.push(format!("{} needs either a path, or an address and a port", name));
+        None
     }
 }
 
-/// Load a Mirra.toml configuration file
-async fn load_config(from: &Path) -> Result<Config> {
-    // Config file always exist when [load_config] is called
-    // Load raw config data from disk
-    let mut mirra_file = File::open(from).await?;
-    let mut config_raw = String::with_capacity(128);
-    mirra_file.read_to_string(&mut config_raw).await?;
-
-    let c = config_raw.as_str().parse::<toml::Value>();
-    if c.is_err() || !c.as_ref().unwrap().is_table() {
-        return Err(Error::new(ErrorKind::InvalidData, "Config file is corrupted"));
+/// Parse a `web_auth = { kind = "...", ... }` or a share's `auth = { ... }` table called [name]
+fn parse_web_auth(table: &Table, name: &str, errors: &mut Vec<String>) -> Option<WebAuth> {
+    let kind = table.get("kind").and_then(Value::as_str);
+    match kind {
+        Some("basic") => {
+            let user = get_typed(table, name, "user", "string", errors);
+            let password = get_typed(table, name, "password", "string", errors);
+            if user.is_none() || password.is_none() {
+                if !table.contains_key("user") || !table.contains_key("password") {
+                    errors.push(format!("{} needs a user and a password", name));
+                }
+                return None;
+            }
+            Some(WebAuth::Basic {
+                user: user.unwrap().as_str().unwrap().to_string(),
+                password: password.unwrap().as_str().unwrap().to_string(),
+            })
+        }
+        Some("bearer") => {
+            let tokens = table.get("tokens").and_then(Value::as_array);
+            if tokens.is_none() || tokens.unwrap().iter().any(|t| !t.is_str()) {
+                errors.push(format!("{} must be a list of strings", key_name(name, "tokens")));
+                return None;
+            }
+            Some(WebAuth::Bearer {
+                tokens: tokens.unwrap().iter().map(|t| t.as_str().unwrap().to_string()).collect(),
+            })
+        }
+        _ => {
+            errors.push(format!("{} must be \"basic\" or \"bearer\"", key_name(name, "kind")));
+            None
+        }
     }
+}
 
-    // Create temporary value, because tables are always borrows
-    let config_value = c.unwrap();
-    let config = config_value.as_table().unwrap();
+/// Turn a [WebAuth] back into a TOML table
+fn web_auth_to_table(auth: WebAuth) -> Table {
+    match auth {
+        WebAuth::Basic { user, password } => Table::from_iter([
+            ("kind".to_string(), Value::String("basic".to_string())),
+            ("user".to_string(), Value::String(user)),
+            ("password".to_string(), Value::String(password)),
+        ]),
+        WebAuth::Bearer { tokens } => Table::from_iter([
+            ("kind".to_string(), Value::String("bearer".to_string())),
+            ("tokens".to_string(), Value::Array(tokens.into_iter().map(Value::String).collect())),
+        ]),
+    }
+}
 
+/// Parse the contents of a Mirra.toml configuration file, noting every problem with it in [errors]
+/// Invalid values are left at their defaults
+pub async fn parse_config(config_raw: &str, errors: &mut Vec<String>) -> Config {
     // Default values
     let mut name = "no name".to_string();
     let mut port = 6007u16;
+    let mut bind = None;
+    let mut web_bind = None;
+    let mut shared_port = false;
+    let mut metrics_bind = None;
+    let mut key_bits = None;
+    let mut hash_algo = HashAlgo::Blake3;
+    let mut max_connections = None;
+    let mut max_connections_per_minute = None;
+    let mut idle_timeout = None;
+    let mut connect_timeout = None;
+    let mut connect_retries = None;
+    let mut buffer_size = None;
+    let mut web_auth = None;
+    let mut web_template = None;
+    let mut web_style = None;
+    let mut access_log = None;
+    let mut base_dir = None;
     let mut syncs = HashMap::new();
     let mut shares = HashMap::new();
+    // Shares whose path is a glob, expanded once every other module is known
+    let mut globs = Vec::new();
+
+    let c = config_raw.parse::<toml::Value>();
+    if let Err(e) = c {
+        errors.push(format!("Mirra.toml isn't valid TOML: {}", e));
+    } else if let Some(config) = c.as_ref().unwrap().as_table() {
+        if let Some(n) = get_typed(config, "", "name", "string", errors) {
+            name = n.as_str().unwrap().to_string();
+        }
+        if let Some(p) = get_port(config, "", "port", errors) {
+            port = p;
+        }
+        bind = get_ip(config, "", "bind", errors);
+        web_bind = get_ip(config, "", "web_bind", errors);
+        if let Some(shared) = get_typed(config, "", "shared_port", "boolean", errors) {
+            shared_port = shared.as_bool().unwrap();
+        }
+        metrics_bind = get_socket_addr(config, "", "metrics_bind", errors);
+        key_bits = get_typed(config, "", "key_bits", "integer", errors)
+            .map(|k| k.as_integer().unwrap().max(0) as usize);
+        if let Some(algo) = get_typed(config, "", "hash_algo", "string", errors) {
+            match algo.as_str().unwrap().parse() {
+                Ok(algo) => hash_algo = algo,
+                Err(e) => errors.push(format!("hash_algo: {}", e)),
+            }
+        }
+        max_connections = get_typed(config, "", "max_connections", "integer", errors)
+            .map(|m| m.as_integer().unwrap().max(0) as usize);
+        max_connections_per_minute = get_typed(config, "", "max_connections_per_minute", "integer", errors)
+            .map(|m| m.as_integer().unwrap().max(0) as u32);
+        idle_timeout = get_typed(config, "", "idle_timeout", "integer", errors)
+            .map(|t| t.as_integer().unwrap().max(1) as u64);
+        connect_timeout = get_typed(config, "", "connect_timeout", "integer", errors)
+            .map(|t| t.as_integer().unwrap().max(1) as u64);
+        connect_retries = get_typed(config, "", "connect_retries", "integer", errors)
+            .map(|r| r.as_integer().unwrap().clamp(0, u32::MAX as i64) as u32);
+        if let Some(size) = get_typed(config, "", "buffer_size", "integer", errors) {
+            let size = size.as_integer().unwrap();
+            if (MIN_BUFFER_SIZE as i64..=MAX_BUFFER_SIZE as i64).contains(&size) {
+                buffer_size = Some(size as usize);
+            } else {
+                errors.push(format!("buffer_size must be between {} and {}, not {}", MIN_BUFFER_SIZE, MAX_BUFFER_SIZE, size));
+            }
+        }
+        web_auth = get_typed(config, "", "web_auth", "table", errors)
+            .and_then(|auth| parse_web_auth(auth.as_table().unwrap(), "web_auth", errors));
+        web_template = get_path(config, "", "web_template", errors);
+        web_style = get_path(config, "", "web_style", errors);
+        access_log = get_path(config, "", "access_log", errors);
+        base_dir = get_path(config, "", "base_dir", errors);
 
-    for value in config {
-        // Any `name = "..."`
-        if value.0 == &"name".to_string() && value.1.is_str() {
-            name = value.1.as_str().unwrap().to_string();
-        // Any `port = xxxx`
-        } else if value.0 == &"port".to_string() && value.1.is_integer() {
-            port = value.1.as_integer().unwrap() as u16;
         // Any `[table_name]\nxxx = xxx`
-        } else if value.1.is_table() {
-            let table = value.1.as_table().unwrap();
-            let root = parse_table(table, value.0.clone()).await?;
+        for (module, value) in config {
+            if module == "web_auth" || !value.is_table() {
+                continue;
+            }
+            let root = parse_table(value.as_table().unwrap(), module.clone(), errors).await;
+            match in_base_dir(&base_dir, root) {
+                Some(Root::Share(share)) if share.paths.iter().any(|path| is_glob(path)) => {
+                    if share.paths.len() == 1 {
+                        globs.push((module.clone(), share));
+                    } else {
+                        errors.push(format!("{} can only be a glob if it is the share's only path", key_name(module, "path")));
+                    }
+                }
+                Some(Root::Share(share)) => { shares.insert(module.clone(), share); }
+                Some(Root::Sync(sync)) => { syncs.insert(module.clone(), sync); }
+                None => {}
+            }
+        }
 
-            match root {
-                Root::Share(share) => { shares.insert(value.0.clone(), share); }
-                Root::Sync(sync) => { syncs.insert(value.0.clone(), sync); }
+        // Every directory a glob matches becomes a share named after it
+        for (entry, share) in globs {
+            let pattern = share.paths[0].clone();
+            for dir in expand_glob(&pattern) {
+                // Patterns like `data/*/..` match directories that can't name a module
+                let name = match dir.file_name() {
+                    Some(name) => name.to_string_lossy().to_string(),
+                    None => {
+                        errors.push(format!("{} matches {}, which has no name to use for a module", key_name(&entry, "path"), dir.display()));
+                        continue;
+                    }
+                };
+                if shares.contains_key(&name) || syncs.contains_key(&name) {
+                    errors.push(format!("{} matches {}, but there already is a module named {}", key_name(&entry, "path"), dir.display(), name));
+                    continue;
+                }
+                shares.insert(name, RootShare {
+                    paths: vec![dir.to_string_lossy().to_string()],
+                    glob: Some(ShareGlob { entry: entry.clone(), pattern: pattern.clone() }),
+                    ..share.clone()
+                });
             }
         }
     }
 
-    Ok(Config {
+    Config {
         name,
         port,
+        bind,
+        web_bind,
+        shared_port,
+        metrics_bind,
+        key_bits,
+        hash_algo,
+        max_connections,
+        max_connections_per_minute,
+        idle_timeout,
+        connect_timeout,
+        connect_retries,
+        buffer_size,
+        web_auth,
+        web_template,
+        web_style,
+        access_log,
+        base_dir,
         shares,
         syncs,
-    })
+    }
+}
+
+/// Load a Mirra.toml configuration file, failing with every problem it has
+async fn load_config(from: &Path) -> Result<Config> {
+    // Config file always exist when [load_config] is called
+    // Load raw config data from disk
+    let config_raw = fs::read_to_string(from).await?;
+
+    let mut errors = Vec::new();
+    let config = parse_config(&config_raw, &mut errors).await;
+    if !errors.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Mirra.toml is invalid:\n  {}", errors.join("\n  "))));
+    }
+    Ok(config)
+}
+
+/// Whether the last time Mirra.toml changed, it couldn't be loaded
+static RELOAD_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the running config is outdated, because Mirra.toml couldn't be reloaded the last time it changed
+pub fn reload_failed() -> bool {
+    RELOAD_FAILED.load(Ordering::Relaxed)
+}
+
+/// Reload Mirra.toml whenever it changes on disk and publish every valid version to [config]
+pub async fn watch_config(config: watch::Sender<Arc<Config>>, mut current: Arc<Config>, mut shutdown: Shutdown) -> Result<()> {
+    let mirra_file = mirra_dir().join("Mirra.toml");
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, Duration::from_secs(1))
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    // Watch the directory, because editors tend to replace files instead of writing to them
+    // note: this creates a new thread
+    watcher.watch(mirra_dir(), RecursiveMode::NonRecursive)
+        .map_err(|e| Error::new(ErrorKind::Other, format!("can't watch {}: {}", mirra_dir().display(), e)))?;
+
+    loop {
+        let mut changed = false;
+        while let Ok(event) = rx.try_recv() {
+            changed |= match event {
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Rename(_, path) =>
+                    path.ends_with("Mirra.toml"),
+                _ => false,
+            };
+        }
+
+        if changed {
+            match load_config(&mirra_file).await {
+                Ok(new) => {
+                    if new.port != current.port || new.bind != current.bind || new.web_bind != current.web_bind ||
+                        new.shared_port != current.shared_port || new.metrics_bind != current.metrics_bind {
+                        warn!("Changing the port or a bind address only takes effect after a restart");
+                    }
+                    if new.web_template != current.web_template || new.web_style != current.web_style {
+                        warn!("Changing the web template or stylesheet only takes effect after a restart");
+                    }
+                    set_buffer_size(new.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE));
+                    set_connect_limits(new.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT), new.connect_retries.unwrap_or(DEFAULT_CONNECT_RETRIES));
+                    info!("Reloaded Mirra.toml");
+                    RELOAD_FAILED.store(false, Ordering::Relaxed);
+                    check_share_paths(&new);
+                    current = Arc::new(new);
+                    if config.send(current.clone()).is_err() {
+                        // Nobody is interested in the config anymore
+                        return Ok(());
+                    }
+                }
+                Err(e) => {
+                    warn!("Keeping the previous config, failed to reload Mirra.toml: {}", e);
+                    RELOAD_FAILED.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(500)) => {}
+            _ = shutdown_requested(&mut shutdown) => return Ok(()),
+        }
+    }
 }
 
 /// Abstraction for loading/creating the configuration file
 pub async fn get_config() -> Result<Config> {
-    let mirra_file = Path::new(".mirra/Mirra.toml");
+    let mirra_file = mirra_dir().join("Mirra.toml");
     // Check if config exists, else create
     if !mirra_file.exists() {
-        setup_config(env::current_dir()?).await
+        setup_config().await
     } else {
-        load_config(mirra_file).await
+        load_config(&mirra_file).await
+    }
+}
+
+/// Turn a [RootShare] back into a TOML table
+pub fn share_to_table(share: RootShare) -> Table {
+    // A single path is written as a string, just like it used to be
+    let path = if share.paths.len() == 1 {
+        Value::String(share.paths.into_iter().next().unwrap())
+    } else {
+        Value::Array(share.paths.into_iter().map(Value::String).collect())
+    };
+    let mut table = Table::from_iter([
+        ("path".to_string(), path)
+    ].into_iter());
+    if let Some(cdn_base) = share.cdn_base {
+        table.insert("cdn_base".to_string(), Value::String(cdn_base));
+    }
+    if let Some(max_downloads) = share.max_downloads {
+        table.insert("max_downloads".to_string(), Value::Integer(max_downloads as i64));
+    }
+    if let Some(auth) = share.auth {
+        table.insert("auth".to_string(), Value::Table(web_auth_to_table(auth)));
+    }
+    if !share.web {
+        table.insert("web".to_string(), Value::Boolean(false));
+    }
+    if let Some(max_file_size) = share.max_file_size {
+        table.insert("max_file_size".to_string(), Value::Integer(max_file_size as i64));
+    }
+    if share.precompress {
+        table.insert("precompress".to_string(), Value::Boolean(true));
+    }
+    if let Some(description) = share.description {
+        table.insert("description".to_string(), Value::String(description));
     }
+    table
+}
+
+/// Turn a [RootSync] back into a TOML table
+pub fn sync_to_table(sync: RootSync) -> Table {
+    let mut table = Table::from_iter([
+        ("address".to_string(), Value::String(sync.address)),
+        ("port".to_string(), Value::Integer(sync.port as i64)),
+        ("path".to_string(), Value::String(sync.path))
+    ].into_iter());
+    if let Some(allowlist) = sync.allowlist {
+        table.insert("allowlist".to_string(), Value::String(allowlist));
+    }
+    if !sync.web {
+        table.insert("web".to_string(), Value::Boolean(false));
+    }
+    if let Some(max_download) = sync.max_download_bytes_per_sec {
+        table.insert("max_download_bytes_per_sec".to_string(), Value::Integer(max_download as i64));
+    }
+    if sync.sync_concurrency != 1 {
+        table.insert("sync_concurrency".to_string(), Value::Integer(sync.sync_concurrency as i64));
+    }
+    if let Some(max_file_size) = sync.max_file_size {
+        table.insert("max_file_size".to_string(), Value::Integer(max_file_size as i64));
+    }
+    if sync.accept_push {
+        table.insert("accept_push".to_string(), Value::Boolean(true));
+    }
+    if sync.read_only {
+        table.insert("read_only".to_string(), Value::Boolean(true));
+    }
+    if let Some(description) = sync.description {
+        table.insert("description".to_string(), Value::String(description));
+    }
+    table
+}
+
+/// Format a single `[name]` table the way it would appear in Mirra.toml
+pub fn format_entry(name: String, table: Table) -> String {
+    toml::to_string(&Table::from_iter([(name, Value::Table(table))])).unwrap()
 }
 
-pub async fn safe_config(into: PathBuf, config: Config) -> Result<()> {
+/// Turn a [Config] back into the TOML table it was loaded from
+fn config_to_table(config: Config) -> Table {
     let mut toml_data = toml::map::Map::new();
     toml_data.insert("name".to_string(), config.name.clone().into());
     toml_data.insert("port".to_string(), toml::Value::Integer(config.port as i64));
+    if let Some(bind) = config.bind {
+        toml_data.insert("bind".to_string(), Value::String(bind.to_string()));
+    }
+    if let Some(web_bind) = config.web_bind {
+        toml_data.insert("web_bind".to_string(), Value::String(web_bind.to_string()));
+    }
+    if config.shared_port {
+        toml_data.insert("shared_port".to_string(), Value::Boolean(true));
+    }
+    if let Some(metrics_bind) = config.metrics_bind {
+        toml_data.insert("metrics_bind".to_string(), Value::String(metrics_bind.to_string()));
+    }
+    if let Some(key_bits) = config.key_bits {
+        toml_data.insert("key_bits".to_string(), Value::Integer(key_bits as i64));
+    }
+    if config.hash_algo != HashAlgo::Blake3 {
+        toml_data.insert("hash_algo".to_string(), Value::String(config.hash_algo.name().to_string()));
+    }
+    if let Some(max_connections) = config.max_connections {
+        toml_data.insert("max_connections".to_string(), Value::Integer(max_connections as i64));
+    }
+    if let Some(max_connections_per_minute) = config.max_connections_per_minute {
+        toml_data.insert("max_connections_per_minute".to_string(), Value::Integer(max_connections_per_minute as i64));
+    }
+    if let Some(idle_timeout) = config.idle_timeout {
+        toml_data.insert("idle_timeout".to_string(), Value::Integer(idle_timeout as i64));
+    }
+    if let Some(connect_timeout) = config.connect_timeout {
+        toml_data.insert("connect_timeout".to_string(), Value::Integer(connect_timeout as i64));
+    }
+    if let Some(connect_retries) = config.connect_retries {
+        toml_data.insert("connect_retries".to_string(), Value::Integer(connect_retries as i64));
+    }
+    if let Some(buffer_size) = config.buffer_size {
+        toml_data.insert("buffer_size".to_string(), Value::Integer(buffer_size as i64));
+    }
+    if let Some(web_auth) = config.web_auth {
+        toml_data.insert("web_auth".to_string(), Value::Table(web_auth_to_table(web_auth)));
+    }
+    if let Some(web_template) = config.web_template {
+        toml_data.insert("web_template".to_string(), Value::String(web_template));
+    }
+    if let Some(web_style) = config.web_style {
+        toml_data.insert("web_style".to_string(), Value::String(web_style));
+    }
+    if let Some(access_log) = config.access_log {
+        toml_data.insert("access_log".to_string(), Value::String(access_log));
+    }
+    if let Some(base_dir) = &config.base_dir {
+        toml_data.insert("base_dir".to_string(), Value::String(base_dir.clone()));
+    }
 
-    for share in config.shares {
-        toml_data.insert(share.0, Value::Table(Table::from_iter([
-            ("path".to_string(), Value::String(share.1.path))
-        ].into_iter())));
+    // Paths are written the way they would be given, relative to `base_dir`
+    let base_dir = config.base_dir;
+    for (name, mut share) in config.shares {
+        share.paths = share.paths.into_iter().map(|path| outside_base_dir(&base_dir, path)).collect();
+        // Shares expanded from a glob are written back as the glob they came from
+        match share.glob.clone() {
+            Some(glob) => {
+                let mut table = share_to_table(share);
+                table.insert("path".to_string(), Value::String(outside_base_dir(&base_dir, glob.pattern)));
+                toml_data.insert(glob.entry, Value::Table(table));
+            }
+            None => { toml_data.insert(name, Value::Table(share_to_table(share))); }
+        }
     }
 
-    for sync in config.syncs {
-        toml_data.insert(sync.0, Value::Table(Table::from_iter([
-            ("address".to_string(), Value::String(sync.1.address)),
-            ("port".to_string(), Value::Integer(sync.1.port as i64)),
-            ("path".to_string(), Value::String(sync.1.path))
-        ].into_iter())));
+    for (name, mut sync) in config.syncs {
+        sync.path = outside_base_dir(&base_dir, sync.path);
+        toml_data.insert(name, Value::Table(sync_to_table(sync)));
     }
 
-    let mut config_file = File::create(into.join(".mirra/Mirra.toml")).await?;
-    config_file.write_all(toml::to_string(&toml_data).unwrap().as_bytes()).await?;
+    toml_data
+}
+
+/// Format [value] the way it would appear in Mirra.toml under [key]
+fn to_item(key: &str, value: &Value) -> Item {
+    let formatted = toml::to_string(&Table::from_iter([(key.to_string(), value.clone())])).unwrap();
+    let mut document = formatted.parse::<Document>().unwrap();
+    let mut item = document.as_table_mut().remove(key).unwrap();
+    move_to_end(&mut item);
+    item
+}
+
+/// Make new tables in [item] come after the ones that are already in the document
+fn move_to_end(item: &mut Item) {
+    if let Item::Table(table) = item {
+        table.set_position(usize::MAX);
+        table.decor_mut().set_prefix("\n");
+        for (_, child) in table.iter_mut() {
+            move_to_end(child);
+        }
+    }
+}
+
+/// Apply every difference between [previous] and [current] to [document], leaving anything else as it is
+fn update_table(document: &mut toml_edit::Table, previous: &Table, current: &Table) {
+    for key in previous.keys() {
+        if !current.contains_key(key) {
+            document.remove(key);
+        }
+    }
+
+    let empty = Table::new();
+    for (key, value) in current {
+        let old = previous.get(key);
+        if old == Some(value) {
+            continue;
+        }
+
+        match (document.get_mut(key), value) {
+            // Keep the table's header and comments, only changing the keys that differ
+            (Some(Item::Table(table)), Value::Table(new)) =>
+                update_table(table, old.and_then(Value::as_table).unwrap_or(&empty), new),
+            // Keep comments after the value
+            (Some(Item::Value(existing)), _) => {
+                if let Ok(mut new) = to_item(key, value).into_value() {
+                    *new.decor_mut() = existing.decor().clone();
+                    *existing = new;
+                }
+            }
+            _ => { document.insert(key, to_item(key, value)); }
+        }
+    }
+}
+
+/// Write [config] to Mirra.toml
+/// Only entries that changed are rewritten, so comments and keys mirra doesn't know survive
+pub async fn safe_config(config: Config) -> Result<()> {
+    let mirra_file = mirra_dir().join("Mirra.toml");
+    let (mut document, previous) = if mirra_file.exists() {
+        let document = fs::read_to_string(&mirra_file).await?.parse::<Document>()
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "Config file is corrupted"))?;
+        (document, config_to_table(load_config(&mirra_file).await?))
+    } else {
+        (Document::new(), Table::new())
+    };
+    update_table(document.as_table_mut(), &previous, &config_to_table(config));
+
+    let mut config_file = File::create(mirra_file).await?;
+    config_file.write_all(document.to_string().as_bytes()).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::util::tests::TestDir;
+    use super::{config_to_table, parse_config};
+
+    #[tokio::test]
+    async fn globs_matching_unnamed_directories_are_errors() {
+        let dir = TestDir::new("config-glob");
+        std::fs::create_dir(dir.join("a")).unwrap();
+
+        let mut errors = Vec::new();
+        let config = parse_config(&format!("[data]\npath = \"{}/*/..\"\n", dir.display()), &mut errors).await;
+        assert!(config.shares.is_empty());
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+
+        let mut errors = Vec::new();
+        let config = parse_config(&format!("[data]\npath = \"{}/*\"\n", dir.display()), &mut errors).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert!(config.shares.contains_key("a"));
+    }
+
+    #[tokio::test]
+    async fn connect_limits_survive_a_roundtrip() {
+        let mut errors = Vec::new();
+        let config = parse_config("connect_timeout = 0\nconnect_retries = 5\n", &mut errors).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!((config.connect_timeout, config.connect_retries), (Some(1), Some(5)));
+
+        let raw = toml::to_string(&config_to_table(config)).unwrap();
+        let config = parse_config(&raw, &mut errors).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!((config.connect_timeout, config.connect_retries), (Some(1), Some(5)));
+    }
+}
@@ -0,0 +1,142 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::Config;
+use crate::state;
+use crate::util::format_duration;
+
+const SOCKET_FILE: &str = "mirra.sock";
+
+/// Modules that are paused, until they are resumed or mirra restarts
+fn paused() -> &'static Mutex<HashSet<String>> {
+    static PAUSED: OnceLock<Mutex<HashSet<String>>> = OnceLock::new();
+    PAUSED.get_or_init(|| Mutex::new(HashSet::new()))
+}
+
+/// Whether syncing [module] is paused
+pub fn is_paused(module: &str) -> bool {
+    paused().lock().unwrap().contains(module)
+}
+
+/// Carry out a single [command] from the control socket, returning what to answer
+fn run_command(command: &str, config: &Config) -> Result<String> {
+    let (name, module) = command.split_once(' ').unwrap_or((command, ""));
+    let known = config.shares.contains_key(module) || config.syncs.contains_key(module);
+    match name {
+        "pause" | "resume" if !known => Err(Error::new(ErrorKind::NotFound, format!("there is no module named {}", module))),
+        "pause" => {
+            paused().lock().unwrap().insert(module.to_string());
+            Ok(String::new())
+        }
+        "resume" => {
+            paused().lock().unwrap().remove(module);
+            Ok(String::new())
+        }
+        "status" => {
+            let state = |module: &str| if is_paused(module) { "paused" } else { "syncing" };
+            let mut shares: Vec<_> = config.shares.keys().collect();
+            shares.sort();
+            let mut syncs: Vec<_> = config.syncs.keys().collect();
+            syncs.sort();
+            let mut status = String::new();
+            for share in shares {
+                status.push_str(&format!("share {}: {}\n", share, state(share)));
+            }
+            for sync in syncs {
+                match state::get(sync).age() {
+                    Some(age) => status.push_str(&format!("sync {}: {}, updated {} ago\n", sync, state(sync), format_duration(age))),
+                    None => status.push_str(&format!("sync {}: {}\n", sync, state(sync))),
+                }
+            }
+            Ok(status)
+        }
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown command {}", name))),
+    }
+}
+
+/// Answer commands from `mirra pause`, `mirra resume` and `mirra status` on a socket next to Mirra.toml, until mirra shuts down
+#[cfg(unix)]
+pub async fn serve_control(config: crate::config::ConfigWatch, mut shutdown: crate::util::Shutdown) {
+    use log::warn;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+    use crate::util::{mirra_dir, shutdown_requested};
+
+    let path = mirra_dir().join(SOCKET_FILE);
+    if path.exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            warn!("Another mirra is already running here, so this one can't be paused or resumed");
+            return;
+        }
+        // Left behind by a mirra that didn't shut down cleanly
+        let _ = std::fs::remove_file(&path);
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            warn!("Can't listen on {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Failed to accept a control connection: {}", e);
+                    continue;
+                }
+            },
+            _ = shutdown_requested(&mut shutdown) => break,
+        };
+
+        let current = config.borrow().clone();
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            let mut command = String::new();
+            if stream.read_line(&mut command).await.is_err() {
+                return;
+            }
+            let answer = match run_command(command.trim_end(), &current) {
+                Ok(answer) => answer,
+                Err(e) => format!("error: {}\n", e),
+            };
+            let _ = stream.get_mut().write_all(answer.as_bytes()).await;
+        });
+    }
+    let _ = std::fs::remove_file(&path);
+}
+
+#[cfg(not(unix))]
+pub async fn serve_control(_config: crate::config::ConfigWatch, _shutdown: crate::util::Shutdown) {}
+
+/// Send [command] to the mirra running next to Mirra.toml, returning its answer
+#[cfg(unix)]
+pub async fn send_command(command: &str) -> Result<String> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UnixStream;
+    use crate::util::mirra_dir;
+
+    let mut stream = UnixStream::connect(mirra_dir().join(SOCKET_FILE)).await
+        .map_err(|_| Error::new(ErrorKind::NotFound, "mirra is not running"))?;
+    stream.write_all(format!("{}\n", command).as_bytes()).await?;
+    let mut answer = String::new();
+    stream.read_to_string(&mut answer).await?;
+    match answer.strip_prefix("error: ") {
+        Some(e) => Err(Error::new(ErrorKind::Other, e.trim_end().to_string())),
+        None => Ok(answer),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn send_command(_command: &str) -> Result<String> {
+    Err(Error::new(ErrorKind::Unsupported, "controlling a running mirra is only supported on unix"))
+}
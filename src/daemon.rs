@@ -0,0 +1,143 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::{env, fs, process};
+use std::fs::OpenOptions;
+use std::io::{Error, ErrorKind, Result};
+use std::process::{Command, Stdio};
+
+use log::LevelFilter;
+use tokio::sync::watch;
+
+use crate::util::mirra_dir;
+
+const PID_FILE: &str = "mirra.pid";
+const LOG_FILE: &str = "mirra.log";
+
+/// Read the PID of the running daemon, if there is a PID file
+fn read_pid() -> Result<Option<i32>> {
+    match fs::read_to_string(mirra_dir().join(PID_FILE)) {
+        Ok(pid) => pid.trim().parse()
+            .map(Some)
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "PID file is corrupted")),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Check whether a process with [pid] exists
+#[cfg(unix)]
+fn is_running(pid: i32) -> bool {
+    unsafe { libc::kill(pid, 0) == 0 }
+}
+
+/// Start `mirra run` again in the background, detached from the terminal and logging to mirra.log next to Mirra.toml
+/// [log_level] is passed on, if a flag set it
+#[cfg(unix)]
+pub fn spawn_daemon(log_level: Option<LevelFilter>) -> Result<u32> {
+    use std::os::unix::process::CommandExt;
+
+    if let Some(pid) = read_pid()? {
+        if is_running(pid) {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("mirra is already running with PID {}", pid)));
+        }
+    }
+
+    let log = OpenOptions::new().create(true).append(true).open(mirra_dir().join(LOG_FILE))?;
+    let mut command = Command::new(env::current_exe()?);
+    command.arg("--config-dir").arg(mirra_dir())
+        .arg("run")
+        .args(log_level.map(|level| format!("--log-level={}", level)))
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    // Start a new session, so the daemon doesn't get the terminal's signals
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setsid() < 0 {
+                return Err(Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    let child = command.spawn()?;
+    fs::write(mirra_dir().join(PID_FILE), child.id().to_string())?;
+    Ok(child.id())
+}
+
+#[cfg(not(unix))]
+pub fn spawn_daemon(_log_level: Option<LevelFilter>) -> Result<u32> {
+    Err(Error::new(ErrorKind::Unsupported, "daemon mode is only supported on unix"))
+}
+
+/// Send [signal] to the running daemon
+#[cfg(unix)]
+fn signal_daemon(signal: libc::c_int) -> Result<i32> {
+    let pid = read_pid()?.ok_or_else(|| Error::new(ErrorKind::NotFound, "mirra is not running in the background"))?;
+    if unsafe { libc::kill(pid, signal) } < 0 {
+        let err = Error::last_os_error();
+        if err.raw_os_error() == Some(libc::ESRCH) {
+            // The daemon is gone, but didn't clean up after itself
+            fs::remove_file(mirra_dir().join(PID_FILE))?;
+            return Err(Error::new(ErrorKind::NotFound, "mirra is not running in the background"));
+        }
+        return Err(err);
+    }
+    Ok(pid)
+}
+
+/// Ask a running daemon to shut down gracefully, like Ctrl-C would
+#[cfg(unix)]
+pub fn stop_daemon() -> Result<i32> {
+    signal_daemon(libc::SIGINT)
+}
+
+#[cfg(not(unix))]
+pub fn stop_daemon() -> Result<i32> {
+    Err(Error::new(ErrorKind::Unsupported, "daemon mode is only supported on unix"))
+}
+
+/// Ask a running daemon to fully resync all modules it syncs
+#[cfg(unix)]
+pub fn resync_daemon() -> Result<i32> {
+    signal_daemon(libc::SIGUSR1)
+}
+
+#[cfg(not(unix))]
+pub fn resync_daemon() -> Result<i32> {
+    Err(Error::new(ErrorKind::Unsupported, "daemon mode is only supported on unix"))
+}
+
+/// Count every SIGUSR1 in [requests], asking nodes to request a full resync
+#[cfg(unix)]
+pub fn watch_resync_signal(requests: watch::Sender<u64>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signal = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signal.recv().await.is_some() {
+            let count = *requests.borrow() + 1;
+            if requests.send(count).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn watch_resync_signal(_requests: watch::Sender<u64>) -> Result<()> {
+    Ok(())
+}
+
+/// Remove the PID file, if it belongs to this process
+pub fn remove_pid_file() -> Result<()> {
+    if read_pid()? == Some(process::id() as i32) {
+        fs::remove_file(mirra_dir().join(PID_FILE))?;
+    }
+    Ok(())
+}
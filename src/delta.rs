@@ -0,0 +1,139 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+/// Files smaller than this are just sent whole
+pub const MIN_DELTA_SIZE: u64 = 1024 * 1024;
+
+/// Bytes of a block's blake3 hash that are compared
+const STRONG_LEN: usize = 16;
+/// A weak checksum, followed by the truncated strong one
+const ENTRY_LEN: usize = 4 + STRONG_LEN;
+
+/// Pick a block size for a file of [len] bytes, roughly its square root like rsync does
+pub fn block_size(len: u64) -> u32 {
+    ((len as f64).sqrt() as u32).clamp(2048, 128 * 1024)
+}
+
+/// rsync's rolling checksum, which can be moved along a file one byte at a time
+pub struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    /// Checksum the window [data]
+    pub fn new(data: &[u8]) -> Self {
+        let mut a = 0u32;
+        let mut b = 0u32;
+        for (i, byte) in data.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((data.len() - i) as u32 * *byte as u32);
+        }
+        Rolling { a, b, len: data.len() as u32 }
+    }
+
+    /// Move the window by one byte, dropping [out] and appending [next]
+    pub fn roll(&mut self, out: u8, next: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(next as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(out as u32)).wrapping_add(self.a);
+    }
+
+    pub fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// The truncated strong checksum of a block
+fn strong(data: &[u8]) -> [u8; STRONG_LEN] {
+    let mut res = [0; STRONG_LEN];
+    res.copy_from_slice(&blake3::hash(data).as_bytes()[..STRONG_LEN]);
+    res
+}
+
+/// Read until [buf] is full or the file ends
+async fn read_block(file: &mut File, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        let s = file.read(&mut buf[read..]).await?;
+        if s == 0 {
+            break;
+        }
+        read += s;
+    }
+    Ok(read)
+}
+
+/// Checksum every full block of [file], packed for a [crate::packet::BlockChecksums]
+pub async fn block_checksums(file: &mut File, block_size: u32) -> Result<Vec<u8>> {
+    let mut buf = vec![0; block_size as usize];
+    let mut res = Vec::new();
+    // A trailing partial block can't be matched anyway
+    while read_block(file, &mut buf).await? == buf.len() {
+        res.extend_from_slice(&Rolling::new(&buf).digest().to_be_bytes());
+        res.extend_from_slice(&strong(&buf));
+    }
+    Ok(res)
+}
+
+/// The block checksums of a node's version of a file, looked up by their weak checksum
+pub struct Signatures {
+    pub block_size: u32,
+    blocks: HashMap<u32, Vec<(u64, [u8; STRONG_LEN])>>,
+}
+
+impl Signatures {
+    /// Unpack checksums made by [block_checksums]
+    pub fn parse(block_size: u32, checksums: &[u8]) -> Result<Self> {
+        if block_size == 0 || checksums.len() % ENTRY_LEN != 0 {
+            return Err(Error::new(ErrorKind::InvalidData, "malformed block checksums"));
+        }
+
+        let mut blocks: HashMap<u32, Vec<_>> = HashMap::new();
+        for (index, entry) in checksums.chunks(ENTRY_LEN).enumerate() {
+            let weak = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let mut strong = [0; STRONG_LEN];
+            strong.copy_from_slice(&entry[4..]);
+            blocks.entry(weak).or_default().push((index as u64, strong));
+        }
+        Ok(Signatures { block_size, blocks })
+    }
+
+    /// Find a block of the node's file that has the same contents as [window]
+    pub fn find(&self, weak: u32, window: &[u8]) -> Option<u64> {
+        // Only compute the strong checksum if the weak one matches something
+        let candidates = self.blocks.get(&weak)?;
+        let hash = strong(window);
+        candidates.iter().find(|candidate| candidate.1 == hash).map(|candidate| candidate.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    use super::Rolling;
+
+    #[test]
+    fn rolling_matches_a_fresh_checksum() {
+        let mut rng = StdRng::seed_from_u64(0x726f6c6c);
+        let data: Vec<u8> = (0..4096).map(|_| rng.gen()).collect();
+        for len in [1, 7, 2048] {
+            let mut rolling = Rolling::new(&data[..len]);
+            for start in 1..data.len() - len {
+                rolling.roll(data[start - 1], data[start + len - 1]);
+                assert_eq!(rolling.digest(), Rolling::new(&data[start..start + len]).digest(), "window of {} at {}", len, start);
+            }
+        }
+    }
+}
@@ -5,15 +5,22 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
 use std::fs;
-use std::fs::{create_dir, File};
+use std::fs::{create_dir_all, File};
 use std::io::{Error, ErrorKind, Read, Result, Write};
 use std::path::Path;
 
-use log::error;
-use rsa::{PaddingScheme, RsaPrivateKey, RsaPublicKey};
+use log::{error, warn};
+use rsa::{PaddingScheme, PublicKey, RsaPrivateKey, RsaPublicKey};
 use rsa::pkcs1::LineEnding;
 use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey};
 
+use crate::util::mirra_dir;
+
+/// Key size used when none is configured
+const DEFAULT_KEY_BITS: usize = 2048;
+/// Smaller keys are rejected by the rsa crate or trivially broken
+const MIN_KEY_BITS: usize = 1024;
+
 /// The servers public and private key
 pub struct LocalKeys {
     pub private_key: rsa::RsaPrivateKey,
@@ -25,14 +32,25 @@ impl LocalKeys {
     pub fn sign(&self, msg: String) -> String {
         base64::encode(self.private_key.sign(PaddingScheme::PKCS1v15Sign { hash: None }, msg.as_bytes()).unwrap())
     }
+
+    /// The public key in PEM format, as it is stored in public.key
+    pub fn public_key_pem(&self) -> String {
+        self.public_key.to_public_key_pem(LineEnding::LF).expect("failed to encode a key")
+    }
+
+    /// A short fingerprint of the public key, the start of the blake3 hash of its DER encoding
+    pub fn fingerprint(&self) -> String {
+        let der = self.public_key.to_public_key_der().expect("failed to encode a key");
+        let hash = blake3::hash(der.as_ref()).to_hex();
+        format!("blake3:{}", &hash[..32])
+    }
 }
 
-/// Generate private and public key and store them to disk
-fn setup_keys(at: &Path) -> Result<LocalKeys> {
+/// Generate private and public key with [bits] bits and store them to disk
+fn setup_keys(at: &Path, bits: usize) -> Result<LocalKeys> {
     // [thread_rng] should be cryptographically secure
     let mut rng = rand::thread_rng();
 
-    let bits = 2048;
     // Generate keys
     let private_key = rsa::RsaPrivateKey::new(&mut rng, bits).expect("failed to generate a key");
     let public_key = rsa::RsaPublicKey::from(&private_key);
@@ -100,15 +118,15 @@ fn load_public_key(from: &Path) -> Result<RsaPublicKey> {
     }
 }
 
-/// Load both keys from disk, regenerate if they don't exist
-fn load_keys(from: &Path) -> Result<LocalKeys> {
+/// Load both keys from disk, regenerate them with [bits] bits if they don't exist
+fn load_keys(from: &Path, bits: usize) -> Result<LocalKeys> {
     // Load private key
     let private_key = load_private_key(from);
 
     if private_key.is_err() {
         error!("Mirra was configured, but private key is missing, regenerating...");
         clear_keys(from)?;
-        return setup_keys(from);
+        return setup_keys(from, bits);
     }
 
     // Load public key
@@ -136,12 +154,105 @@ fn load_keys(from: &Path) -> Result<LocalKeys> {
     })
 }
 
-/// Abstraction for loading/creating private/public keys
-pub fn get_keys() -> Result<LocalKeys> {
-    let mirra_folder = Path::new(".mirra");
+/// Replace both keys with a new pair, keeping the old public key in previous.key for a grace period
+/// The new public key's hash is signed with the old private key in rollover.sig, so peers that know
+/// the old key can verify the new one
+/// Fails while an earlier rotation isn't finished, its previous key would be lost otherwise
+pub fn rotate_keys(old: &LocalKeys, bits: Option<usize>) -> Result<LocalKeys> {
+    let mirra_folder = mirra_dir();
+    if mirra_folder.join("previous.key").exists() {
+        return Err(Error::new(ErrorKind::AlreadyExists, "the previous rotation isn't finished yet, finish it with `mirra rotate-keys --finish` first"));
+    }
+
+    // Keep the old public key around until the rotation is finished
+    let encoded_old = old.public_key_pem();
+    let mut previous_key_file = File::create(mirra_folder.join("previous.key"))?;
+    previous_key_file.write_all(encoded_old.as_bytes())?;
+
+    // Overwrites private.key and public.key
+    let new = setup_keys(mirra_folder, key_bits(bits))?;
+
+    // Vouch for the new key with the old one
+    let encoded_new = new.public_key_pem();
+    let signature = old.sign(blake3::hash(encoded_new.as_bytes()).to_string());
+    let mut rollover_file = File::create(mirra_folder.join("rollover.sig"))?;
+    rollover_file.write_all(signature.as_bytes())?;
+
+    Ok(new)
+}
+
+/// Check that [signature], the contents of a peer's rollover.sig, vouches for [new], its new public key
+/// [previous] is the public key the peer had before, both in PEM format
+pub fn verify_rollover(previous: &str, new: &str, signature: &str) -> Result<bool> {
+    let previous = RsaPublicKey::from_public_key_pem(previous)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("the previous key is invalid: {}", e)))?;
+    RsaPublicKey::from_public_key_pem(new)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("the new key is invalid: {}", e)))?;
+    let signature = match base64::decode(signature.trim()) {
+        Ok(signature) => signature,
+        Err(_) => return Ok(false),
+    };
+    let hash = blake3::hash(new.as_bytes()).to_string();
+    Ok(previous.verify(PaddingScheme::PKCS1v15Sign { hash: None }, hash.as_bytes(), &signature).is_ok())
+}
+
+/// End a key rotation's grace period by deleting the previous key and its rollover signature
+pub fn finish_rotation() -> Result<()> {
+    let mirra_folder = mirra_dir();
+    if mirra_folder.join("previous.key").exists() {
+        fs::remove_file(mirra_folder.join("previous.key"))?;
+    }
+
+    if mirra_folder.join("rollover.sig").exists() {
+        fs::remove_file(mirra_folder.join("rollover.sig"))?;
+    }
+
+    Ok(())
+}
+
+/// The configured key size, or the default, but never less than [MIN_KEY_BITS]
+fn key_bits(bits: Option<usize>) -> usize {
+    let bits = bits.unwrap_or(DEFAULT_KEY_BITS);
+    if bits < MIN_KEY_BITS {
+        warn!("Key size of {} bits is too small, using {} bits instead", bits, MIN_KEY_BITS);
+        return MIN_KEY_BITS;
+    }
+    bits
+}
+
+/// Abstraction for loading/creating private/public keys, new keys have [bits] bits
+pub fn get_keys(bits: Option<usize>) -> Result<LocalKeys> {
+    let mirra_folder = mirra_dir();
     // Check if keys exists, else create
     if !mirra_folder.exists() {
-        create_dir(mirra_folder)?;
+        create_dir_all(mirra_folder)?;
+    }
+    load_keys(mirra_folder, key_bits(bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::{RsaPrivateKey, RsaPublicKey};
+
+    use super::{LocalKeys, verify_rollover};
+
+    fn keys() -> LocalKeys {
+        // Anything smaller can't sign a hex encoded hash
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        LocalKeys { public_key: RsaPublicKey::from(&private_key), private_key }
+    }
+
+    #[test]
+    fn rollovers_are_signed_by_the_previous_key() {
+        let (old, new, other) = (keys(), keys(), keys());
+        let signature = old.sign(blake3::hash(new.public_key_pem().as_bytes()).to_string());
+
+        assert!(verify_rollover(&old.public_key_pem(), &new.public_key_pem(), &signature).unwrap());
+        assert!(verify_rollover(&old.public_key_pem(), &new.public_key_pem(), &format!("{}\n", signature)).unwrap());
+        // Someone else's key, or the right one vouched for by someone else
+        assert!(!verify_rollover(&old.public_key_pem(), &other.public_key_pem(), &signature).unwrap());
+        assert!(!verify_rollover(&other.public_key_pem(), &new.public_key_pem(), &signature).unwrap());
+        assert!(!verify_rollover(&old.public_key_pem(), &new.public_key_pem(), "not base64").unwrap());
+        assert!(verify_rollover("not a key", &new.public_key_pem(), &signature).is_err());
     }
-    load_keys(mirra_folder)
 }
@@ -6,26 +6,41 @@
 
 extern crate core;
 
-use std::env;
-use std::io::Result;
+use std::process;
+use std::io::{Error, ErrorKind, Result};
 use std::path::PathBuf;
+use std::process::Command;
 use std::sync::Arc;
 
-use tokio::join;
-use clap::{Parser, Subcommand};
+use tokio::{join, signal};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use clap::{CommandFactory, Parser, Subcommand};
 use dialoguer::Confirm;
+use log::{error, info, LevelFilter, warn};
 
-use crate::config::{get_config, RootShare, RootSync, safe_config};
-use crate::keys::{LocalKeys, get_keys};
+use crate::config::{Config, format_entry, get_config, RootShare, RootSync, safe_config, share_to_table, sync_to_table};
+use crate::keys::{LocalKeys, finish_rotation, get_keys, rotate_keys, verify_rollover};
+use crate::mux::{DEFAULT_CONNECT_RETRIES, DEFAULT_CONNECT_TIMEOUT, set_connect_limits};
 use crate::socket::{Client, Server};
-use crate::util::{stringify, parse_address};
+use crate::util::{AsyncFileLock, DEFAULT_BUFFER_SIZE, format_address, open_module_lock, stringify, parse_address, set_buffer_size, set_mirra_dir};
 
+mod auth;
+mod control;
+mod daemon;
+mod delta;
 mod keys;
+mod metrics;
+mod mux;
 mod socket;
+mod state;
 mod util;
 mod root;
 mod node;
+mod origin;
 mod packet;
+#[cfg(test)]
+mod wire_tests;
 mod config;
 mod web;
 
@@ -33,18 +48,91 @@ mod web;
 #[clap(name = "mirra")]
 #[clap(about = "A mirror management software", version = "0.1.0")]
 struct Cli {
+    #[clap(long, global = true, parse(from_os_str), alias = "config-path", value_name = "DIR",
+           help = "Keep Mirra.toml, the keys and everything else in DIR instead of .mirra")]
+    config_dir: Option<PathBuf>,
+
+    #[clap(short, long, global = true, conflicts_with_all = &["verbose", "log-level"], help = "Only log warnings and errors")]
+    quiet: bool,
+
+    #[clap(short, long, global = true, conflicts_with = "log-level", help = "Log debug messages as well")]
+    verbose: bool,
+
+    #[clap(long, global = true, value_name = "LEVEL",
+           help = "Set what is logged: off, error, warn, info, debug or trace, overrides RUST_LOG")]
+    log_level: Option<LevelFilter>,
+
     #[clap(subcommand)]
-    commands: Subcommands,
+    commands: Option<Subcommands>,
+}
+
+impl Cli {
+    /// The level set by a flag, if any, which overrides RUST_LOG
+    fn log_level(&self) -> Option<LevelFilter> {
+        if self.quiet {
+            Some(LevelFilter::Warn)
+        } else if self.verbose {
+            Some(LevelFilter::Debug)
+        } else {
+            self.log_level
+        }
+    }
 }
 
 #[derive(Subcommand)]
 enum Subcommands {
-    #[clap(about = "Run mirra normally")]
-    Run,
+    Run(Run),
+    #[clap(about = "Stop mirra running in the background")]
+    Stop,
+    #[clap(about = "Make mirra running in the background fully resync every module it syncs")]
+    Resync,
+    #[clap(arg_required_else_help = true)]
+    Pause(Pause),
+    #[clap(arg_required_else_help = true)]
+    Resume(Resume),
+    #[clap(about = "List every module of the running mirra and whether it is paused")]
+    Status,
     #[clap(arg_required_else_help = true)]
     Sync(Sync),
     #[clap(arg_required_else_help = true)]
     Share(Share),
+    #[clap(arg_required_else_help = true)]
+    Pull(Pull),
+    #[clap(arg_required_else_help = true)]
+    Push(Push),
+    #[clap(arg_required_else_help = true)]
+    Verify(Verify),
+    #[clap(arg_required_else_help = true)]
+    Lock(Lock),
+    RotateKeys(RotateKeys),
+    #[clap(arg_required_else_help = true)]
+    VerifyRollover(VerifyRollover),
+    Pubkey(Pubkey),
+    #[clap(about = "Check Mirra.toml and list every problem with it")]
+    Check,
+    #[clap(about = "List every shared and synced module")]
+    List,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Run mirra normally")]
+struct Run {
+    #[clap(short, long, help = "Run in the background, logging to mirra.log next to Mirra.toml")]
+    daemon: bool,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Stop syncing changes to a module in the running mirra, until it is resumed")]
+struct Pause {
+    #[clap(help = "Set the module's name")]
+    module: String,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Sync changes to a paused module again")]
+struct Resume {
+    #[clap(help = "Set the module's name")]
+    module: String,
 }
 
 #[derive(clap::Args)]
@@ -58,6 +146,9 @@ struct Sync {
 
     #[clap(short = 'p', long, parse(from_os_str), help = "Set where the module will be stored")]
     output_path: Option<PathBuf>,
+
+    #[clap(long, help = "Print the change to Mirra.toml instead of making it")]
+    dry_run: bool,
 }
 
 #[derive(clap::Args)]
@@ -66,81 +157,455 @@ struct Share {
     #[clap(help = "Set the module's name")]
     name: String,
 
-    #[clap(short = 'p', long, parse(from_os_str), help = "Set what directory to share")]
-    module_path: Option<PathBuf>,
+    #[clap(short = 'p', long, parse(from_os_str), help = "Set what directory to share, given several times to merge directories")]
+    module_path: Vec<PathBuf>,
+
+    #[clap(long, help = "Print the change to Mirra.toml instead of making it")]
+    dry_run: bool,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    // hack to enable logging by default
-    if env::var("RUST_LOG").is_err() {
-        env::set_var("RUST_LOG", "info")
+#[derive(clap::Args)]
+#[clap(about = "Receive a module from a remote mirra once, without syncing it afterwards")]
+struct Pull {
+    #[clap(value_name = "ADDR[:PORT]", help = "Set the remote mirra's address")]
+    remote_addr: String,
+
+    #[clap(help = "Set the remote module's name")]
+    module: String,
+
+    #[clap(short = 'p', long, parse(from_os_str), help = "Set where the module will be stored")]
+    output_path: Option<PathBuf>,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Send a shared module to a remote mirra once, connecting to it instead of waiting for it")]
+struct Push {
+    #[clap(value_name = "ADDR[:PORT]", help = "Set the remote mirra's address")]
+    remote_addr: String,
+
+    #[clap(help = "Set the shared module's name")]
+    module: String,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Check that the files of a synced module are still what was received")]
+struct Verify {
+    #[clap(help = "Set the synced module's name")]
+    module: String,
+
+    #[clap(long, value_name = "ADDR[:PORT]", help = "Compare with a remote mirra's manifest instead of the stored one")]
+    against: Option<String>,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Pause syncing a module while running a command")]
+struct Lock {
+    #[clap(help = "Set the module's name")]
+    module: String,
+
+    #[clap(required = true, last = true, help = "Set the command to run while the module is locked")]
+    command: Vec<String>,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Replace the local key pair, keeping the old public key for a grace period")]
+struct RotateKeys {
+    #[clap(long, help = "End the grace period by deleting the previous key")]
+    finish: bool,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Check that a peer's new public key is vouched for by its previous one")]
+struct VerifyRollover {
+    #[clap(help = "The peer's previous public key, its previous.key")]
+    previous: PathBuf,
+    #[clap(help = "The peer's new public key, its public.key")]
+    new: PathBuf,
+    #[clap(help = "The peer's rollover.sig")]
+    signature: PathBuf,
+}
+
+#[derive(clap::Args)]
+#[clap(about = "Print the local public key and its fingerprint")]
+struct Pubkey {
+    #[clap(long, help = "Only print the fingerprint")]
+    fingerprint_only: bool,
+}
+
+/// Check the rollover.sig, previous and new public key that [verify] names
+fn verify_rollover_files(verify: &VerifyRollover) -> Result<bool> {
+    let read = |path: &PathBuf| std::fs::read_to_string(path)
+        .map_err(|e| Error::new(e.kind(), format!("can't read {}: {}", path.display(), e)));
+    verify_rollover(&read(&verify.previous)?, &read(&verify.new)?, &read(&verify.signature)?)
+}
+
+/// Print a Mirra.toml entry that a dry run would have written
+fn print_dry_run(replaces: bool, entry: String) {
+    if replaces {
+        println!("Would replace the existing entry with:");
+    } else {
+        println!("Would add:");
+    }
+    print!("{}", entry);
+}
+
+/// Receive a module once, returning whether every file arrived intact
+async fn pull_module(pull: Pull) -> Result<bool> {
+    let addr = parse_address(&pull.remote_addr).await?;
+    let path = match pull.output_path {
+        Some(path) => stringify(path)?,
+        None => pull.module.clone(),
+    };
+
+    let sync = RootSync {
+        address: addr.address,
+        port: addr.port,
+        path,
+        allowlist: None,
+        web: false,
+        max_download_bytes_per_sec: None,
+        sync_concurrency: 1,
+        max_file_size: None,
+        accept_push: false,
+        read_only: false,
+        description: None,
+    };
+    let corrupted = node::pull_once(pull.module, sync).await?;
+    for path in &corrupted {
+        error!("{} arrived corrupted", path);
+    }
+    Ok(corrupted.is_empty())
+}
+
+/// Compare a synced module on disk with its manifest, returning whether it's intact
+async fn verify_module(verify: Verify, config: &Config) -> Result<bool> {
+    let sync = config.syncs.get(&verify.module).cloned()
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} isn't synced", verify.module)))?;
+
+    let manifest = match verify.against {
+        Some(against) => {
+            let addr = parse_address(&against).await?;
+            let remote = RootSync { address: addr.address, port: addr.port, ..sync.clone() };
+            node::fetch_manifest(verify.module.clone(), remote).await?
+        }
+        None => {
+            let manifest = node::load_manifest(&verify.module).await?;
+            if manifest.is_empty() {
+                return Err(Error::new(ErrorKind::NotFound, format!("no manifest is stored for {} yet, it is stored after a full sync", verify.module)));
+            }
+            manifest
+        }
+    };
+
+    let differences = node::verify_module(&PathBuf::from(&sync.path), &manifest).await?;
+    for path in &differences.missing {
+        error!("{} is missing", path);
+    }
+    for path in &differences.changed {
+        error!("{} doesn't match its hash", path);
+    }
+    for path in &differences.extra {
+        warn!("{} isn't in the manifest", path);
     }
-    env_logger::init();
 
+    let broken = differences.missing.len() + differences.changed.len();
+    info!("Verified {} files of {}, {} missing or changed, {} extra", manifest.len(), verify.module, broken, differences.extra.len());
+    Ok(broken == 0 && differences.extra.is_empty())
+}
+
+/// Wait for the [name] server to finish, logging right away if it fails and shutting everything else down
+async fn supervise(name: &str, task: JoinHandle<Result<()>>, shutdown: Arc<watch::Sender<bool>>) -> Result<()> {
+    let res = match task.await {
+        Ok(res) => res,
+        Err(e) => Err(Error::new(ErrorKind::Other, e)),
+    };
+    if let Err(e) = &res {
+        error!("The {} server stopped: {}", name, e);
+        let _ = shutdown.send(true);
+    }
+    res
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
     // Load config and keys from disk
     // Atomically refcounted, so we can use them with [tokio::spawn], which might
     // move tasks between threads with feature "rt-multi-thread" enabled
-    let mut raw_config = get_config().await?;
-    let raw_env = get_keys()?;
+    let args = Cli::try_parse();
+    let log_level = args.as_ref().ok().and_then(Cli::log_level);
+    util::init_logging(log_level);
+    if let Some(config_dir) = args.as_ref().ok().and_then(|args| args.config_dir.clone()) {
+        set_mirra_dir(config_dir);
+    }
 
-    let args = Cli::parse();
+    // Checking the config has to work when it can't be loaded
+    if let Ok(Cli { commands: Some(Subcommands::Check), .. }) = args {
+        let valid = config::check_config().await?;
+        process::exit(if valid { 0 } else { 1 });
+    }
 
-    match args.commands {
-        Subcommands::Run => {
-            let config = Arc::from(raw_config);
+    // Verifying someone else's keys doesn't need the local ones
+    if let Ok(Cli { commands: Some(Subcommands::VerifyRollover(verify)), .. }) = args {
+        match verify_rollover_files(&verify) {
+            Ok(true) => {
+                info!("{} is signed by the previous key", verify.new.display());
+                process::exit(0);
+            }
+            Ok(false) => error!("{} is not signed by the previous key, don't trust it", verify.new.display()),
+            Err(e) => error!("{}", e),
+        }
+        process::exit(1);
+    }
+
+    // Pulling doesn't need a config either, so it can be used in scripts
+    if let Ok(Cli { commands: Some(Subcommands::Pull(pull)), .. }) = args {
+        match pull_module(pull).await {
+            Ok(intact) => process::exit(if intact { 0 } else { 1 }),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let mut raw_config = match get_config().await {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let raw_env = get_keys(raw_config.key_bits)?;
+    origin::set_local(raw_env.fingerprint());
+    set_buffer_size(raw_config.buffer_size.unwrap_or(DEFAULT_BUFFER_SIZE));
+    set_connect_limits(raw_config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT), raw_config.connect_retries.unwrap_or(DEFAULT_CONNECT_RETRIES));
+
+    let args = args.unwrap_or_else(|e| e.exit());
+
+    let commands = match args.commands {
+        Some(commands) => commands,
+        // Running mirra without a command only sets it up
+        None => return Cli::command().print_help(),
+    };
+
+    match commands {
+        Subcommands::Run(run) if run.daemon => {
+            let pid = daemon::spawn_daemon(log_level)?;
+            info!("Running in the background with PID {}", pid);
+        }
+        Subcommands::Run(_) => {
             let env = Arc::from(raw_env);
 
+            // Stop accepting new work on the first Ctrl-C, and exit immediately on the second one
+            let (shutdown_tx, shutdown) = watch::channel(false);
+            let shutdown_tx = Arc::new(shutdown_tx);
+            let signal_tx = shutdown_tx.clone();
+            tokio::spawn(async move {
+                if signal::ctrl_c().await.is_ok() {
+                    info!("Shutting down, press Ctrl-C again to force");
+                    let _ = signal_tx.send(true);
+                    if signal::ctrl_c().await.is_ok() {
+                        warn!("Forcing shutdown");
+                        process::exit(130);
+                    }
+                }
+            });
+
+            // Reload the config whenever Mirra.toml changes
+            config::check_share_paths(&raw_config);
+            let initial = Arc::from(raw_config);
+            let (config_tx, config) = watch::channel(initial.clone());
+            tokio::spawn(config::watch_config(config_tx, initial, shutdown.clone()));
+
             // Start root and node servers
             // See [root::root]'s and [node::node]'s descriptions for more info
-            let root_fut = tokio::spawn(root::root(config.clone(), env.clone()));
-            let web_fut = tokio::spawn(web::web(config.clone(), env.clone()));
-            let node_fut = node::node(config.clone(), env.clone());
-
-            // Run them in parallel until both finish
-            // todo: this will only print errors at the end of execution
-            let (root_res, web_res, node_res) = join!(root_fut, web_fut, node_fut);
-            root_res??;
-            web_res??;
+            // With a shared port, the root hands connections that speak HTTP to the web server
+            let (web_tx, web_rx) = if config.borrow().shared_port {
+                let (tx, rx) = mpsc::channel(16);
+                (Some(tx), Some(rx))
+            } else {
+                (None, None)
+            };
+            let root_fut = tokio::spawn(root::root(config.clone(), env.clone(), web_tx, shutdown.clone()));
+            let web_fut = tokio::spawn(web::web(config.clone(), env.clone(), web_rx, shutdown.clone()));
+            tokio::spawn(control::serve_control(config.clone(), shutdown.clone()));
+            let node_fut = tokio::spawn(node::node(config, env.clone(), shutdown));
+
+            // Run them in parallel until all of them finish, the first one to fail shuts down the others
+            let (root_res, web_res, node_res) = join!(
+                supervise("root", root_fut, shutdown_tx.clone()),
+                supervise("web", web_fut, shutdown_tx.clone()),
+                supervise("node", node_fut, shutdown_tx),
+            );
+            daemon::remove_pid_file()?;
+            root_res?;
+            web_res?;
             node_res?;
         }
+        Subcommands::Stop => {
+            let pid = daemon::stop_daemon()?;
+            info!("Asked mirra with PID {} to shut down", pid);
+        }
+        Subcommands::Resync => {
+            let pid = daemon::resync_daemon()?;
+            info!("Asked mirra with PID {} to resync", pid);
+        }
+        Subcommands::Pause(pause) => {
+            control::send_command(&format!("pause {}", pause.module)).await?;
+            info!("Paused {}", pause.module);
+        }
+        Subcommands::Resume(resume) => {
+            control::send_command(&format!("resume {}", resume.module)).await?;
+            info!("Resumed {}", resume.module);
+        }
+        Subcommands::Status => {
+            print!("{}", control::send_command("status").await?);
+        }
         Subcommands::Sync(sync) => {
-            if !raw_config.syncs.contains_key(&sync.module) ||
+            // Both would end up as the same table in Mirra.toml
+            if raw_config.shares.contains_key(&sync.module) {
+                error!("Already sharing a module named {}, a module can't be shared and synced at once", sync.module);
+                process::exit(1);
+            }
+            if sync.dry_run || !raw_config.syncs.contains_key(&sync.module) ||
                 Confirm::new()
                     .with_prompt(format!("Already syncing a module named {}. Overwrite?", sync.module))
                     .interact()? {
-                let addr = parse_address(sync.remote_addr);
+                let addr = match parse_address(&sync.remote_addr).await {
+                    Ok(addr) => addr,
+                    Err(e) => {
+                        error!("{}", e);
+                        process::exit(1);
+                    }
+                };
                 let path = if sync.output_path.is_some() {
                     stringify(sync.output_path.unwrap())?
                 } else {
                     sync.module.as_str().to_string()
                 };
 
-                raw_config.syncs.insert(sync.module.clone(), RootSync {
+                let entry = RootSync {
                     address: addr.address,
                     port: addr.port,
                     path,
-                });
-                safe_config(env::current_dir()?, raw_config).await?;
+                    allowlist: None,
+                    web: true,
+                    max_download_bytes_per_sec: None,
+                    sync_concurrency: 1,
+                    max_file_size: None,
+                    accept_push: false,
+                    read_only: false,
+                    description: None,
+                };
+                if sync.dry_run {
+                    print_dry_run(raw_config.syncs.contains_key(&sync.module), format_entry(sync.module, sync_to_table(entry)));
+                } else {
+                    raw_config.syncs.insert(sync.module.clone(), entry);
+                    safe_config(raw_config).await?;
+                }
             }
         }
         Subcommands::Share(share) => {
-            if !raw_config.shares.contains_key(&share.name) ||
+            if raw_config.syncs.contains_key(&share.name) {
+                error!("Already syncing a module named {}, a module can't be shared and synced at once", share.name);
+                process::exit(1);
+            }
+            if let Some(glob) = raw_config.shares.get(&share.name).and_then(|share| share.glob.as_ref()) {
+                error!("{} is shared because it matches {} in [{}], change that instead", share.name, glob.pattern, glob.entry);
+                process::exit(1);
+            }
+            if share.dry_run || !raw_config.shares.contains_key(&share.name) ||
                 Confirm::new()
                     .with_prompt(format!("Already sharing a module named {}. Overwrite?", share.name))
                     .interact()? {
-                let path = if share.module_path.is_some() {
-                    stringify(share.module_path.unwrap())?
+                let paths = if !share.module_path.is_empty() {
+                    share.module_path.into_iter().map(stringify).collect::<Result<_>>()?
                 } else {
-                    share.name.as_str().to_string()
+                    vec![share.name.as_str().to_string()]
                 };
 
-                raw_config.shares.insert(share.name, RootShare {
-                    path
-                });
-                safe_config(env::current_dir()?, raw_config).await?;
+                let entry = RootShare {
+                    paths,
+                    cdn_base: None,
+                    max_downloads: None,
+                    auth: None,
+                    web: true,
+                    max_file_size: None,
+                    precompress: false,
+                    description: None,
+                    glob: None,
+                };
+                if share.dry_run {
+                    print_dry_run(raw_config.shares.contains_key(&share.name), format_entry(share.name, share_to_table(entry)));
+                } else {
+                    raw_config.shares.insert(share.name, entry);
+                    safe_config(raw_config).await?;
+                }
+            }
+        }
+        Subcommands::Push(push) => {
+            let res = match parse_address(&push.remote_addr).await {
+                Ok(addr) => root::push_once(format_address(&addr.address, addr.port), push.module.clone(), Arc::new(raw_config), Arc::new(raw_env)).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                error!("{}", e);
+                process::exit(1);
+            }
+            info!("Pushed {}", push.module);
+        }
+        Subcommands::Verify(verify) => {
+            match verify_module(verify, &raw_config).await {
+                Ok(intact) => process::exit(if intact { 0 } else { 1 }),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        }
+        Subcommands::List => {
+            let mut shares: Vec<_> = raw_config.shares.iter().collect();
+            shares.sort_by_key(|share| share.0);
+            for (name, share) in shares {
+                match &share.glob {
+                    Some(glob) => println!("share {}: {} (from {})", name, share.paths.join(", "), glob.pattern),
+                    None => println!("share {}: {}", name, share.paths.join(", ")),
+                }
+            }
+            let mut syncs: Vec<_> = raw_config.syncs.iter().collect();
+            syncs.sort_by_key(|sync| sync.0);
+            for (name, sync) in syncs {
+                println!("sync {}: {} from {}", name, sync.path, format_address(&sync.address, sync.port));
+            }
+        }
+        Subcommands::Lock(lock) => {
+            // Mirra waits for this lock before performing a full sync of the module
+            let lock_file = open_module_lock(&lock.module).await?;
+            lock_file.lock().await?;
+            info!("Locked {}", lock.module);
+
+            let status = Command::new(&lock.command[0]).args(&lock.command[1..]).status();
+            lock_file.unlock().await?;
+
+            process::exit(status?.code().unwrap_or(1));
+        }
+        Subcommands::RotateKeys(rotate) => {
+            if rotate.finish {
+                finish_rotation()?;
+                info!("Removed the previous key");
+            } else {
+                rotate_keys(&raw_env, raw_config.key_bits)?;
+                info!("Generated a new key pair, the previous public key is kept in {}", util::mirra_dir().join("previous.key").display());
+            }
+        }
+        Subcommands::Pubkey(pubkey) => {
+            // Printed directly, so the output can be piped
+            if !pubkey.fingerprint_only {
+                print!("{}", raw_env.public_key_pem());
             }
+            println!("{}", raw_env.fingerprint());
         }
+        Subcommands::Check | Subcommands::Pull(_) | Subcommands::VerifyRollover(_) => unreachable!(),
     }
 
     return Ok(());
@@ -0,0 +1,96 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static FILES_SENT: AtomicU64 = AtomicU64::new(0);
+static FILES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static SYNC_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// When the last full sync of each module finished, in seconds since the epoch
+fn last_syncs() -> &'static Mutex<HashMap<String, u64>> {
+    static LAST_SYNCS: OnceLock<Mutex<HashMap<String, u64>>> = OnceLock::new();
+    LAST_SYNCS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+pub fn bytes_sent(bytes: u64) {
+    BYTES_SENT.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn bytes_received(bytes: u64) {
+    BYTES_RECEIVED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn file_sent() {
+    FILES_SENT.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn file_received() {
+    FILES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn sync_error() {
+    SYNC_ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Note that a full sync of [module] just finished
+pub fn synced(module: &str) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    last_syncs().lock().unwrap().insert(module.to_string(), now);
+}
+
+/// Counts a connection as active until it is dropped
+pub struct ActiveConnection;
+
+impl ActiveConnection {
+    pub fn new() -> Self {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        ActiveConnection
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Escape [value] for use as a label value
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Render every metric in the Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    let metrics: [(&str, &str, &str, &AtomicU64); 6] = [
+        ("mirra_sent_bytes_total", "counter", "Bytes of file data sent to nodes", &BYTES_SENT),
+        ("mirra_received_bytes_total", "counter", "Bytes of file data received from roots", &BYTES_RECEIVED),
+        ("mirra_sent_files_total", "counter", "Files sent to nodes", &FILES_SENT),
+        ("mirra_received_files_total", "counter", "Files received from roots", &FILES_RECEIVED),
+        ("mirra_active_connections", "gauge", "Connections the sync server is handling", &CONNECTIONS),
+        ("mirra_sync_errors_total", "counter", "Connections and syncs that ended with an error", &SYNC_ERRORS),
+    ];
+    for (name, kind, help, value) in metrics {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value.load(Ordering::Relaxed));
+    }
+
+    let _ = writeln!(out, "# HELP mirra_last_sync_timestamp_seconds When the last full sync of a module finished\n# TYPE mirra_last_sync_timestamp_seconds gauge");
+    let last_syncs = last_syncs().lock().unwrap();
+    let mut modules: Vec<_> = last_syncs.iter().collect();
+    modules.sort();
+    for (module, time) in modules {
+        let _ = writeln!(out, "mirra_last_sync_timestamp_seconds{{module=\"{}\"}} {}", escape_label(module), time);
+    }
+    out
+}
@@ -0,0 +1,401 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex, DuplexStream, ReadHalf, split, WriteHalf};
+use tokio::sync::{mpsc, Mutex, Semaphore};
+
+use crate::Client;
+use crate::packet::{Multiplex, Ok};
+use crate::socket::Stream;
+
+/// How much received data each channel buffers, the remote end never sends more than that before it's read
+const CHANNEL_BUFFER: usize = 1024 * 1024;
+/// The largest frame that is sent or accepted
+const MAX_FRAME: usize = 0x10000;
+/// Set in the length of frames that carry no data, but let the remote end send that many more bytes
+const WINDOW_FLAG: u32 = 0x80000000;
+/// How many frames may be waiting to be sent
+const SEND_QUEUE: usize = 64;
+/// Seconds connecting to a remote mirra may take unless `connect_timeout` is set, so an unreachable one fails quickly
+pub const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+/// How often connecting to a remote mirra is tried again before giving up, unless `connect_retries` is set
+pub const DEFAULT_CONNECT_RETRIES: u32 = 2;
+
+static CONNECT_TIMEOUT: AtomicU64 = AtomicU64::new(DEFAULT_CONNECT_TIMEOUT);
+static CONNECT_RETRIES: AtomicU32 = AtomicU32::new(DEFAULT_CONNECT_RETRIES);
+
+/// Give up on connecting to a remote mirra after [timeout] seconds and [retries] more tries from now on
+pub fn set_connect_limits(timeout: u64, retries: u32) {
+    CONNECT_TIMEOUT.store(timeout.max(1), Ordering::Relaxed);
+    CONNECT_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// How long connecting to a remote mirra may take
+pub fn connect_timeout() -> Duration {
+    Duration::from_secs(CONNECT_TIMEOUT.load(Ordering::Relaxed))
+}
+
+/// How often connecting to a remote mirra is tried again before giving up
+pub fn connect_retries() -> u32 {
+    CONNECT_RETRIES.load(Ordering::Relaxed)
+}
+
+/// Something to send for a channel
+enum Frame {
+    /// Data for the channel, or nothing to close it
+    Data(u16, Vec<u8>),
+    /// Room for that many more bytes of data, because the channel's client read them
+    Window(u16, u32),
+}
+
+/// This side's end of an open channel
+struct Channel {
+    /// Where data received for the channel goes, until its client reads it
+    received: mpsc::UnboundedSender<Vec<u8>>,
+    /// How many received bytes the client didn't read yet
+    pending: Arc<AtomicUsize>,
+    /// How many bytes the remote end has room for
+    credit: Arc<Semaphore>,
+}
+
+/// The channels of a multiplexed connection
+struct Channels {
+    open: HashMap<u16, Channel>,
+    /// Frames to send, taken once the connection closes
+    frames: Option<mpsc::Sender<Frame>>,
+    /// The next id that the opening side will use
+    next_id: u16,
+    /// Every id the accepting side has seen, so late data for closed channels doesn't reopen them
+    seen: HashSet<u16>,
+}
+
+/// A connection that carries several [Client]s, each in a channel of its own
+/// Every frame is a u16 channel id, a u32 length and that many bytes, an empty frame closes a channel
+/// A length with [WINDOW_FLAG] set carries no data, it lets the remote end send that many more bytes,
+/// so a channel whose client reads slowly never holds up the others
+#[derive(Clone)]
+pub struct Mux {
+    channels: Arc<Mutex<Channels>>,
+    peer: SocketAddr,
+    /// Whether this side opens channels, and hangs up once all of them are closed
+    opens: bool,
+}
+
+impl Mux {
+    fn new(stream: Stream, peer: SocketAddr, opens: bool) -> (Self, ReadHalf<Stream>) {
+        let (reader, writer) = split(stream);
+        let (frames, queue) = mpsc::channel(SEND_QUEUE);
+        tokio::spawn(write_frames(writer, queue));
+
+        let mux = Mux {
+            channels: Arc::new(Mutex::new(Channels {
+                open: HashMap::new(),
+                frames: Some(frames),
+                next_id: 0,
+                seen: HashSet::new(),
+            })),
+            peer,
+            opens,
+        };
+        (mux, reader)
+    }
+
+    /// Multiplex [stream] to [peer], opening channels from this side
+    pub fn connect(stream: Stream, peer: SocketAddr) -> Self {
+        let (mux, reader) = Mux::new(stream, peer, true);
+        let local = mux.clone();
+        tokio::spawn(async move {
+            if let Err(e) = local.read_frames(reader, None).await {
+                debug!("Multiplexed connection to {} failed: {}", local.peer, e);
+            }
+            local.close_all().await;
+        });
+        mux
+    }
+
+    /// Serve channels that [peer] opens over [stream], handing each one to [incoming]
+    /// Returns once the connection is closed
+    pub async fn accept(stream: Stream, peer: SocketAddr, incoming: mpsc::Sender<Client>) -> Result<()> {
+        let (mux, reader) = Mux::new(stream, peer, false);
+        let res = mux.read_frames(reader, Some(incoming)).await;
+        mux.close_all().await;
+        match res {
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(()),
+            res => res,
+        }
+    }
+
+    /// Open a new channel to the remote end
+    pub async fn open(&self) -> Result<Client> {
+        let mut channels = self.channels.lock().await;
+        if channels.frames.is_none() {
+            return Err(Error::new(ErrorKind::NotConnected, "multiplexed connection is closed"));
+        }
+        let id = channels.next_id;
+        channels.next_id = id.checked_add(1)
+            .ok_or_else(|| Error::new(ErrorKind::Other, "out of channel ids"))?;
+        Ok(self.add_channel(&mut channels, id))
+    }
+
+    /// Register channel [id], returning a client for it
+    fn add_channel(&self, channels: &mut Channels, id: u16) -> Client {
+        let (inner, outer) = duplex(CHANNEL_BUFFER);
+        let (outer_read, outer_write) = split(outer);
+        let (received, queue) = mpsc::unbounded_channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let credit = Arc::new(Semaphore::new(CHANNEL_BUFFER));
+
+        let frames = channels.frames.clone().unwrap();
+        tokio::spawn(deliver(id, outer_write, queue, pending.clone(), frames.clone()));
+        tokio::spawn(self.clone().pump(id, outer_read, frames, credit.clone()));
+        channels.open.insert(id, Channel { received, pending, credit });
+        Client::from_connection(Box::new(inner), self.peer)
+    }
+
+    /// Send everything written to channel [id] to the remote end, and close the channel once its client is gone
+    async fn pump(self, id: u16, mut from: ReadHalf<DuplexStream>, frames: mpsc::Sender<Frame>, credit: Arc<Semaphore>) {
+        let mut buf = vec![0; MAX_FRAME];
+        loop {
+            let read = match from.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => read,
+            };
+            // Never send more than the remote end has room for, the channel is closed if that never happens
+            match credit.acquire_many(read as u32).await {
+                Ok(permit) => permit.forget(),
+                Err(_) => break,
+            }
+            if frames.send(Frame::Data(id, buf[..read].to_vec())).await.is_err() {
+                break;
+            }
+        }
+        let _ = frames.send(Frame::Data(id, Vec::new())).await;
+        drop(frames);
+
+        let mut channels = self.channels.lock().await;
+        remove_channel(&mut channels, id);
+        // Without any channels left, the connection has no purpose anymore
+        if self.opens && channels.open.is_empty() {
+            channels.frames = None;
+        }
+    }
+
+    /// Hand received frames to their channels until the connection closes
+    async fn read_frames(&self, mut reader: ReadHalf<Stream>, incoming: Option<mpsc::Sender<Client>>) -> Result<()> {
+        loop {
+            let id = reader.read_u16().await?;
+            let len = reader.read_u32().await?;
+            if len & WINDOW_FLAG != 0 {
+                let room = (len & !WINDOW_FLAG) as usize;
+                let mut channels = self.channels.lock().await;
+                if let Some(channel) = channels.open.get(&id) {
+                    if channel.credit.available_permits() + room > CHANNEL_BUFFER {
+                        warn!("Closing channel {} to {}, it made room for more than it buffers", id, self.peer);
+                        close_channel(&mut channels, id).await;
+                    } else {
+                        channel.credit.add_permits(room);
+                    }
+                }
+                continue;
+            }
+
+            let len = len as usize;
+            if len > MAX_FRAME {
+                return Err(Error::new(ErrorKind::InvalidData, "frame too large"));
+            }
+            let mut data = vec![0; len];
+            reader.read_exact(&mut data).await?;
+
+            let mut channels = self.channels.lock().await;
+            if data.is_empty() {
+                remove_channel(&mut channels, id);
+                continue;
+            }
+
+            // The remote end opened a new channel
+            if let Some(incoming) = &incoming {
+                if channels.seen.insert(id) {
+                    let client = self.add_channel(&mut channels, id);
+                    if incoming.send(client).await.is_err() {
+                        // Nobody is accepting channels anymore
+                        return Ok(());
+                    }
+                }
+            }
+
+            // Only queue the data, waiting for a slow client here would hold up every other channel
+            if let Some(channel) = channels.open.get(&id) {
+                let overflow = channel.pending.fetch_add(len, Ordering::Relaxed) + len > CHANNEL_BUFFER;
+                if overflow {
+                    warn!("Closing channel {} to {}, it sent more than the channel buffers", id, self.peer);
+                }
+                // The client is gone otherwise
+                if overflow || channel.received.send(data).is_err() {
+                    close_channel(&mut channels, id).await;
+                }
+            }
+        }
+    }
+
+    /// Close every channel and stop sending
+    async fn close_all(&self) {
+        let mut channels = self.channels.lock().await;
+        for (_, channel) in channels.open.drain() {
+            channel.credit.close();
+        }
+        channels.frames = None;
+    }
+}
+
+/// Forget channel [id], so nothing is delivered to or sent from it anymore
+fn remove_channel(channels: &mut Channels, id: u16) {
+    if let Some(channel) = channels.open.remove(&id) {
+        channel.credit.close();
+    }
+}
+
+/// Close channel [id] from this side, telling the remote end about it
+async fn close_channel(channels: &mut Channels, id: u16) {
+    remove_channel(channels, id);
+    if let Some(frames) = &channels.frames {
+        let _ = frames.send(Frame::Data(id, Vec::new())).await;
+    }
+}
+
+/// Write data received for channel [id] to its client as it reads it, making room for more on the remote end
+/// Once the channel is closed, the client reads the end of it after everything that was received
+async fn deliver(id: u16, mut to: WriteHalf<DuplexStream>, mut queue: mpsc::UnboundedReceiver<Vec<u8>>, pending: Arc<AtomicUsize>, frames: mpsc::Sender<Frame>) {
+    while let Some(data) = queue.recv().await {
+        if to.write_all(&data).await.is_err() {
+            break;
+        }
+        pending.fetch_sub(data.len(), Ordering::Relaxed);
+        if frames.send(Frame::Window(id, data.len() as u32)).await.is_err() {
+            break;
+        }
+    }
+    let _ = to.shutdown().await;
+}
+
+/// Write queued frames to the connection, until every sender is gone
+async fn write_frames(mut writer: WriteHalf<Stream>, mut queue: mpsc::Receiver<Frame>) {
+    while let Some(frame) = queue.recv().await {
+        let res = async {
+            match frame {
+                Frame::Data(id, data) => {
+                    writer.write_u16(id).await?;
+                    writer.write_u32(data.len() as u32).await?;
+                    writer.write_all(&data).await
+                }
+                Frame::Window(id, room) => {
+                    writer.write_u16(id).await?;
+                    writer.write_u32(room | WINDOW_FLAG).await
+                }
+            }
+        }.await;
+        if res.is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+/// Multiplexed connections to remote mirras, shared by every module synced from the same one
+#[derive(Default)]
+pub struct MuxPool {
+    connections: Mutex<HashMap<String, Mux>>,
+}
+
+impl MuxPool {
+    /// Open a channel to the mirra at [addr], connecting to it first if needed
+    pub async fn open(&self, addr: String) -> Result<Client> {
+        let mut connections = self.connections.lock().await;
+        if let Some(mux) = connections.get(&addr) {
+            if let Ok(client) = mux.open().await {
+                return Ok(client);
+            }
+        }
+
+        let mut client = Client::connect_with(addr.clone(), connect_timeout(), connect_retries()).await?;
+        client.send(Multiplex::new()).await?;
+        client.expect::<Ok>().await?;
+        info!("Connected to {}", addr);
+
+        let peer = client.peer_addr();
+        let mux = Mux::connect(client.into_connection(), peer);
+        let channel = mux.open().await?;
+        connections.insert(addr, mux);
+        Ok(channel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    use super::{CHANNEL_BUFFER, MAX_FRAME, Mux};
+
+    /// Two ends of a multiplexed connection, and where the accepting end's channels arrive
+    fn connected() -> (Mux, mpsc::Receiver<crate::Client>) {
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let (near, far) = duplex(MAX_FRAME);
+        let (channels, incoming) = mpsc::channel(4);
+        tokio::spawn(Mux::accept(Box::new(far), peer, channels));
+        (Mux::connect(Box::new(near), peer), incoming)
+    }
+
+    #[tokio::test]
+    async fn slow_channel_doesnt_hold_up_others() {
+        let (mux, mut incoming) = connected();
+
+        // Send far more than a channel buffers to one that is never read
+        let mut slow = mux.open().await.unwrap().into_connection();
+        tokio::spawn(async move {
+            let _ = slow.write_all(&vec![0; CHANNEL_BUFFER * 4]).await;
+        });
+        let _unread = incoming.recv().await.unwrap();
+
+        let exchange = async {
+            let mut fast = mux.open().await.unwrap().into_connection();
+            fast.write_all(b"ping").await.unwrap();
+            let mut remote = incoming.recv().await.unwrap().into_connection();
+            let mut buf = [0; 4];
+            remote.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            remote.write_all(b"pong").await.unwrap();
+            fast.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"pong");
+        };
+        timeout(Duration::from_secs(5), exchange).await.expect("the slow channel held up the other one");
+    }
+
+    #[tokio::test]
+    async fn closing_a_channel_ends_it_on_the_other_side() {
+        let (mux, mut incoming) = connected();
+
+        let mut channel = mux.open().await.unwrap().into_connection();
+        channel.write_all(b"hi").await.unwrap();
+        let remote = incoming.recv().await.unwrap();
+        drop(remote);
+
+        let mut buf = [0; 1];
+        let read = timeout(Duration::from_secs(5), channel.read(&mut buf)).await.expect("the channel wasn't closed");
+        assert_eq!(read.unwrap(), 0);
+    }
+}
@@ -4,177 +4,1359 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind, Result};
 use std::io::ErrorKind::InvalidData;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as std_mpsc;
+use async_recursion::async_recursion;
+use futures_util::future::join_all;
 use log::{debug, info, warn};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 
 use tokio::fs;
 use tokio::fs::{File, OpenOptions};
+use tokio::net::lookup_host;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant, sleep_until};
 
-use crate::{Client, LocalKeys};
-use crate::config::{Config, RootSync};
-use crate::packet::{FileHeader, Ok, Skip, Handshake, PacketKind, Remove, Rename};
-use crate::util::{AsyncFileLock, hash_file, stringify};
+use crate::{Client, control, daemon, LocalKeys, origin, state};
+use crate::metrics;
+use crate::mux::MuxPool;
+use crate::config::{ConfigWatch, RootSync};
+use crate::delta::{block_checksums, block_size, MIN_DELTA_SIZE};
+use crate::packet::{BlockChecksums, Close, ErrorMsg, Fetch, FileHash, FileHeader, MakeDir, Manifest, NotFound, Ok, Origin, Skip, Handshake, PacketKind, Remove, RemoveMany, Rename, RenameMany, RequestFile, RequestSync, Resume, Want};
+use crate::util::{AsyncFileLock, cached_hash_file, format_address, format_size, hash_file, HashAlgo, local_path, mirra_dir, retry_read, Shutdown, shutdown_requested, stringify, wire_path, with_connection};
+
+/// Load a hash allowlist, containing one `algo:hex` hash per line, plain hashes are taken as blake3
+async fn load_allowlist(from: &str) -> Result<HashSet<String>> {
+    let content = fs::read_to_string(from).await?;
+    Ok(content.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| if l.contains(':') { l.to_string() } else { format!("blake3:{}", l) })
+        .collect())
+}
+
+/// Which files a sync accepts from its root, and what happens to them afterwards
+struct FileRules {
+    /// The only hashes that are accepted, if there is an allowlist
+    allowlist: Option<HashSet<String>>,
+    /// Files larger than this many bytes are refused
+    max_file_size: Option<u64>,
+    /// Whether received files are made read-only, and local changes to them reverted
+    read_only: bool,
+    /// The hash of every file received from the root
+    received: Mutex<HashMap<String, String>>,
+    /// Whether [received] changed since it was last saved
+    changed: AtomicBool,
+}
+
+impl FileRules {
+    /// Remember that [path] at [file_path] has the contents the root sent, and make it read-only if the sync is
+    async fn keep(&self, path: &str, hash: &str, file_path: &Path) -> Result<()> {
+        let previous = self.received.lock().unwrap().insert(path.to_string(), hash.to_string());
+        if previous.as_deref() != Some(hash) {
+            self.changed.store(true, Ordering::Relaxed);
+        }
+        if self.read_only {
+            let mut permissions = fs::metadata(file_path).await?.permissions();
+            // Setting them again would trigger another change event
+            if !permissions.readonly() {
+                permissions.set_readonly(true);
+                fs::set_permissions(file_path, permissions).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Forget [path] and everything below it, after the root removed it
+    fn forget(&self, path: &str) {
+        let below = format!("{}/", path);
+        self.received.lock().unwrap().retain(|p, _| p != path && !p.starts_with(&below));
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Move what is known about [old] and everything below it to [new], after the root renamed it
+    fn moved(&self, old: &str, new: &str) {
+        let mut received = self.received.lock().unwrap();
+        let below = format!("{}/", old);
+        let paths: Vec<String> = received.keys().filter(|p| *p == old || p.starts_with(&below)).cloned().collect();
+        for path in paths {
+            let hash = received.remove(&path).unwrap();
+            received.insert(format!("{}{}", new, &path[old.len()..]), hash);
+        }
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Forget every file that isn't in the root's [manifest]
+    fn retain(&self, manifest: &Manifest) {
+        let paths: HashSet<&String> = manifest.paths.iter().collect();
+        self.received.lock().unwrap().retain(|p, _| paths.contains(p));
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// What the root sent for [path], if anything
+    fn received(&self, path: &str) -> Option<String> {
+        self.received.lock().unwrap().get(path).cloned()
+    }
+
+    /// Store what was received for [module], if it changed since the last time
+    /// Failing to store it isn't fatal, it's tried again the next time
+    async fn save(&self, module: &str) {
+        if !self.changed.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let received = self.received.lock().unwrap().clone();
+        if let Err(e) = save_manifest(module, &received).await {
+            warn!("Failed to store the manifest of {}: {}", module, e);
+            self.changed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Where the hashes of every file received for [module] are stored
+fn manifest_path(module: &str) -> PathBuf {
+    mirra_dir().join("manifests").join(module)
+}
+
+/// Load the hashes stored for [module], one `algo:hex path` per line
+/// Returns an empty manifest if none was stored yet
+pub async fn load_manifest(module: &str) -> Result<HashMap<String, String>> {
+    let content = match fs::read_to_string(manifest_path(module)).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    content.lines()
+        .filter(|l| !l.is_empty())
+        .map(|l| match l.split_once(' ') {
+            Some((hash, path)) => Ok((path.to_string(), hash.to_string())),
+            None => Err(Error::new(ErrorKind::InvalidData, format!("malformed manifest line: {}", l))),
+        })
+        .collect()
+}
+
+/// Store the hashes of every file received for [module]
+async fn save_manifest(module: &str, received: &HashMap<String, String>) -> Result<()> {
+    let path = manifest_path(module);
+    fs::create_dir_all(path.parent().unwrap()).await?;
+
+    let mut paths: Vec<&String> = received.keys().collect();
+    paths.sort();
+    let content: String = paths.into_iter().map(|p| format!("{} {}\n", received[p], p)).collect();
+    // Write it next to the old one first, so it's never left half-written
+    let temp = path.with_extension("tmp");
+    fs::write(&temp, content).await?;
+    fs::rename(temp, path).await
+}
+
+/// Hash the local copy at [path] while holding its lock, along with its size
+async fn local_copy(path: &Path, algo: HashAlgo) -> Result<(String, u64)> {
+    let mut file = File::open(path).await?;
+    file.lock().await?;
+    let hash = cached_hash_file(path, &mut file, algo).await;
+    file.unlock().await?;
+    Ok((hash?, file.metadata().await?.len()))
+}
+
+/// Refuse a file from a remote mirra with [hash], if the allowlist doesn't explicitly allow it
+/// Returns true if it was refused
+async fn refuse_unlisted(client: &mut Client, rules: &FileRules, path: &str, hash: &str) -> Result<bool> {
+    match &rules.allowlist {
+        Some(allowlist) if !allowlist.contains(hash) => {
+            warn!("Rejecting {}, its hash {} is not in the allowlist", path, hash);
+            client.send(ErrorMsg::new(format!("hash {} is not in the allowlist", hash))).await?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Read a [FileHeader] and the fingerprint of the mirra the file's contents came from, if the remote mirra sends it
+async fn expect_header(client: &mut Client) -> Result<(FileHeader, String)> {
+    let header: FileHeader = client.expect_unchecked().await?;
+    let origin = if client.supports("origin") {
+        client.expect::<Origin>().await?.fingerprint
+    } else {
+        String::new()
+    };
+    Ok((header, origin))
+}
 
 /// Receive a file from a remote mirra
-async fn receive_file(client: &mut Client, header: FileHeader, into: PathBuf) -> Result<()> {
+/// Returns false if it arrived corrupted, so it can be requested again
+async fn receive_file(client: &mut Client, mut header: FileHeader, origin: &str, into: PathBuf, rules: &FileRules) -> Result<bool> {
+    // Hash local files the same way the remote mirra did, so they can be compared
+    let algo = match HashAlgo::of(&header.hash) {
+        Ok(algo) => algo,
+        Err(e) => {
+            client.send(ErrorMsg::new(e.to_string())).await?;
+            return Err(e);
+        }
+    };
+    // Part of the hash ends up in a file name, see [partial_path]
+    if let Err(e) = check_hex(&header.hash) {
+        client.send(ErrorMsg::new(e.to_string())).await?;
+        return Err(e);
+    }
+
+    // The root hashes large files while sending them, their hash follows in a [FileHash]
+    let trailing = header.hash.split_once(':').map_or(false, |(_, hex)| hex.is_empty());
+
+    // Refuse anything that isn't explicitly allowed, regardless of its signature
+    if !trailing && refuse_unlisted(client, rules, &header.path, &header.hash).await? {
+        return Ok(true);
+    }
+    if let Some(max_file_size) = rules.max_file_size {
+        if header.size > max_file_size {
+            warn!("Rejecting {}, it is larger than {}", header.path, format_size(max_file_size));
+            client.send(ErrorMsg::new(format!("file is larger than {}", format_size(max_file_size)))).await?;
+            return Ok(true);
+        }
+    }
+
     // Create absolute file path from received header path and local destination directory
-    let file_path = into.join(&header.path);
+    let file_path = match local_path(&header.path) {
+        Ok(relative) => into.join(relative),
+        Err(e) => {
+            warn!("Rejecting {}: {}", header.path, e);
+            client.send(ErrorMsg::new(e.to_string())).await?;
+            return Ok(true);
+        }
+    };
     // Check if the file is already on dist
-    if file_path.exists() {
-        // Open and lock file for hashing
-        let mut file = File::open(file_path.clone()).await?;
-        file.lock().await?;
-        let hash = hash_file(&mut file).await?;
-        file.unlock().await?;
-
-        // File is already on disk
-        if hash == header.hash {
-            info!("Skipping {}, already on disk", header.path);
-            client.send(Skip::new()).await?;
-            return Ok(());
+    let mut existing = 0;
+    if file_path.exists() && trailing {
+        // There's no hash to compare with yet, but the old version can still be used for a delta
+        existing = fs::metadata(&file_path).await.map_or(0, |metadata| metadata.len());
+    } else if file_path.exists() {
+        match retry_read(&header.path, || local_copy(&file_path, algo)).await {
+            // File is already on disk
+            Ok((hash, _)) if hash == header.hash => {
+                info!("Skipping {}, already on disk", header.path);
+                client.send(Skip::new()).await?;
+                rules.keep(&header.path, &header.hash, &file_path).await?;
+                return Ok(true);
+            }
+            Ok((_, len)) => existing = len,
+            // It's replaced anyway, so receive all of it instead of failing the sync
+            Err(e) => warn!("Can't read the local copy of {}: {}, receiving all of it", header.path, e),
         }
     }
 
-    // config
-    client.send(Ok::new()).await?;
+    // Continue an interrupted transfer of the same contents, if there was one and the root can
+    let part_path = partial_path(&file_path, &header.hash);
+    // Interrupted transfers of older contents can't be continued anymore
+    remove_stale_parts(&file_path, &part_path).await;
+    let offset = match fs::metadata(&part_path).await {
+        Ok(metadata) if client.supports("resume") && !trailing => metadata.len(),
+        _ => 0,
+    };
+
+    // Large files that only changed a bit are rebuilt from the old version
+    let mut basis = None;
+    if offset == 0 && existing >= MIN_DELTA_SIZE && client.supports("delta") {
+        let block_size = block_size(existing);
+        let mut file = File::open(file_path.clone()).await?;
+        let checksums = block_checksums(&mut file, block_size).await?;
+        client.send(BlockChecksums::new(block_size, checksums)).await?;
+        basis = Some((file, block_size));
+    } else if offset > 0 {
+        info!("Resuming {} at {}", header.path, format_size(offset));
+        client.send(Resume::new(offset)).await?;
+    } else {
+        client.send(Ok::new()).await?;
+    }
 
     // If the file is in a directory that previously didnt exist, create that
     if file_path.parent().is_some() && !file_path.parent().unwrap().exists() {
         fs::create_dir_all(file_path.parent().unwrap()).await?;
     }
 
-    // Create/overwrite file
+    // Create/continue the partial file, so the transfer can be resumed if it fails
     let file = OpenOptions::new()
         .write(true)
         .read(false)
-        .truncate(true)
+        .truncate(false)
         .create(true)
-        .open(file_path).await?;
+        .open(part_path.clone()).await?;
+
+    // Progress toward the same contents carries over from attempts that were interrupted
+    let transfer = format!("{}@{}", file_path.display(), header.hash);
+    if let Some((mut basis, block_size)) = basis {
+        info!("Receiving changes to {}", header.path);
+        let received = client.expect_delta(&mut basis, file, block_size, &transfer).await?;
+        info!("Received {} of {}", format_size(received as u64), header.path);
+    } else {
+        info!("Receiving {}", header.path);
+        client.expect_file(file, &transfer).await?;
+    }
+
+    if trailing {
+        let trailer: FileHash = client.expect().await?;
+        header.hash = trailer.hash;
+        if refuse_unlisted(client, rules, &header.path, &header.hash).await? {
+            fs::remove_file(part_path).await?;
+            return Ok(true);
+        }
+    }
+
+    // Make sure what ended up on disk is what was sent
+    let mut written = File::open(part_path.clone()).await?;
+    let hash = hash_file(&mut written, algo).await?;
+    if hash != header.hash {
+        drop(written);
+        fs::remove_file(part_path).await?;
+        warn!("Hash mismatch after receiving {}, requesting it again", header.path);
+        client.send(ErrorMsg::new(format!("hash mismatch after receiving {}", header.path))).await?;
+        return Ok(false);
+    }
 
-    info!("Receiving {}", header.path);
-    client.expect_file(file).await?;
+    // Replace the old file only once the new one is complete
+    fs::rename(part_path, &file_path).await?;
+    // Tell the change apart from local ones, so it isn't sent back to where it came from
+    let metadata = fs::metadata(&file_path).await?;
+    origin::record(&file_path, (metadata.modified().ok(), metadata.len()), origin);
+    rules.keep(&header.path, &header.hash, &file_path).await?;
+    metrics::file_received();
 
+    client.send(Ok::new()).await?;
+    Ok(true)
+}
+
+/// Make sure everything after the algorithm of [hash] is hex, as it should be
+fn check_hex(hash: &str) -> Result<()> {
+    let hex = hash.split_once(':').map(|(_, hex)| hex).unwrap_or(hash);
+    if hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Ok(())
+    } else {
+        Err(Error::new(InvalidData, format!("{} isn't a hex hash", hash)))
+    }
+}
+
+/// Where the partial download of a file with [hash] is stored
+fn partial_path(file_path: &Path, hash: &str) -> PathBuf {
+    let name = file_path.file_name().unwrap_or_default().to_string_lossy();
+    // Leave out the algorithm, colons aren't allowed in file names everywhere
+    let hex = hash.split_once(':').map(|(_, hex)| hex).unwrap_or(hash);
+    file_path.with_file_name(format!(".{}.{}.mirra-part", name, hex.chars().take(16).collect::<String>()))
+}
+
+/// Remove partial downloads of [file_path] other than [keep], which were left behind by interrupted transfers
+async fn remove_stale_parts(file_path: &Path, keep: &Path) {
+    let (dir, name) = match (file_path.parent(), file_path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return,
+    };
+    let mut list = match fs::read_dir(dir).await {
+        Ok(list) => list,
+        Err(_) => return,
+    };
+    let prefix = format!(".{}.", name);
+    while let Ok(Some(entry)) = list.next_entry().await {
+        let entry_name = entry.file_name().to_string_lossy().to_string();
+        // Parts of other files can start the same way, like `a.b`'s for `a`, but they don't end in just the hash
+        let hash = entry_name.strip_prefix(&prefix).and_then(|rest| rest.strip_suffix(".mirra-part"));
+        if hash.map_or(false, |hash| hash.chars().all(|c| c.is_ascii_hexdigit())) && entry.path() != keep {
+            debug!("Removing {}, it is from an older transfer", entry.path().display());
+            let _ = fs::remove_file(entry.path()).await;
+        }
+    }
+}
+
+/// Create an (empty) directory sent by a remote mirra
+async fn receive_make_dir(client: &mut Client, into: PathBuf) -> Result<()> {
+    let make_dir: MakeDir = client.expect_unchecked().await?;
+    info!("Creating directory {}", make_dir.path);
+    let relative = match local_path(&make_dir.path) {
+        Ok(relative) => relative,
+        Err(e) => {
+            client.send(ErrorMsg::new(e.to_string())).await?;
+            return Err(e);
+        }
+    };
+    fs::create_dir_all(into.join(relative)).await?;
     client.send(Ok::new()).await?;
     Ok(())
 }
 
-/// Sync the entire remote module
-async fn receive_sync(client: &mut Client, into: PathBuf) -> Result<()> {
+/// Compare a remote mirra's manifest with what's on disk, returning the files that differ
+async fn receive_manifest(client: &mut Client, into: &Path, rules: &FileRules) -> Result<Vec<String>> {
+    let manifest: Manifest = client.expect().await?;
+    if manifest.paths.len() != manifest.hashes.len() {
+        client.send(ErrorMsg::new("malformed manifest".to_string())).await?;
+        return Err(Error::new(ErrorKind::InvalidData, "malformed manifest"));
+    }
+    rules.retain(&manifest);
+
+    let mut wanted = Vec::new();
+    for (path, hash) in manifest.paths.iter().zip(manifest.hashes.iter()) {
+        let algo = match HashAlgo::of(hash) {
+            Ok(algo) => algo,
+            Err(e) => {
+                client.send(ErrorMsg::new(e.to_string())).await?;
+                return Err(e);
+            }
+        };
+
+        // Don't even ask for files that would be refused anyway
+        if let Some(allowlist) = &rules.allowlist {
+            if !allowlist.contains(hash) {
+                warn!("Not requesting {}, its hash {} is not in the allowlist", path, hash);
+                continue;
+            }
+        }
+
+        let file_path = match local_path(path) {
+            Ok(relative) => into.join(relative),
+            Err(e) => {
+                warn!("Not requesting {}: {}", path, e);
+                continue;
+            }
+        };
+        if file_path.is_file() {
+            let mut file = File::open(&file_path).await?;
+            file.lock().await?;
+            let local = cached_hash_file(&file_path, &mut file, algo).await;
+            file.unlock().await?;
+            if &local? == hash {
+                rules.keep(path, hash, &file_path).await?;
+                continue;
+            }
+        }
+        wanted.push(path.clone());
+    }
+
+    info!("Requesting {} of {} files", wanted.len(), manifest.paths.len());
+    Ok(wanted)
+}
+
+/// Receive [paths] of [module] over a connection of their own
+/// Returns the files that arrived corrupted
+async fn fetch_files(module: &str, sync: &RootSync, connections: &MuxPool, paths: Vec<String>, rules: &FileRules) -> Result<Vec<String>> {
+    let mut client = connections.open(format_address(&sync.address, sync.port)).await?;
+    if let Some(max_download) = sync.max_download_bytes_per_sec {
+        client.limit_download((max_download / sync.sync_concurrency as u64).max(1));
+    }
+
+    client.send(Fetch::new(module.to_string(), paths)).await?;
+    match client.read_packet_kind().await? {
+        PacketKind::Ok => {}
+        PacketKind::NotFound => return Err(Error::new(ErrorKind::NotFound, format!("{} not found on remote mirra", module))),
+        PacketKind::ErrorMsg => return Err(client.remote_error().await),
+        _ => return Err(Error::from(InvalidData)),
+    }
+    client.negotiate().await?;
+
+    let into = PathBuf::from(&sync.path);
+    let mut retry = Vec::new();
+    loop {
+        match client.read_packet_kind().await? {
+            PacketKind::FileHeader => {
+                let (header, origin) = expect_header(&mut client).await?;
+                let path = header.path.clone();
+                if !receive_file(&mut client, header, &origin, into.clone(), rules).await? {
+                    retry.push(path);
+                }
+            }
+            PacketKind::EndSync => {
+                client.send(Ok::new()).await?;
+                return Ok(retry);
+            }
+            PacketKind::ErrorMsg => return Err(client.remote_error().await),
+            _ => {
+                client.send(ErrorMsg::new("unexpected packet during fetch".to_string())).await?;
+                return Err(Error::from(InvalidData));
+            }
+        }
+    }
+}
+
+/// Sync the entire remote module, without acknowledging the end of the sync
+/// Files are received over up to `sync_concurrency` connections, the ones that arrived corrupted are added to [retry]
+async fn receive_sync(client: &mut Client, module: &str, sync: &RootSync, connections: &MuxPool, rules: &FileRules, retry: &mut Vec<String>) -> Result<()> {
+    let into = PathBuf::from(&sync.path);
+    let mut wanted = receive_manifest(client, &into, rules).await?;
+
+    // Deal the files out to the extra connections, the rest come over this one
+    let extra = (sync.sync_concurrency - 1).min(wanted.len().saturating_sub(1));
+    let mut fetched: Vec<Vec<String>> = vec![Vec::new(); extra];
+    if extra > 0 {
+        let mut own = Vec::new();
+        for (i, path) in wanted.into_iter().enumerate() {
+            match i % (extra + 1) {
+                0 => own.push(path),
+                n => fetched[n - 1].push(path),
+            }
+        }
+        wanted = own;
+        info!("Receiving files over {} connections", extra + 1);
+    }
+    client.send(Want::new(wanted)).await?;
+
+    let fetches = fetched.iter().map(|paths| fetch_files(module, sync, connections, paths.clone(), rules));
+    let (res, fetches) = tokio::join!(receive_files(client, into, rules, retry), join_all(fetches));
+    res?;
+
+    for (paths, fetch) in fetched.into_iter().zip(fetches) {
+        match fetch {
+            Ok(corrupted) => retry.extend(corrupted),
+            Err(e) => {
+                // Ask for them one by one later instead, files that did arrive are skipped then
+                warn!("Failed to fetch {} files: {}", paths.len(), e);
+                retry.extend(paths);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Receive the files of a full sync until it ends
+async fn receive_files(client: &mut Client, into: PathBuf, rules: &FileRules, retry: &mut Vec<String>) -> Result<()> {
     loop {
         let next = client.read_packet_kind().await?;
         // Remote mirra has gone through all files
         if next == PacketKind::EndSync {
-            // Acknowledge and return
-            client.send(Ok::new()).await?;
             break;
-        // Only [PacketKind::EndSync] and [PacketKind::FileHeader] are valid
+        } else if next == PacketKind::MakeDir {
+            receive_make_dir(client, into.clone()).await?;
+            continue;
+        } else if next == PacketKind::ErrorMsg {
+            return Err(client.remote_error().await);
+        // Only [PacketKind::EndSync], [PacketKind::MakeDir] and [PacketKind::FileHeader] are valid
         } else if next != PacketKind::FileHeader {
+            client.send(ErrorMsg::new("unexpected packet during sync".to_string())).await?;
             return Err(Error::from(ErrorKind::InvalidData));
         }
 
         // Receive another file from the remote mirra
-        let header: FileHeader = client.expect_unchecked().await?;
-        receive_file(client, header, into.clone()).await?;
+        let (header, origin) = expect_header(client).await?;
+        let path = header.path.clone();
+        if !receive_file(client, header, &origin, into.clone(), rules).await? {
+            retry.push(path);
+        }
     }
 
     Ok(())
 }
 
-/// The main node lifecycle
-pub async fn process_node(module: String, sync: RootSync) -> Result<()> {
-    // Connect to remote mirra
-    let mut client = Client::new(sync.address.clone() + ":" + &sync.port.to_string()).await?;
-    info!("Connected to {}", sync.address);
+/// Remove a file or directory removed on the remote mirra
+async fn remove_path(dir: &Path, path: String) -> Result<()> {
+    info!("Removing {}", path);
+
+    // Never remove anything outside of the module, or the module itself
+    let relative = match local_path(&path) {
+        Ok(relative) => relative,
+        Err(e) => {
+            warn!("Not removing {}: {}", path, e);
+            return Ok(());
+        }
+    };
+
+    let path = dir.join(&relative);
+    // The remote mirra can't tell what the path was anymore, so look at what's here
+    let res = match fs::symlink_metadata(&path).await {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&path).await,
+        Ok(_) => fs::remove_file(&path).await,
+        // Ignore files that are already deleted
+        Err(_) => Ok(()),
+    };
+    if let Err(e) = res {
+        warn!("Failed to delete {}: {}", stringify(&path)?, e);
+    }
+    Ok(())
+}
+
+/// Watch [dir] for local changes, sending them to [changes]
+/// The watcher stops once the returned handle is dropped
+fn watch_local_changes(dir: &Path, changes: mpsc::UnboundedSender<DebouncedEvent>) -> Result<RecommendedWatcher> {
+    let (tx, rx) = std_mpsc::channel();
+    let mut watcher = notify::watcher(tx, Duration::from_secs(1))
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    watcher.watch(dir, RecursiveMode::Recursive)
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    // notify only sends to blocking channels
+    std::thread::spawn(move || {
+        while let Ok(event) = rx.recv() {
+            if changes.send(event).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(watcher)
+}
+
+/// A file in a read-only sync that changed locally, and the hash the root sent for it
+struct LocalChange {
+    relative: String,
+    path: PathBuf,
+    hash: String,
+}
+
+/// Find out whether a local change to [path] inside the read-only sync at [dir] has to be undone
+/// Anything the root didn't send is removed right away, files it did send have to be hashed first
+async fn check_local_change(dir: &Path, path: &Path, rules: &FileRules) -> Result<Option<LocalChange>> {
+    let relative = match path.strip_prefix(dir) {
+        Ok(relative) => wire_path(relative)?,
+        Err(_) => return Ok(None),
+    };
+    // Partial downloads and directories are mirra's own business
+    if relative.ends_with(".mirra-part") || path.is_dir() {
+        return Ok(None);
+    }
+
+    match rules.received(&relative) {
+        Some(hash) => {
+            // Receiving a file and making it read-only are reported as changes too
+            if let Ok(metadata) = fs::metadata(path).await {
+                if metadata.permissions().readonly() && origin::is_received(path, (metadata.modified().ok(), metadata.len())) {
+                    return Ok(None);
+                }
+            }
+            Ok(Some(LocalChange { relative, path: path.to_path_buf(), hash }))
+        }
+        None if path.is_file() => {
+            warn!("Removing {}, it wasn't received from the root", relative);
+            fs::remove_file(path).await?;
+            Ok(None)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Hash the file of [change] in the background, so the sync keeps answering the root meanwhile
+/// The result is sent to [hashed], none if the file is gone or can't be read
+fn hash_local_change(change: LocalChange, hashed: mpsc::UnboundedSender<(LocalChange, Option<String>)>) {
+    tokio::spawn(async move {
+        let local = async {
+            let algo = HashAlgo::of(&change.hash)?;
+            hash_file(&mut File::open(&change.path).await?, algo).await
+        }.await.ok();
+        let _ = hashed.send((change, local));
+    });
+}
+
+/// Undo [change] once its file was hashed to [local]
+/// Files whose contents differ from what the root sent are requested again through [retry]
+async fn revert_local_change(change: LocalChange, local: Option<String>, rules: &FileRules, retry: &mut Vec<String>) -> Result<()> {
+    // The root sent something new while the file was hashed
+    if rules.received(&change.relative).as_ref() != Some(&change.hash) {
+        return Ok(());
+    }
+    if local.as_ref() == Some(&change.hash) {
+        // Only the permissions changed
+        rules.keep(&change.relative, &change.hash, &change.path).await?;
+    } else if !retry.contains(&change.relative) {
+        warn!("{} was changed locally, requesting it again", change.relative);
+        retry.push(change.relative);
+    }
+    Ok(())
+}
+
+/// Rename a file or directory renamed on the remote mirra
+/// Returns false if it couldn't be renamed, so the remote mirra can send it again
+async fn rename_path(dir: &Path, old: String, new: String) -> bool {
+    info!("Renaming {} -> {}", old, new);
+
+    // Like removing, never move anything out of or into a place outside of the module
+    let (from, to) = match (local_path(&old), local_path(&new)) {
+        (Ok(from), Ok(to)) => (dir.join(from), dir.join(to)),
+        (Err(e), _) | (_, Err(e)) => {
+            warn!("Not renaming {} -> {}: {}", old, new, e);
+            return false;
+        }
+    };
+    // The new parent might not have been synced yet
+    if let Some(parent) = to.parent() {
+        let _ = fs::create_dir_all(parent).await;
+    }
+
+    let res = match fs::rename(&from, &to).await {
+        // The target directory may already be partly there, so merge into it
+        Err(_) if from.is_dir() => move_dir(&from, &to).await,
+        res => res,
+    };
+    if let Err(e) = res {
+        warn!("Failed to rename {} -> {}: {}", old, new, e);
+        return false;
+    }
+    true
+}
+
+/// Move everything in [from] into [to], replacing files that are already there, then remove [from]
+#[async_recursion]
+async fn move_dir(from: &Path, to: &Path) -> Result<()> {
+    fs::create_dir_all(to).await?;
+    let mut entries = fs::read_dir(from).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let target = to.join(entry.file_name());
+        if entry.file_type().await?.is_dir() {
+            move_dir(&entry.path(), &target).await?;
+        } else {
+            fs::rename(entry.path(), target).await?;
+        }
+    }
+    fs::remove_dir(from).await
+}
+
+/// Open a channel to the remote mirra [module] is synced from and perform the handshake
+async fn connect_module(module: &str, sync: &RootSync, connections: &MuxPool) -> Result<Client> {
+    // Share the connection to the remote mirra with other modules synced from it
+    let mut client = connections.open(format_address(&sync.address, sync.port)).await?;
+    if let Some(max_download) = sync.max_download_bytes_per_sec {
+        client.limit_download(max_download);
+    }
 
     // Send handshake
-    client.send(Handshake::new(module.clone())).await?;
+    client.send(Handshake::new(module.to_string())).await?;
 
     let status = client.read_packet_kind().await?;
     // Close if remote mirra doesn't have the requested module
     if status == PacketKind::NotFound {
-        info!("{} not found on remote mirra", module);
         client.close().await?;
-        return Err(Error::from(ErrorKind::InvalidInput));
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{} not found on remote mirra", module)));
+    } else if status == PacketKind::ErrorMsg {
+        return Err(client.remote_error().await);
     } else if status != PacketKind::Ok {
         return Err(Error::from(ErrorKind::InvalidData));
     }
+    client.negotiate().await?;
 
     info!("Performed handshake");
+    Ok(client)
+}
+
+/// Load which files [sync] accepts, and what was already received for [module] if it's stored
+async fn load_rules(module: Option<&str>, sync: &RootSync) -> Result<FileRules> {
+    let allowlist = if let Some(allowlist) = &sync.allowlist {
+        Some(load_allowlist(allowlist).await?)
+    } else {
+        None
+    };
+    Ok(FileRules {
+        allowlist,
+        max_file_size: sync.max_file_size,
+        read_only: sync.read_only,
+        received: Mutex::new(match module {
+            Some(module) => load_manifest(module).await?,
+            None => HashMap::new(),
+        }),
+        changed: AtomicBool::new(false),
+    })
+}
+
+/// Receive a single full sync of [module] over [client], then close the connection
+/// Returns the files that arrived corrupted
+async fn receive_once(mut client: Client, module: &str, sync: &RootSync, connections: &MuxPool, rules: &FileRules) -> Result<Vec<String>> {
+    fs::create_dir_all(&sync.path).await?;
+
+    // The root starts with a full sync right after the handshake
+    match client.read_packet_kind().await? {
+        PacketKind::BeginSync => {}
+        PacketKind::ErrorMsg => return Err(client.remote_error().await),
+        _ => return Err(Error::from(InvalidData)),
+    };
+    client.send(Ok::new()).await?;
+    info!("Performing a full sync");
+
+    let mut corrupted = Vec::new();
+    receive_sync(&mut client, module, sync, connections, rules, &mut corrupted).await?;
+
+    // Ending the sync with a close instead of an acknowledgement tells the root not to wait for changes
+    client.close().await?;
+    Ok(corrupted)
+}
+
+/// Receive [module] once, without staying connected for changes
+/// Returns the files that arrived corrupted
+pub async fn pull_once(module: String, sync: RootSync) -> Result<Vec<String>> {
+    let connections = MuxPool::default();
+    let client = connect_module(&module, &sync, &connections).await?;
+    // Nothing is stored, the module isn't in Mirra.toml
+    let rules = load_rules(None, &sync).await?;
+    receive_once(client, &module, &sync, &connections, &rules).await
+}
+
+/// Go through a full sync without receiving any files, returning the root's manifest
+async fn skip_sync(client: &mut Client) -> Result<Manifest> {
+    let manifest: Manifest = client.expect().await?;
+    client.send(Want::new(Vec::new())).await?;
+    // Empty directories are still announced, but nothing is created for them
+    loop {
+        match client.read_packet_kind().await? {
+            PacketKind::MakeDir => {
+                client.expect_unchecked::<MakeDir>().await?;
+                client.send(Ok::new()).await?;
+            }
+            PacketKind::EndSync => return Ok(manifest),
+            PacketKind::ErrorMsg => return Err(client.remote_error().await),
+            _ => return Err(Error::from(InvalidData)),
+        }
+    }
+}
+
+/// Acknowledge the change [kind] without applying it, so a paused module stays as it is
+/// Returns false if [kind] isn't a change
+async fn skip_change(client: &mut Client, kind: &PacketKind) -> Result<bool> {
+    match kind {
+        PacketKind::BeginSync => {
+            client.send(Ok::new()).await?;
+            skip_sync(client).await?;
+            client.send(Ok::new()).await?;
+        }
+        PacketKind::FileHeader => {
+            expect_header(client).await?;
+            client.send(Skip::new()).await?;
+        }
+        PacketKind::MakeDir => {
+            client.expect_unchecked::<MakeDir>().await?;
+            client.send(Ok::new()).await?;
+        }
+        PacketKind::Remove => {
+            client.expect_unchecked::<Remove>().await?;
+            client.send(Ok::new()).await?;
+        }
+        PacketKind::RemoveMany => {
+            client.expect_unchecked::<RemoveMany>().await?;
+            client.send(Ok::new()).await?;
+        }
+        PacketKind::Rename => {
+            client.expect_unchecked::<Rename>().await?;
+            client.send(Ok::new()).await?;
+        }
+        PacketKind::RenameMany => {
+            client.expect_unchecked::<RenameMany>().await?;
+            client.send(Ok::new()).await?;
+        }
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
+/// Fetch the manifest of [module] from its root, without receiving any files
+pub async fn fetch_manifest(module: String, sync: RootSync) -> Result<HashMap<String, String>> {
+    let connections = MuxPool::default();
+    let mut client = connect_module(&module, &sync, &connections).await?;
+    match client.read_packet_kind().await? {
+        PacketKind::BeginSync => {}
+        PacketKind::ErrorMsg => return Err(client.remote_error().await),
+        _ => return Err(Error::from(InvalidData)),
+    };
+    client.send(Ok::new()).await?;
+
+    let manifest = skip_sync(&mut client).await?;
+    client.close().await?;
+
+    if manifest.paths.len() != manifest.hashes.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "malformed manifest"));
+    }
+    Ok(manifest.paths.into_iter().zip(manifest.hashes).collect())
+}
+
+/// How a module on disk differs from its manifest
+#[derive(Default)]
+pub struct Differences {
+    /// Files in the manifest that aren't on disk
+    pub missing: Vec<String>,
+    /// Files whose contents don't match their hash
+    pub changed: Vec<String>,
+    /// Files on disk that aren't in the manifest
+    pub extra: Vec<String>,
+}
+
+/// Add every file below [dir] to [files], relative to [base]
+#[async_recursion]
+async fn list_files(base: &Path, dir: &Path, files: &mut HashSet<String>) -> Result<()> {
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file_type = entry.file_type().await?;
+        if file_type.is_dir() {
+            list_files(base, &entry.path(), files).await?;
+        } else if file_type.is_file() {
+            files.insert(wire_path(entry.path().strip_prefix(base).unwrap())?);
+        }
+    }
+    Ok(())
+}
+
+/// Hash every file of the module at [dir] again and compare it with [manifest]
+pub async fn verify_module(dir: &Path, manifest: &HashMap<String, String>) -> Result<Differences> {
+    let mut on_disk = HashSet::new();
+    list_files(dir, dir, &mut on_disk).await?;
+
+    let mut differences = Differences::default();
+    let mut paths: Vec<&String> = manifest.keys().collect();
+    paths.sort();
+    for path in paths {
+        if !on_disk.remove(path) {
+            differences.missing.push(path.clone());
+            continue;
+        }
+        let hash = &manifest[path];
+        let mut file = File::open(dir.join(local_path(path)?)).await?;
+        if &hash_file(&mut file, HashAlgo::of(hash)?).await? != hash {
+            differences.changed.push(path.clone());
+        }
+    }
+
+    // Partial downloads are expected to be there
+    differences.extra = on_disk.into_iter().filter(|p| !p.ends_with(".mirra-part")).collect();
+    differences.extra.sort();
+    Ok(differences)
+}
+
+/// Receive [module] from a root that connected to push it, if its sync accepts pushes
+/// Whether [peer] is one of the addresses the root of [sync] resolves to
+async fn is_sync_root(sync: &RootSync, peer: IpAddr) -> bool {
+    // Dual-stack sockets see IPv4 peers as IPv4-mapped IPv6 addresses
+    let canonical = |ip: IpAddr| match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    };
+    match lookup_host(format_address(&sync.address, sync.port)).await {
+        Ok(mut addrs) => addrs.any(|addr| canonical(addr.ip()) == canonical(peer)),
+        Err(_) => false,
+    }
+}
+
+pub async fn receive_push(mut client: Client, config: &ConfigWatch, module: String) -> Result<()> {
+    let sync = config.borrow().syncs.get(&module).filter(|sync| sync.accept_push).cloned();
+    let sync = match sync {
+        // Files can only come over this connection, the root can't be asked for more
+        Some(sync) => RootSync { sync_concurrency: 1, ..sync },
+        None => {
+            warn!("Refusing a push of {}, it isn't synced with accept_push", module);
+            client.send(NotFound::new()).await?;
+            return Ok(());
+        }
+    };
+    // Only the root the module is synced from may push it
+    let peer = client.peer_addr().ip();
+    if !is_sync_root(&sync, peer).await {
+        warn!("Refusing a push of {} from {}, it is synced from {}", module, peer, sync.address);
+        client.send(NotFound::new()).await?;
+        return Ok(());
+    }
+    client.send(Ok::new()).await?;
+    client.negotiate().await?;
+
+    info!("Receiving a push of {}", module);
+    let rules = load_rules(Some(&module), &sync).await?;
+    let corrupted = receive_once(client, &module, &sync, &MuxPool::default(), &rules).await?;
+    metrics::synced(&module);
+    state::synced(&module);
+    rules.save(&module).await;
+    state::save().await;
+    for path in corrupted {
+        warn!("{} arrived corrupted, it is fixed by the next push", path);
+    }
+    Ok(())
+}
+
+/// The main node lifecycle
+/// Full syncs are requested whenever [resync] changes
+pub async fn process_node(module: String, sync: RootSync, connections: Arc<MuxPool>, resync: watch::Receiver<u64>, mut shutdown: Shutdown) -> Result<()> {
+    let mut client = connect_module(&module, &sync, &connections).await?;
+    let rules = load_rules(Some(&module), &sync).await?;
 
     // Create target directory if it doesn't exist
-    let dir = PathBuf::from(sync.path);
+    let dir = PathBuf::from(&sync.path);
     if !dir.exists() {
         fs::create_dir_all(dir.clone()).await?;
     }
 
+    // Files to request again, and the last resync request that was passed on
+    let mut retry = Vec::new();
+    let mut resynced = *resync.borrow();
+    // Whether changes were skipped while the module was paused, which a full sync catches up on
+    let mut missed = false;
+
+    // Read-only syncs undo anything that changes locally
+    // Changes are reported with absolute paths
+    let (changes_tx, mut changes) = mpsc::unbounded_channel();
+    let (hashed_tx, mut hashed) = mpsc::unbounded_channel();
+    let watched = fs::canonicalize(&dir).await?;
+    let _watcher = if sync.read_only {
+        Some(watch_local_changes(&watched, changes_tx)?)
+    } else {
+        None
+    };
+
     loop {
-        let next = client.read_packet_kind().await?;
+        // Only stop between packets, so no file is left half-written
+        let next = tokio::select! {
+            next = client.read_packet_kind() => next?,
+            Some(change) = changes.recv() => {
+                let paths = match change {
+                    DebouncedEvent::Create(path) | DebouncedEvent::Write(path) |
+                    DebouncedEvent::Chmod(path) | DebouncedEvent::Remove(path) => vec![path],
+                    DebouncedEvent::Rename(old, new) => vec![old, new],
+                    _ => Vec::new(),
+                };
+                for path in paths {
+                    if let Some(change) = check_local_change(&watched, &path, &rules).await? {
+                        hash_local_change(change, hashed_tx.clone());
+                    }
+                }
+                continue;
+            }
+            Some((change, local)) = hashed.recv() => {
+                revert_local_change(change, local, &rules, &mut retry).await?;
+                continue;
+            }
+            _ = shutdown_requested(&mut shutdown) => {
+                info!("Closing connection to {}", sync.address);
+                rules.save(&module).await;
+                client.send(Close::new()).await?;
+                return Ok(());
+            }
+        };
+
+        // A paused module is still told about every change, but doesn't apply any
+        if control::is_paused(&module) && skip_change(&mut client, &next).await? {
+            if !missed {
+                info!("{} is paused, skipping changes until it is resumed", module);
+                missed = true;
+            }
+            continue;
+        }
 
         match next {
             // Just a heartbeat, acknowledge and continue
+            // The root waits for the answer, so it's also the time to ask it for something
             PacketKind::Heartbeat => {
-                client.send(Ok::new()).await?;
+                if !dir.exists() {
+                    warn!("{} disappeared, requesting a full sync", stringify(&dir)?);
+                    fs::create_dir_all(dir.clone()).await?;
+                    client.send(RequestSync::new()).await?;
+                    retry.clear();
+                } else if *resync.borrow() != resynced {
+                    resynced = *resync.borrow();
+                    info!("Requesting a full sync of {}", module);
+                    client.send(RequestSync::new()).await?;
+                    retry.clear();
+                } else if missed && !control::is_paused(&module) {
+                    missed = false;
+                    info!("{} was resumed, requesting a full sync", module);
+                    client.send(RequestSync::new()).await?;
+                    retry.clear();
+                } else if let Some(path) = retry.pop() {
+                    info!("Requesting {} again", path);
+                    client.send(RequestFile::new(path)).await?;
+                } else {
+                    client.send(Ok::new()).await?;
+                }
+                rules.save(&module).await;
+                state::save().await;
                 debug!("Heartbeat");
             }
             // Sync the entire module
             PacketKind::BeginSync => {
                 client.send(Ok::new()).await?;
                 info!("Performing a full sync");
-                receive_sync(&mut client, dir.clone()).await?;
+                receive_sync(&mut client, &module, &sync, &connections, &rules, &mut retry).await?;
+                // Only acknowledge once everything arrived, so changes made in the meantime come after
+                client.send(Ok::new()).await?;
+                metrics::synced(&module);
+                state::synced(&module);
+                rules.save(&module).await;
+                state::save().await;
             }
             // Sync a single file
             PacketKind::FileHeader => {
                 info!("Single file sync");
-                let header = client.expect_unchecked().await?;
-                receive_file(&mut client, header, dir.clone()).await?;
+                let (header, origin) = expect_header(&mut client).await?;
+                // Mirras that sync from each other would otherwise send the change back and forth forever
+                if origin == origin::local() {
+                    info!("Skipping {}, the change was made here", header.path);
+                    client.send(Skip::new()).await?;
+                    continue;
+                }
+                let path = header.path.clone();
+                if !receive_file(&mut client, header, &origin, dir.clone(), &rules).await? {
+                    retry.push(path);
+                }
+                state::changed(&module);
+            }
+            // Create an empty directory
+            PacketKind::MakeDir => {
+                receive_make_dir(&mut client, dir.clone()).await?;
+                state::changed(&module);
             }
             // Remove a file
             PacketKind::Remove => {
                 let remove: Remove = client.expect_unchecked().await?;
                 client.send(Ok::new()).await?;
-
-                info!("Removing {}", remove.path.clone());
-
-                let path = dir.join(remove.path);
-                // Ignore files that are already deleted, and directories
-                if path.exists() && path.is_file() && fs::remove_file(path.clone()).await.is_err() {
-                    warn!("Failed to delete {} due to lack of permissions", stringify(&path)?);
+                rules.forget(&remove.path);
+                remove_path(&dir, remove.path).await?;
+                state::changed(&module);
+            }
+            // Remove a burst of files, acknowledged all at once
+            PacketKind::RemoveMany => {
+                let remove: RemoveMany = client.expect_unchecked().await?;
+                client.send(Ok::new()).await?;
+                for path in remove.paths {
+                    rules.forget(&path);
+                    remove_path(&dir, path).await?;
                 }
+                state::changed(&module);
             }
             // Rename a file
             PacketKind::Rename => {
                 let rename: Rename = client.expect_unchecked().await?;
-                client.send(Ok::new()).await?;
-
-                info!("Renaming {} -> {}", rename.old.clone(), rename.new.clone());
-
-                let res = fs::rename(dir.join(rename.old.clone()), dir.join(rename.new.clone())).await;
-                if res.is_err() {
-                    warn!("Failed to rename {} -> {}: {}", rename.old, rename.new, res.err().unwrap().to_string());
+                rules.moved(&rename.old, &rename.new);
+                if rename_path(&dir, rename.old, rename.new.clone()).await {
+                    client.send(Ok::new()).await?;
+                } else {
+                    client.send(Want::new(vec![rename.new])).await?;
                 }
+                state::changed(&module);
+            }
+            // Rename a burst of files, acknowledged all at once
+            PacketKind::RenameMany => {
+                let rename: RenameMany = client.expect_unchecked().await?;
+                let mut failed = Vec::new();
+                for (old, new) in rename.old.into_iter().zip(rename.new) {
+                    rules.moved(&old, &new);
+                    if !rename_path(&dir, old, new.clone()).await {
+                        failed.push(new);
+                    }
+                }
+                if failed.is_empty() {
+                    client.send(Ok::new()).await?;
+                } else {
+                    client.send(Want::new(failed)).await?;
+                }
+                state::changed(&module);
+            }
+            // Remote mirra is shutting down
+            PacketKind::Close => {
+                client.send(Close::new()).await?;
+                info!("{} closed the connection", sync.address);
+                return Ok(());
+            }
+            // Remote mirra ran into a problem
+            PacketKind::ErrorMsg => {
+                return Err(client.remote_error().await);
             }
             _ => {
                 // politely deny that
-                client.close().await?;
+                client.send(ErrorMsg::new("unexpected packet".to_string())).await?;
                 return Err(Error::from(InvalidData));
             }
         }
     }
 }
 
+/// How long a sync that failed or was disconnected waits before connecting again
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// How long a sync that was removed or changed gets to end on its own before it is aborted
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A [process_node] task, running or waiting to be restarted
+struct RunningNode {
+    sync: RootSync,
+    stop: watch::Sender<bool>,
+    /// Tells apart a module's current task from ones that were replaced
+    id: u64,
+    handle: JoinHandle<()>,
+    /// When the task ended on its own
+    down_since: Option<Instant>,
+}
+
+/// Log which syncs are up and which are waiting to reconnect
+fn log_status(running: &HashMap<String, RunningNode>) {
+    let mut down: Vec<&str> = running.iter()
+        .filter(|(_, node)| node.down_since.is_some())
+        .map(|(module, _)| module.as_str())
+        .collect();
+    down.sort_unstable();
+    if down.is_empty() {
+        info!("All {} syncs are up", running.len());
+    } else {
+        info!("{} of {} syncs are up, down: {}", running.len() - down.len(), running.len(), down.join(", "));
+    }
+}
+
 /// Create a node process for every module that needs to synced from a remote mirra
-pub async fn node(config: Arc<Config>, _env: Arc<LocalKeys>) -> Result<()> {
-    let mut futs = Vec::with_capacity(config.syncs.len());
+/// Processes are started and stopped as syncs are added to or removed from the config
+/// A process that fails doesn't affect the others, and is restarted after [RETRY_DELAY]
+pub async fn node(mut config: ConfigWatch, _env: Arc<LocalKeys>, mut shutdown: Shutdown) -> Result<()> {
+    let mut running: HashMap<String, RunningNode> = HashMap::new();
+    let connections = Arc::new(MuxPool::default());
+    let (resync_tx, resync) = watch::channel(0);
+    daemon::watch_resync_signal(resync_tx)?;
+    // Every task reports here once it ends
+    let (finished_tx, mut finished) = mpsc::unbounded_channel::<(String, u64, Result<()>)>();
+    let mut next_id = 0;
+
+    loop {
+        let current = config.borrow().clone();
+
+        // Stop syncs that were removed or changed, leaving the others connected
+        let stopped: Vec<String> = running.iter()
+            .filter(|(module, node)| current.syncs.get(*module) != Some(&node.sync))
+            .map(|(module, _)| module.clone())
+            .collect();
+        for module in stopped {
+            let mut node = running.remove(&module).unwrap();
+            info!("Stopping sync of {}", module);
+            let _ = node.stop.send(true);
+            // A changed sync is started again right away, and must not write to the same directory as the old one
+            if tokio::time::timeout(STOP_TIMEOUT, &mut node.handle).await.is_err() {
+                node.handle.abort();
+                let _ = node.handle.await;
+            }
+        }
+
+        // Start syncs that are new or changed, and restart the ones that have been down for long enough
+        for sync in &current.syncs {
+            let restart = match running.get(sync.0) {
+                Some(node) => node.down_since.map_or(false, |since| since.elapsed() >= RETRY_DELAY),
+                None => true,
+            };
+            if restart {
+                let (stop, stopped) = watch::channel(false);
+                let (module, id, finished_tx) = (sync.0.clone(), next_id, finished_tx.clone());
+                let peer = format!("{}@{}", sync.0, format_address(&sync.1.address, sync.1.port));
+                let task = with_connection(peer, process_node(sync.0.clone(), sync.1.clone(), connections.clone(), resync.clone(), stopped));
+                running.insert(sync.0.clone(), RunningNode {
+                    sync: sync.1.clone(),
+                    stop,
+                    id,
+                    handle: tokio::spawn(async move {
+                        let _ = finished_tx.send((module, id, task.await));
+                    }),
+                    down_since: None,
+                });
+                next_id += 1;
+            }
+        }
 
-    for sync in &config.syncs {
-        futs.push(tokio::spawn(process_node(sync.0.clone(), sync.1.clone())));
+        // Wake up once the next sync that is down should be restarted
+        let retry = running.values().filter_map(|node| node.down_since).min().map(|since| since + RETRY_DELAY);
+
+        tokio::select! {
+            changed = config.changed() => {
+                if changed.is_err() {
+                    // The config won't change anymore
+                    shutdown_requested(&mut shutdown).await;
+                    break;
+                }
+            }
+            Some((module, id, res)) = finished.recv() => {
+                // Tasks that were stopped because of a config change are expected to end
+                if let Some(node) = running.get_mut(&module).filter(|node| node.id == id) {
+                    match res {
+                        Err(e) => {
+                            metrics::sync_error();
+                            warn!("Sync of {} failed: {}, retrying in {} seconds", module, e, RETRY_DELAY.as_secs());
+                        }
+                        Ok(()) => info!("Sync of {} ended, reconnecting in {} seconds", module, RETRY_DELAY.as_secs()),
+                    }
+                    node.down_since = Some(Instant::now());
+                    log_status(&running);
+                }
+            }
+            _ = sleep_until(retry.unwrap_or_else(Instant::now)), if retry.is_some() => {}
+            _ = shutdown_requested(&mut shutdown) => break,
+        }
+    }
+
+    for node in running.values() {
+        let _ = node.stop.send(true);
+    }
+    for (_, node) in running {
+        let _ = node.handle.await;
     }
-    for fut in futs {
-        fut.await??;
+    // Report the failures of syncs that ended while shutting down
+    while let Ok((module, _, res)) = finished.try_recv() {
+        if let Err(e) = res {
+            warn!("Sync of {} failed: {}", module, e);
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicBool;
+
+    use crate::packet::{ErrorMsg, PacketKind};
+    use crate::socket::tests::connected;
+    use crate::util::tests::TestDir;
+    use super::{check_hex, FileRules, load_allowlist, partial_path, refuse_unlisted, remove_stale_parts};
+
+    /// Rules that only accept the hashes in [allowlist]
+    fn rules(allowlist: Option<&str>) -> FileRules {
+        FileRules {
+            allowlist: allowlist.map(|hash| [hash.to_string()].into_iter().collect()),
+            max_file_size: None,
+            read_only: false,
+            received: Mutex::new(HashMap::new()),
+            changed: AtomicBool::new(false),
+        }
+    }
+
+    #[tokio::test]
+    async fn allowlists_take_plain_hashes_as_blake3() {
+        let dir = TestDir::new("allowlist");
+        let path = dir.join("allowlist");
+        std::fs::write(&path, "# released builds\nab12\n\n  sha256:cd34  \n").unwrap();
+        let allowlist = load_allowlist(path.to_str().unwrap()).await.unwrap();
+        assert_eq!(allowlist, ["blake3:ab12".to_string(), "sha256:cd34".to_string()].into_iter().collect());
+    }
+
+    #[tokio::test]
+    async fn only_listed_hashes_are_accepted() {
+        let (mut node, mut root) = connected();
+
+        let listed = rules(Some("blake3:ab12"));
+        assert!(!refuse_unlisted(&mut node, &listed, "a", "blake3:ab12").await.unwrap());
+        assert!(refuse_unlisted(&mut node, &listed, "b", "blake3:ef56").await.unwrap());
+        // Without an allowlist everything is accepted
+        assert!(!refuse_unlisted(&mut node, &rules(None), "c", "blake3:ef56").await.unwrap());
+
+        // Only the refused file was answered
+        assert!(root.read_packet_kind().await.unwrap() == PacketKind::ErrorMsg);
+        let refusal: ErrorMsg = root.expect_unchecked().await.unwrap();
+        assert!(refusal.message.contains("blake3:ef56"));
+        drop(node);
+        assert!(root.read_packet_kind().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stale_parts_of_the_same_file_are_removed() {
+        let dir = TestDir::new("parts");
+        let file = dir.join("a.txt");
+        let stale = partial_path(&file, "blake3:1111");
+        let current = partial_path(&file, "blake3:2222");
+        let other = partial_path(&dir.join("a.txt.b"), "blake3:3333");
+        for part in [&stale, &current, &other] {
+            std::fs::write(part, "part").unwrap();
+        }
+
+        remove_stale_parts(&file, &current).await;
+        assert!(!stale.exists());
+        assert!(current.exists());
+        assert!(other.exists(), "another file's part was removed");
+    }
+
+    #[test]
+    fn hashes_have_to_be_hex() {
+        assert!(check_hex("blake3:0123456789abcdefABCDEF").is_ok());
+        // Large files announce their hash only after sending them
+        assert!(check_hex("blake3:").is_ok());
+        assert!(check_hex("blake3:../../x").is_err());
+        assert!(check_hex("blake3:ääääääää").is_err());
+        assert_eq!(partial_path(std::path::Path::new("a"), "blake3:ääääääääää"), std::path::Path::new(".a.ääääääääää.mirra-part"));
+    }
+}
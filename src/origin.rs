@@ -0,0 +1,73 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
+
+/// What a file was like when it was written, its modification time and size
+pub type FileState = (Option<SystemTime>, u64);
+
+/// How many received files are remembered, so the map can't grow with every file ever synced
+const MAX_RECEIVED: usize = 0x10000;
+
+/// The fingerprint of the local mirra's key, which changes made here are tagged with
+static LOCAL: OnceLock<String> = OnceLock::new();
+
+/// Files received from other mirras, with what they were like when they were written and where the change to them came from
+fn received() -> &'static Mutex<HashMap<PathBuf, (FileState, String)>> {
+    static RECEIVED: OnceLock<Mutex<HashMap<PathBuf, (FileState, String)>>> = OnceLock::new();
+    RECEIVED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The same file can be reached through relative and absolute paths, so they're compared fully resolved
+fn key(path: &Path) -> PathBuf {
+    std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Tag changes made here with [fingerprint]
+pub fn set_local(fingerprint: String) {
+    let _ = LOCAL.set(fingerprint);
+}
+
+/// The fingerprint changes made here are tagged with
+pub fn local() -> &'static str {
+    LOCAL.get().map_or("", String::as_str)
+}
+
+/// Note that the file at [path] was just written in [state] with a change that came from [origin]
+pub fn record(path: &Path, state: FileState, origin: &str) {
+    let key = key(path);
+    let mut received = received().lock().unwrap();
+    // Forgetting a file only means its change might be sent back once, the other side already has it then
+    if received.len() >= MAX_RECEIVED && !received.contains_key(&key) {
+        if let Some(evicted) = received.keys().next().cloned() {
+            received.remove(&evicted);
+        }
+    }
+    received.insert(key, (state, origin.to_string()));
+}
+
+/// Whether the file at [path] is still in [state], the way it was received
+pub fn is_received(path: &Path, state: FileState) -> bool {
+    matches!(received().lock().unwrap().get(&key(path)), Some((written, _)) if *written == state)
+}
+
+/// Where the change that left the file at [path] in [state] came from
+/// That's the mirra it was received from, unless it changed here since
+pub fn of(path: &Path, state: FileState) -> String {
+    let key = key(path);
+    let mut received = received().lock().unwrap();
+    match received.get(&key) {
+        Some((written, origin)) if *written == state => origin.clone(),
+        _ => {
+            // The file changed here since, so the entry won't match again
+            received.remove(&key);
+            local().to_string()
+        }
+    }
+}
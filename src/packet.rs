@@ -8,8 +8,7 @@ use std::io::{Error, ErrorKind, Result};
 
 use async_trait::async_trait;
 use num_derive::FromPrimitive;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 #[derive(PartialEq, FromPrimitive)]
 pub enum PacketKind {
@@ -25,21 +24,43 @@ pub enum PacketKind {
     Remove = 0xA,
     Rename = 0xB,
     Skip = 0xC,
+    MakeDir = 0xD,
+    Resume = 0xE,
+    SparseFile = 0xF,
+    ErrorMsg = 0x10,
+    Manifest = 0x11,
+    Want = 0x12,
+    BlockChecksums = 0x13,
+    Delta = 0x14,
+    RemoveMany = 0x15,
+    RenameMany = 0x16,
+    Multiplex = 0x17,
+    RequestSync = 0x18,
+    RequestFile = 0x19,
+    Fetch = 0x1A,
+    Capabilities = 0x1B,
+    Push = 0x1C,
+    FileHash = 0x1D,
+    Origin = 0x1E,
 }
 
+/// Optional protocol features this build supports, exchanged in a [Capabilities] right after the handshake
+/// Either side only uses the features both of them support, so new ones can be added without breaking old peers
+pub const CAPABILITIES: &[&str] = &["delta", "sparse", "resume", "hash:blake3", "hash:sha256", "trailing-hash", "origin"];
+
 /// Convenience trait for passing [PacketKinds]'s
 pub trait Packet {
     const KIND: PacketKind;
 }
 
-/// Convenience trait for writing to TcpStream
+/// Convenience trait for writing to streams
 #[async_trait]
 pub trait WriteAny<T> {
     /// Write [t] to the stream
     async fn write_any(&mut self, t: T) -> Result<usize>;
 }
 
-/// Convenience trait for reading from TcpStream
+/// Convenience trait for reading from streams
 #[async_trait]
 pub trait ReadAny<T> {
     /// Read a [T] from the stream
@@ -47,7 +68,7 @@ pub trait ReadAny<T> {
 }
 
 #[async_trait]
-impl WriteAny<bool> for TcpStream {
+impl<S: AsyncWrite + Unpin + Send> WriteAny<bool> for S {
     async fn write_any(&mut self, t: bool) -> Result<usize> {
         self.write_u8(t as u8).await?;
         Ok(1)
@@ -55,27 +76,65 @@ impl WriteAny<bool> for TcpStream {
 }
 
 #[async_trait]
-impl ReadAny<bool> for TcpStream {
+impl<S: AsyncRead + Unpin + Send> ReadAny<bool> for S {
     async fn read_any(&mut self) -> Result<bool> {
         Ok(self.read_u8().await? != 0)
     }
 }
 
+macro_rules! number_any {
+    ($typ:ty, $write:ident, $read:ident) => {
+        #[async_trait]
+        impl<S: AsyncWrite + Unpin + Send> WriteAny<$typ> for S {
+            async fn write_any(&mut self, t: $typ) -> Result<usize> {
+                // Numbers are always big endian
+                self.$write(t).await?;
+                Ok(std::mem::size_of::<$typ>())
+            }
+        }
+
+        #[async_trait]
+        impl<S: AsyncRead + Unpin + Send> ReadAny<$typ> for S {
+            async fn read_any(&mut self) -> Result<$typ> {
+                self.$read().await
+            }
+        }
+    };
+}
+
+number_any!(u8, write_u8, read_u8);
+number_any!(u16, write_u16, read_u16);
+number_any!(u32, write_u32, read_u32);
+number_any!(u64, write_u64, read_u64);
+number_any!(i64, write_i64, read_i64);
+number_any!(f64, write_f64, read_f64);
+
+/// Read [size] bytes from [stream]
+/// The size comes from the peer, so the buffer only grows as the bytes actually arrive
+async fn read_sized<S: AsyncRead + Unpin + Send>(stream: &mut S, size: u32) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    stream.take(size as u64).read_to_end(&mut buf).await?;
+    if buf.len() < size as usize {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "stream ended before the announced size"));
+    }
+    Ok(buf)
+}
+
 #[async_trait]
-impl WriteAny<String> for TcpStream {
+impl<S: AsyncWrite + Unpin + Send> WriteAny<String> for S {
     async fn write_any(&mut self, t: String) -> Result<usize> {
         // Encoding is 4 bytes of size, then the entire string as utf8
         self.write_u32(t.len() as u32).await?;
-        self.write(t.as_bytes()).await
+        self.write_all(t.as_bytes()).await?;
+        Ok(4 + t.len())
     }
 }
 
 #[async_trait]
-impl ReadAny<String> for TcpStream {
+impl<S: AsyncRead + Unpin + Send> ReadAny<String> for S {
     async fn read_any(&mut self) -> Result<String> {
-        let size = self.read_u32().await? as usize;
-        let mut buf = vec![0; size];
-        self.read_exact(buf.as_mut_slice()).await?;
+        let size = self.read_u32().await?;
+        let buf = read_sized(self, size).await?;
         let res = String::from_utf8(buf);
         if res.is_ok() {
             Ok(res.unwrap())
@@ -86,7 +145,25 @@ impl ReadAny<String> for TcpStream {
 }
 
 #[async_trait]
-impl WriteAny<Vec<String>> for TcpStream {
+impl<S: AsyncWrite + Unpin + Send> WriteAny<Vec<u8>> for S {
+    async fn write_any(&mut self, t: Vec<u8>) -> Result<usize> {
+        // 4 bytes of len, then the raw bytes
+        self.write_u32(t.len() as u32).await?;
+        self.write_all(&t).await?;
+        Ok(4 + t.len())
+    }
+}
+
+#[async_trait]
+impl<S: AsyncRead + Unpin + Send> ReadAny<Vec<u8>> for S {
+    async fn read_any(&mut self) -> Result<Vec<u8>> {
+        let size = self.read_u32().await?;
+        read_sized(self, size).await
+    }
+}
+
+#[async_trait]
+impl<S: AsyncWrite + Unpin + Send> WriteAny<Vec<String>> for S {
     async fn write_any(&mut self, t: Vec<String>) -> Result<usize> {
         // Again, 4 bytes of len, then every element
         self.write_u32(t.len() as u32).await?;
@@ -99,10 +176,11 @@ impl WriteAny<Vec<String>> for TcpStream {
 }
 
 #[async_trait]
-impl ReadAny<Vec<String>> for TcpStream {
+impl<S: AsyncRead + Unpin + Send> ReadAny<Vec<String>> for S {
     async fn read_any(&mut self) -> Result<Vec<String>> {
         let size = self.read_u32().await? as usize;
-        let mut res = Vec::with_capacity(size);
+        // Like above, don't trust the size with more than a little memory up front
+        let mut res = Vec::with_capacity(size.min(1024));
         for _ in 0..size {
             res.push(self.read_any().await?);
         }
@@ -116,10 +194,10 @@ macro_rules! generic_packet {
         impl $name { pub fn new() -> Self { Self {} } }
         impl Packet for $name { const KIND: PacketKind = $id; }
         #[async_trait]
-        impl WriteAny<$name> for TcpStream { async fn write_any(&mut self, _t: $name) -> Result<usize> { Ok(0) } }
+        impl<S: AsyncWrite + Unpin + Send> WriteAny<$name> for S { async fn write_any(&mut self, _t: $name) -> Result<usize> { Ok(0) } }
 
         #[async_trait]
-        impl ReadAny<$name> for TcpStream { async fn read_any(&mut self) -> Result<$name> { Ok($name {}) } }
+        impl<S: AsyncRead + Unpin + Send> ReadAny<$name> for S { async fn read_any(&mut self) -> Result<$name> { Ok($name {}) } }
     };
     ($name:ident, $id:expr, $($arg:ident, $typ:ty),*) => {
         pub struct $name {
@@ -145,7 +223,7 @@ macro_rules! generic_packet {
         impl Packet for $name { const KIND: PacketKind = $id; }
 
         #[async_trait]
-        impl WriteAny<$name> for TcpStream {
+        impl<S: AsyncWrite + Unpin + Send> WriteAny<$name> for S {
             async fn write_any(&mut self, t: $name) -> Result<usize> {
                 Ok(
                     $(
@@ -156,7 +234,7 @@ macro_rules! generic_packet {
         }
 
         #[async_trait]
-        impl ReadAny<$name> for TcpStream {
+        impl<S: AsyncRead + Unpin + Send> ReadAny<$name> for S {
             async fn read_any(&mut self) -> Result<$name> {
                 Ok($name {
                     $(
@@ -175,7 +253,23 @@ generic_packet!(NotFound, PacketKind::NotFound);
 generic_packet!(Heartbeat, PacketKind::Heartbeat);
 generic_packet!(BeginSync, PacketKind::BeginSync);
 generic_packet!(EndSync, PacketKind::EndSync);
-generic_packet!(FileHeader, PacketKind::FileHeader, path, String, hash, String, cert, String);
+generic_packet!(FileHeader, PacketKind::FileHeader, path, String, size, u64, hash, String, cert, String);
 generic_packet!(Remove, PacketKind::Remove, path, String);
 generic_packet!(Rename, PacketKind::Rename, old, String, new, String);
 generic_packet!(Skip, PacketKind::Skip);
+generic_packet!(MakeDir, PacketKind::MakeDir, path, String);
+generic_packet!(Resume, PacketKind::Resume, offset, u64);
+generic_packet!(ErrorMsg, PacketKind::ErrorMsg, message, String);
+generic_packet!(Manifest, PacketKind::Manifest, paths, Vec<String>, hashes, Vec<String>);
+generic_packet!(Want, PacketKind::Want, paths, Vec<String>);
+generic_packet!(Multiplex, PacketKind::Multiplex);
+generic_packet!(RequestSync, PacketKind::RequestSync);
+generic_packet!(RequestFile, PacketKind::RequestFile, path, String);
+generic_packet!(Fetch, PacketKind::Fetch, module, String, paths, Vec<String>);
+generic_packet!(Capabilities, PacketKind::Capabilities, features, Vec<String>);
+generic_packet!(Push, PacketKind::Push, module, String);
+generic_packet!(FileHash, PacketKind::FileHash, hash, String, cert, String);
+generic_packet!(Origin, PacketKind::Origin, fingerprint, String);
+generic_packet!(RemoveMany, PacketKind::RemoveMany, paths, Vec<String>);
+generic_packet!(RenameMany, PacketKind::RenameMany, old, Vec<String>, new, Vec<String>);
+generic_packet!(BlockChecksums, PacketKind::BlockChecksums, block_size, u32, checksums, Vec<u8>);
@@ -4,109 +4,523 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use std::collections::{HashMap, HashSet};
 use std::io::{Error, ErrorKind, Result};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, mpsc};
 use std::sync::mpsc::TryRecvError;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
 use tokio::fs;
 use async_recursion::async_recursion;
-use log::{info, warn};
-use notify::{DebouncedEvent, RecursiveMode, Watcher};
+use log::{debug, info, warn};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 use tokio::fs::File;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc as tokio_mpsc, Semaphore};
 
-use crate::{Client, Server};
-use crate::config::Config;
+use crate::{Client, control, node, origin, Server};
+use crate::config::{Config, ConfigWatch, module_max_file_size, module_paths};
 use crate::keys::LocalKeys;
-use crate::packet::{BeginSync, Close, EndSync, FileHeader, Handshake, Ok, PacketKind, Heartbeat, NotFound, Remove, Rename};
-use crate::util::{AsyncFileLock, hash_file, stringify};
-
-/// Send a file to a remote mirra node
-async fn sync_file(socket: &mut Client, outof: PathBuf, path: &Path, keys: Arc<LocalKeys>) -> Result<()> {
+use crate::metrics;
+use crate::delta::{MIN_DELTA_SIZE, Signatures};
+use crate::mux::{connect_retries, connect_timeout, Mux};
+use crate::packet::{BeginSync, BlockChecksums, Close, EndSync, ErrorMsg, Fetch, FileHash, FileHeader, Handshake, MakeDir, Ok, PacketKind, Heartbeat, Manifest, Multiplex, NotFound, Origin, Push, Remove, RemoveMany, Rename, RenameMany, RequestFile, RequestSync, Resume, Want};
+use crate::util::{AsyncFileLock, cached_hash, cached_hash_file, forget_hash, format_duration, format_size, HashAlgo, is_sparse, local_path, open_module_lock, remember_hash, retry_read, Shutdown, shutdown_requested, stringify, StreamHasher, wire_path, with_connection};
+
+/// How often a file that changes while it's being sent is sent again right away
+const MAX_SEND_ATTEMPTS: usize = 3;
+/// How long a file has to stay unchanged before it's synced
+const WATCH_DELAY: Duration = Duration::from_secs(1);
+/// How often an idle connection is checked with a heartbeat
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+/// How long a node may go without answering before its connection is closed, unless configured
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Send a file to a remote mirra node, starting over if it changes in the meantime
+/// Files larger than [max_size] are skipped
+/// Returns false if the file couldn't be read, nothing is sent for it then
+async fn sync_file(socket: &mut Client, outof: PathBuf, path: &Path, keys: Arc<LocalKeys>, algo: HashAlgo, max_size: Option<u64>) -> Result<bool> {
     // Make path relative, so the node knows where to put it
-    let relative_path = stringify(path.strip_prefix(outof.clone()).unwrap())?;
-    info!("Syncing {}", relative_path);
+    let relative_path = wire_path(path.strip_prefix(outof.clone()).unwrap())?;
+    // Fall back to blake3 if the node can't verify the configured algorithm
+    let algo = socket.hash_algo(algo);
+    let hash_later = socket.supports("trailing-hash");
+
+    for attempt in 1..=MAX_SEND_ATTEMPTS {
+        // Nothing was sent yet, so the file can be skipped without confusing the node
+        let file = match retry_read(&relative_path, || open_file(path, algo, max_size, hash_later)).await {
+            Ok(Some(file)) => file,
+            Ok(None) => {
+                warn!("Not syncing {}, it is larger than {}", relative_path, format_size(max_size.unwrap()));
+                return Ok(true);
+            }
+            Err(e) => {
+                warn!("Not syncing {}, it can't be read: {}", relative_path, e);
+                return Ok(false);
+            }
+        };
+
+        info!("Syncing {}", relative_path);
+        if send_once(socket, &relative_path, file, keys.clone()).await? {
+            break;
+        } else if attempt == MAX_SEND_ATTEMPTS {
+            warn!("{} keeps changing while it's sent, leaving it until it's written again", relative_path);
+        } else {
+            info!("{} changed while it was sent, sending it again", relative_path);
+        }
+    }
+    Ok(true)
+}
 
-    // Open and lock file
-    let mut file = File::open(path).await?;
-    file.lock().await?;
+/// Whether the file at [path] is larger than [max_size]
+async fn too_large(path: &Path, max_size: Option<u64>) -> Result<bool> {
+    Ok(match max_size {
+        Some(max_size) => fs::metadata(path).await?.len() > max_size,
+        None => false,
+    })
+}
+
+/// What changes whenever a file is written, even by writers that ignore its lock
+async fn file_state(file: &File) -> Result<(Option<SystemTime>, u64)> {
+    let metadata = file.metadata().await?;
+    Ok((metadata.modified().ok(), metadata.len()))
+}
+
+/// A file that is locked and hashed, ready to be sent
+struct OpenFile {
+    path: PathBuf,
+    file: File,
+    /// What the file was like when it was hashed
+    before: (Option<SystemTime>, u64),
+    algo: HashAlgo,
+    /// None if the file is hashed while it's sent instead
+    hash: Option<String>,
+    /// The fingerprint of the mirra the file's contents came from
+    origin: String,
+}
 
-    // Hash file
-    let hash = hash_file(&mut file).await?;
+/// Open, lock and hash the file at [path], unless it is larger than [max_size]
+/// With [hash_later], large files that weren't hashed before are hashed while they're sent instead of reading them twice
+async fn open_file(path: &Path, algo: HashAlgo, max_size: Option<u64>, hash_later: bool) -> Result<Option<OpenFile>> {
+    if too_large(path, max_size).await? {
+        return Ok(None);
+    }
+    let mut file = File::open(path).await?;
+    // Reading only needs to keep writers out, nodes and web downloads can read it at the same time
+    file.lock_shared().await?;
+    let before = file_state(&file).await?;
+    // Small files are still hashed up front, so the node can skip them if it has them already
+    // Large ones it has an old version of are sent as a delta, which costs almost nothing if they didn't change
+    let hash = if hash_later && before.1 >= MIN_DELTA_SIZE {
+        cached_hash(path, &file.metadata().await?, algo)
+    } else {
+        Some(cached_hash_file(path, &mut file, algo).await?)
+    };
+    let origin = origin::of(path, before);
+    Ok(Some(OpenFile { path: path.to_path_buf(), file, before, algo, hash, origin }))
+}
 
-    // Send file metadata
-    socket.send(FileHeader::new(relative_path, hash.clone(), keys.sign(hash))).await?;
+/// Send a file to a remote mirra node once
+/// Returns false if the file changed after it was hashed, so the node got something else than announced
+async fn send_once(socket: &mut Client, relative_path: &str, mut open: OpenFile, keys: Arc<LocalKeys>) -> Result<bool> {
+    // Send file metadata, a hash without hex digits announces a [FileHash] after the file
+    let (hash, mut hasher) = match open.hash.clone() {
+        Some(hash) => (hash, None),
+        None => (format!("{}:", open.algo.name()), Some(StreamHasher::new(open.algo))),
+    };
+    let cert = if hasher.is_none() { keys.sign(hash.clone()) } else { String::new() };
+    socket.send(FileHeader::new(relative_path.to_string(), open.before.1, hash, cert)).await?;
+    if socket.supports("origin") {
+        socket.send(Origin::new(open.origin.clone())).await?;
+    }
 
     let next = socket.read_packet_kind().await?;
-    match next {
-        PacketKind::Ok => {}
+    let offset = match next {
+        PacketKind::Ok => 0,
+        // The node already has the beginning of the file
+        PacketKind::Resume => {
+            let resume: Resume = socket.expect_unchecked().await?;
+            if hasher.is_some() {
+                return Err(Error::new(ErrorKind::InvalidData, "node resumed a file that wasn't hashed yet"));
+            }
+            info!("Resuming {} at {}", relative_path, format_size(resume.offset));
+            resume.offset
+        }
+        // The node has an older version of the file, only send what changed
+        PacketKind::BlockChecksums => {
+            let checksums: BlockChecksums = socket.expect_unchecked().await?;
+            let signatures = Signatures::parse(checksums.block_size, &checksums.checksums)?;
+            let (literal, reused) = socket.send_delta(&mut open.file, &signatures, hasher.as_mut()).await?;
+            info!("Sent {} of {} as a delta, reusing {} blocks", format_size(literal), relative_path, reused);
+            let unchanged = finish_send(socket, open, hasher, &keys).await?;
+
+            expect_received(socket, relative_path).await?;
+            return Ok(unchanged);
+        }
         // Skip file if it already exists on the node
         PacketKind::Skip | PacketKind::Close => {
-            return Ok(());
+            return Ok(true);
+        }
+        // The node refused the file
+        PacketKind::ErrorMsg => {
+            warn!("Node refused {}: {}", relative_path, socket.remote_error().await);
+            return Ok(true);
         }
         _ => {
             return Err(Error::new(ErrorKind::InvalidData, "unexpected package"));
         }
+    };
+
+    // Send file, skipping the holes of sparse files
+    if offset == 0 && socket.supports("sparse") && is_sparse(&open.file).await? {
+        socket.send_sparse_file(&mut open.file, hasher.as_mut()).await?;
+    } else {
+        socket.send_file(&mut open.file, offset, hasher.as_mut()).await?;
     }
+    let unchanged = finish_send(socket, open, hasher, &keys).await?;
 
-    // Send file
-    socket.send_file(&mut file).await?;
-    file.unlock().await?;
+    expect_received(socket, relative_path).await?;
+    Ok(unchanged)
+}
 
-    socket.expect::<Ok>().await?;
+/// Unlock a file after it was sent, following it with a [FileHash] if it was hashed by [hasher] while it was sent
+/// Returns false if the file changed after it was opened
+async fn finish_send(socket: &mut Client, open: OpenFile, hasher: Option<StreamHasher>, keys: &LocalKeys) -> Result<bool> {
+    let unchanged = file_state(&open.file).await? == open.before;
+    if let Some(hasher) = hasher {
+        let hash = hasher.finish();
+        if unchanged {
+            remember_hash(&open.path, &open.file.metadata().await?, open.algo, &hash);
+        }
+        socket.send(FileHash::new(hash.clone(), keys.sign(hash))).await?;
+    }
+    open.file.unlock().await?;
+    Ok(unchanged)
+}
 
-    Ok(())
+/// Wait for the node to confirm it received [relative_path]
+async fn expect_received(socket: &mut Client, relative_path: &str) -> Result<()> {
+    match socket.read_packet_kind().await? {
+        PacketKind::Ok => {
+            metrics::file_sent();
+            Ok(())
+        }
+        // The node asks for the file again later
+        PacketKind::ErrorMsg => {
+            warn!("Node failed to receive {}: {}", relative_path, socket.remote_error().await);
+            Ok(())
+        }
+        _ => Err(Error::new(ErrorKind::InvalidData, "unexpected package")),
+    }
 }
 
-/// Sync a directory to a remote mirra node
+/// Collect all files and empty directories below [dir]
 #[async_recursion]
-async fn sync_dir(socket: &mut Client, root_dir: PathBuf, dir: PathBuf, keys: Arc<LocalKeys>) -> Result<()> {
-    info!("Syncing directory {}", dir.to_str().unwrap_or("<couldnt read path>"));
+async fn collect_dir(root_dir: &Path, dir: PathBuf, files: &mut Vec<PathBuf>, empty_dirs: &mut Vec<PathBuf>) -> Result<()> {
     // Go through each entry (tokio's ReadDir doesn't support iter)
-    let mut list = tokio::fs::read_dir(dir).await?;
+    let mut list = tokio::fs::read_dir(dir.clone()).await?;
+    let mut empty = true;
     loop {
         // Get next directory entry
         let entry = list.next_entry().await?;
         if entry.is_none() { break; }
         if let Some(entry) = entry {
+            empty = false;
             if entry.path().is_file() {
-                // Send file directly
-                sync_file(socket, root_dir.clone(), entry.path().as_path(), keys.clone()).await?;
+                files.push(entry.path());
             } else if entry.path().is_dir() {
-                // Sync directories recursively
-                sync_dir(socket, root_dir.clone(), entry.path(), keys.clone()).await?;
+                collect_dir(root_dir, entry.path(), files, empty_dirs).await?;
+            }
+        }
+    }
+
+    // Files implicitly create their directories, but empty ones have to be created explicitly
+    if empty && dir != root_dir {
+        empty_dirs.push(dir);
+    }
+
+    Ok(())
+}
+
+/// The directory of a module's [dirs] that [path] is in
+fn source_dir<'a>(dirs: &'a [PathBuf], path: &Path) -> Result<&'a PathBuf> {
+    dirs.iter().find(|dir| path.starts_with(dir))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} isn't in the module", path.display())))
+}
+
+/// Find a directory of [dirs] other than [dir] that also has something at [relative_path]
+fn collision<'a>(dirs: &'a [PathBuf], dir: &Path, relative_path: &Path) -> Option<&'a PathBuf> {
+    dirs.iter().find(|other| other.as_path() != dir && other.join(relative_path).exists())
+}
+
+/// Collect all files and empty directories of a module, each with the directory of [dirs] it is in
+/// Fails if a file is in more than one of them, since the node can only have one of them
+async fn collect_module(dirs: &[PathBuf]) -> Result<(Vec<(PathBuf, PathBuf)>, Vec<(PathBuf, PathBuf)>)> {
+    let mut files = Vec::new();
+    let mut empty_dirs = Vec::new();
+    for dir in dirs {
+        let mut dir_files = Vec::new();
+        let mut dir_empty = Vec::new();
+        collect_dir(dir, dir.clone(), &mut dir_files, &mut dir_empty).await?;
+
+        for file in dir_files {
+            if let Some(other) = collision(dirs, dir, file.strip_prefix(dir).unwrap()) {
+                return Err(Error::new(ErrorKind::AlreadyExists, format!("{} is in both {} and {}",
+                    wire_path(file.strip_prefix(dir).unwrap())?, dir.display(), other.display())));
             }
+            files.push((dir.clone(), file));
         }
+        empty_dirs.extend(dir_empty.into_iter().map(|empty| (dir.clone(), empty)));
+    }
+    Ok((files, empty_dirs))
+}
+
+/// Hash the file at [path] while holding its lock, unless it is larger than [max_size]
+async fn hash_path(path: &Path, algo: HashAlgo, max_size: Option<u64>) -> Result<Option<String>> {
+    if too_large(path, max_size).await? {
+        return Ok(None);
+    }
+    let mut file = File::open(path).await?;
+    file.lock_shared().await?;
+    let hash = cached_hash_file(path, &mut file, algo).await;
+    file.unlock().await?;
+    hash.map(Some)
+}
+
+/// Create an empty directory on a remote mirra node
+async fn sync_make_dir(socket: &mut Client, outof: PathBuf, path: &Path) -> Result<()> {
+    let relative_path = wire_path(path.strip_prefix(outof).unwrap())?;
+    info!("Syncing directory {}", relative_path);
+    socket.send(MakeDir::new(relative_path)).await?;
+    socket.expect::<Ok>().await?;
+    Ok(())
+}
+
+/// Send a file, or everything below a directory, to a remote mirra node
+async fn sync_path(socket: &mut Client, dir: &Path, path: &Path, keys: Arc<LocalKeys>, algo: HashAlgo, max_size: Option<u64>) -> Result<()> {
+    if path.is_file() {
+        sync_file(socket, dir.to_path_buf(), path, keys, algo, max_size).await?;
+        return Ok(());
+    } else if !path.is_dir() {
+        return Ok(());
     }
 
+    let mut files = Vec::new();
+    let mut empty_dirs = Vec::new();
+    collect_dir(dir, path.to_path_buf(), &mut files, &mut empty_dirs).await?;
+    for empty_dir in empty_dirs {
+        sync_make_dir(socket, dir.to_path_buf(), &empty_dir).await?;
+    }
+    for file in files {
+        sync_file(socket, dir.to_path_buf(), &file, keys.clone(), algo, max_size).await?;
+    }
     Ok(())
 }
 
 /// Sync an entire module to a remote mirra node
-async fn process_full_sync(socket: &mut Client, dir: PathBuf, keys: Arc<LocalKeys>) -> Result<()> {
+/// Returns false if the node only wanted this sync and closed the connection
+async fn process_full_sync(socket: &mut Client, module: &str, dirs: &[PathBuf], keys: Arc<LocalKeys>, algo: HashAlgo, max_size: Option<u64>) -> Result<bool> {
+    // Wait for external writers holding the module lock
+    let lock = open_module_lock(module).await?;
+    lock.lock_shared().await?;
+
+    let res = sync_module(socket, dirs, keys, algo, max_size).await;
+    lock.unlock().await?;
+    if res.is_ok() {
+        metrics::synced(module);
+    }
+    res
+}
+
+/// Sync an entire module to a remote mirra node, without locking it
+async fn sync_module(socket: &mut Client, dirs: &[PathBuf], keys: Arc<LocalKeys>, algo: HashAlgo, max_size: Option<u64>) -> Result<bool> {
+    let (files, empty_dirs) = match collect_module(dirs).await {
+        Ok(collected) => collected,
+        // Let the node know why it doesn't get anything
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            socket.send(ErrorMsg::new(e.to_string())).await?;
+            return Err(e);
+        }
+        Err(e) => return Err(e),
+    };
+
     info!("Performing a sync");
+    let algo = socket.hash_algo(algo);
     // Tell the node
     socket.send(BeginSync::new()).await?;
     socket.expect::<Ok>().await?;
 
-    // Sync the root dir
-    sync_dir(socket, dir.clone(), dir, keys).await?;
+    // Tell the node about every file up front, so it can pick the ones it's missing in one go
+    let mut paths = Vec::with_capacity(files.len());
+    let mut hashes = Vec::with_capacity(files.len());
+    let mut sources = HashMap::with_capacity(files.len());
+    // Files that can't be read are skipped, instead of failing the whole sync
+    let mut unreadable = Vec::new();
+    for (dir, file) in &files {
+        let path = wire_path(file.strip_prefix(dir).unwrap())?;
+        let hash = match retry_read(&path, || hash_path(file, algo, max_size)).await {
+            Ok(Some(hash)) => hash,
+            // Don't offer what would be skipped anyway
+            Ok(None) => {
+                warn!("Not syncing {}, it is larger than {}", path, format_size(max_size.unwrap()));
+                continue;
+            }
+            Err(e) => {
+                warn!("Not syncing {}, it can't be read: {}", path, e);
+                unreadable.push(path);
+                continue;
+            }
+        };
+        paths.push(path.clone());
+        hashes.push(hash);
+        sources.insert(path, dir);
+    }
+    socket.send(Manifest::new(paths, hashes)).await?;
+    let want: Want = socket.expect().await?;
+    info!("Node wants {} of {} files", want.paths.len(), sources.len());
+
+    for (dir, empty_dir) in empty_dirs {
+        sync_make_dir(socket, dir, &empty_dir).await?;
+    }
+
+    for path in want.paths {
+        // Only send what was offered, anything else might not even be in the module
+        let dir = match sources.get(&path) {
+            Some(dir) => (*dir).clone(),
+            None => {
+                warn!("Node wants {}, which wasn't in the manifest", path);
+                continue;
+            }
+        };
+        if !sync_file(socket, dir.clone(), &dir.join(local_path(&path)?), keys.clone(), algo, max_size).await? {
+            unreadable.push(path);
+        }
+    }
+
+    if !unreadable.is_empty() {
+        warn!("Skipped {} files that couldn't be read: {}", unreadable.len(), unreadable.join(", "));
+    }
 
     // Tell the node it's over :)
     socket.send(EndSync::new()).await?;
 
-    socket.expect::<Ok>().await?;
+    match socket.read_packet_kind().await? {
+        PacketKind::Ok => Ok(true),
+        // The node pulls the module once, instead of staying in sync
+        PacketKind::Close => {
+            socket.send(Close::new()).await?;
+            Ok(false)
+        }
+        PacketKind::ErrorMsg => Err(socket.remote_error().await),
+        _ => Err(Error::new(ErrorKind::InvalidData, "unexpected package")),
+    }
+}
+
+/// Resolve [path] relative to the module at [dirs], if it is a file inside of the module
+/// Returns the directory it is in as well
+async fn module_file(dirs: &[PathBuf], path: &str) -> Option<(PathBuf, PathBuf)> {
+    // Don't let the node ask for anything outside of the module
+    let relative = local_path(path).ok()?;
+    for dir in dirs {
+        let path = dir.join(&relative);
+        // Symlinks could still lead out of it
+        match fs::canonicalize(&path).await {
+            Ok(real) if real.starts_with(dir) && path.is_file() => return Some((dir.clone(), path)),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Resolve every path of [module] to an absolute one, telling the node if that isn't possible
+async fn resolve_module(socket: &mut Client, module: &str, paths: &[String]) -> Result<Option<Vec<PathBuf>>> {
+    let mut dirs = Vec::with_capacity(paths.len());
+    for path in paths {
+        match fs::canonicalize(PathBuf::from(path)).await {
+            Ok(dir) => dirs.push(dir),
+            Err(e) => {
+                warn!("Can't sync {}, its path {} is unusable: {}", module, path, e);
+                socket.send(ErrorMsg::new(format!("module path of {} does not exist", module))).await?;
+                return Ok(None);
+            }
+        }
+    }
+    Ok(Some(dirs))
+}
+
+/// Send files a node fetches over an extra connection, to receive a full sync faster
+async fn process_fetch(socket: &mut Client, config: &ConfigWatch, keys: Arc<LocalKeys>, fetch: Fetch) -> Result<()> {
+    let current = config.borrow().clone();
+    let dirs = match module_paths(&current, &fetch.module) {
+        Some(paths) => match resolve_module(socket, &fetch.module, &paths).await? {
+            Some(dirs) => dirs,
+            None => return Ok(()),
+        },
+        None => {
+            socket.send(NotFound::new()).await?;
+            return Ok(());
+        }
+    };
+    socket.send(Ok::new()).await?;
+    socket.answer_capabilities().await?;
+    info!("Sending {} files of {}", fetch.paths.len(), fetch.module);
+    let max_size = module_max_file_size(&current, &fetch.module);
+
+    // Wait for external writers holding the module lock, just like a full sync does
+    let lock = open_module_lock(&fetch.module).await?;
+    lock.lock_shared().await?;
+
+    let res = async {
+        for path in fetch.paths {
+            match module_file(&dirs, &path).await {
+                Some((dir, file)) => { sync_file(socket, dir, &file, keys.clone(), current.hash_algo, max_size).await?; }
+                None => warn!("Node fetched {}, which isn't in {}", path, fetch.module),
+            }
+        }
+        socket.send(EndSync::new()).await?;
+        socket.expect::<Ok>().await
+    }.await;
+    lock.unlock().await?;
+    res.map(|_| ())
+}
+
+/// Push [module] to the mirra at [addr] once, acting as its root over a connection opened from this side
+pub async fn push_once(addr: String, module: String, config: Arc<Config>, keys: Arc<LocalKeys>) -> Result<()> {
+    let paths = module_paths(&config, &module)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} isn't shared", module)))?;
+
+    let mut socket = Client::connect_with(addr, connect_timeout(), connect_retries()).await?;
+    socket.send(Push::new(module.clone())).await?;
+    match socket.read_packet_kind().await? {
+        PacketKind::Ok => {}
+        PacketKind::NotFound => return Err(Error::new(ErrorKind::PermissionDenied, format!("remote mirra doesn't accept pushes of {}", module))),
+        PacketKind::ErrorMsg => return Err(socket.remote_error().await),
+        _ => return Err(Error::from(ErrorKind::InvalidData)),
+    }
+    socket.answer_capabilities().await?;
+
+    let dirs = match resolve_module(&mut socket, &module, &paths).await? {
+        Some(dirs) => dirs,
+        None => return Err(Error::new(ErrorKind::NotFound, format!("the path of {} doesn't exist", module))),
+    };
+    let max_size = module_max_file_size(&config, &module);
+    if process_full_sync(&mut socket, &module, &dirs, keys, config.hash_algo, max_size).await? {
+        socket.close().await?;
+    }
     Ok(())
 }
 
 /// Main lifecycle of a connection to a node
-async fn process_socket(socket: &mut Client, config: Arc<Config>, keys: Arc<LocalKeys>) -> Result<()> {
+/// Multiplexed connections hand their channels to [channels], which are then processed like any other connection
+async fn process_socket(mut socket: Client, config: ConfigWatch, keys: Arc<LocalKeys>, shutdown: Shutdown, channels: tokio_mpsc::Sender<Client>) -> Result<()> {
     let remote = socket.peer_addr();
     info!("Connected with {}", remote.ip());
 
     let mut module: String;
-    let dir: PathBuf;
+    let module_dirs: Vec<String>;
+    let dirs: Vec<PathBuf>;
 
     // Handshake with the node
     loop {
@@ -115,18 +529,19 @@ async fn process_socket(socket: &mut Client, config: Arc<Config>, keys: Arc<Loca
             PacketKind::Handshake => {
                 let handshake: Handshake = socket.expect_unchecked().await?;
 
-                socket.send(Ok::new()).await?;
-
-                info!("Performed handshake");
-
                 module = handshake.module;
-                if let Some(share) = config.shares.get(&module) {
-                    // Save an absolute path
-                    dir = fs::canonicalize(PathBuf::from(share.path.clone())).await?;
-                    break;
-                } else if let Some(sync) = config.syncs.get(&module) {
-                    // Save an absolute path
-                    dir = fs::canonicalize(PathBuf::from(sync.path.clone())).await?;
+                let current = config.borrow().clone();
+                if let Some(paths) = module_paths(&current, &module) {
+                    // Save absolute paths
+                    dirs = match resolve_module(&mut socket, &module, &paths).await? {
+                        Some(dirs) => dirs,
+                        None => return Ok(()),
+                    };
+                    module_dirs = paths;
+
+                    socket.send(Ok::new()).await?;
+                    socket.answer_capabilities().await?;
+                    info!("Performed handshake");
                     break;
                 } else {
                     // The requested module wasn't found
@@ -139,100 +554,399 @@ async fn process_socket(socket: &mut Client, config: Arc<Config>, keys: Arc<Loca
                 socket.send(Close::new()).await?;
                 return Ok(());
             }
+            // The node receives part of a full sync over this connection
+            PacketKind::Fetch => {
+                let fetch: Fetch = socket.expect_unchecked().await?;
+                return process_fetch(&mut socket, &config, keys, fetch).await;
+            }
+            // A root behind a NAT pushes a module to this mirra instead
+            PacketKind::Push => {
+                let push: Push = socket.expect_unchecked().await?;
+                return node::receive_push(socket, &config, push.module).await;
+            }
+            // The node wants to sync several modules over this connection
+            PacketKind::Multiplex => {
+                socket.expect_unchecked::<Multiplex>().await?;
+                socket.send(Ok::new()).await?;
+                info!("Multiplexing connection with {}", remote.ip());
+                return Mux::accept(socket.into_connection(), remote, channels).await;
+            }
             _ => {
+                socket.send(ErrorMsg::new("expected a handshake".to_string())).await?;
                 return Err(Error::from(ErrorKind::InvalidData));
             }
         }
     }
 
+    // Only look at the config again once it changed
+    let mut current = config.borrow().clone();
+
     // Sync the entire module at first
-    process_full_sync(socket, dir.clone(), keys.clone()).await?;
+    if !process_full_sync(&mut socket, &module, &dirs, keys.clone(), current.hash_algo, module_max_file_size(&current, &module)).await? {
+        info!("{} pulled {}", remote.ip(), module);
+        return Ok(());
+    }
 
     // Watch the module for any changes to files
-    let (tx, rx) = mpsc::channel();
-    let mut watcher = notify::watcher(tx, Duration::from_secs(1)).unwrap();
-    // note: this creates a new thread
-    watcher.watch(dir.clone(), RecursiveMode::Recursive).unwrap();
+    let (tx, mut rx) = mpsc::channel();
+    // Only kept alive, the changes arrive through [rx]
+    let mut _watcher = watch_module(&dirs, tx)?;
 
     let mut last_heartbeat = SystemTime::now();
+    // When the node last proved it's still there
+    let mut last_ack = Instant::now();
 
     // Main loop
     loop {
+        // Politely end the connection once mirra shuts down
+        if *shutdown.borrow() {
+            info!("Closing connection with {}", remote.ip());
+            socket.close().await?;
+            return Ok(());
+        }
+
+        // Stop syncing modules that were removed from Mirra.toml or moved, leave everything else be
+        if !Arc::ptr_eq(&current, &config.borrow()) {
+            current = config.borrow().clone();
+            if module_paths(&current, &module).as_ref() != Some(&module_dirs) {
+                info!("{} is no longer shared, closing connection with {}", module, remote.ip());
+                socket.close().await?;
+                return Ok(());
+            }
+        }
+
         // This gives us an Err if there are no events
         // giving us time to do heartbeating
-        let event = rx.try_recv();
+        // Changes to a paused module wait in [rx] until it's resumed
+        let event = if control::is_paused(&module) { Err(TryRecvError::Empty) } else { rx.try_recv() };
         if event.is_err() {
             if event.as_ref().err().unwrap() == &TryRecvError::Empty {
                 let now = SystemTime::now();
-                // Send a heartbeat every 20 seconds
-                if now.duration_since(last_heartbeat).unwrap() > Duration::from_secs(20) {
+                let idle_timeout = current.idle_timeout.map(Duration::from_secs).unwrap_or(DEFAULT_IDLE_TIMEOUT);
+                // Heartbeat often enough that a short timeout still gets a chance to be answered
+                if now.duration_since(last_heartbeat).unwrap_or_default() > HEARTBEAT_INTERVAL.min(idle_timeout / 2) {
                     // Reset timer
                     last_heartbeat = now;
                     socket.send(Heartbeat::new()).await?;
 
-                    let next = socket.read_packet_kind().await?;
+                    // A node that vanished without closing the connection would never answer
+                    let deadline = last_ack + idle_timeout;
+                    let next = match tokio::time::timeout_at(deadline.into(), socket.read_packet_kind()).await {
+                        Ok(next) => next?,
+                        Err(_) => {
+                            warn!("{} didn't answer for {}, closing connection", remote.ip(), format_duration(idle_timeout));
+                            return Ok(());
+                        }
+                    };
+                    last_ack = Instant::now();
                     match next {
                         // The node should acknowledge, but you never know
                         PacketKind::Ok => {}
+                        // The node uses the heartbeat to ask for a full sync
+                        PacketKind::RequestSync => {
+                            socket.expect_unchecked::<RequestSync>().await?;
+                            info!("{} asked for a full sync of {}", remote.ip(), module);
+                            if !process_full_sync(&mut socket, &module, &dirs, keys.clone(), current.hash_algo, module_max_file_size(&current, &module)).await? {
+                                return Ok(());
+                            }
+                        }
+                        // Or to get a single file again
+                        PacketKind::RequestFile => {
+                            let request: RequestFile = socket.expect_unchecked().await?;
+                            if let Some((dir, path)) = module_file(&dirs, &request.path).await {
+                                info!("{} asked for {} again", remote.ip(), request.path);
+                                sync_file(&mut socket, dir, &path, keys.clone(), current.hash_algo, module_max_file_size(&current, &module)).await?;
+                            } else {
+                                warn!("{} asked for {}, which isn't in {}", remote.ip(), request.path, module);
+                            }
+                        }
                         PacketKind::Close => {
                             socket.send(Close::new()).await?;
                             return Ok(());
                         }
+                        PacketKind::ErrorMsg => {
+                            return Err(socket.remote_error().await);
+                        }
                         _ => {
                             return Err(Error::from(ErrorKind::InvalidData));
                         }
                     }
                 }
-            } else if let Err(e) = event {
-                println!("watch error: {}", e.to_string());
+            } else {
+                // The watcher's thread is gone, so nothing would be synced anymore
+                warn!("Watching {} stopped, watching it again", module);
+                (_watcher, rx) = rewatch_module(&dirs)?;
+                if !process_full_sync(&mut socket, &module, &dirs, keys.clone(), current.hash_algo, module_max_file_size(&current, &module)).await? {
+                    return Ok(());
+                }
             }
+            // Give other tasks, like the shutdown signal, a chance to run
+            tokio::time::sleep(Duration::from_millis(100)).await;
             continue;
         }
 
+        // Take everything that piled up, so bursts of removes and renames can be sent together
+        let mut events = vec![event.unwrap()];
+        while let Ok(event) = rx.try_recv() {
+            events.push(event);
+        }
+        let mut events = events.into_iter().peekable();
+
         // Handle any changes
-        match event.unwrap() {
-            // Create and write are basically the same
-            DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
-                info!("Dispatching file update event: {}", stringify(&path)?);
-                sync_file(socket, dir.clone(), path.as_path(), keys.clone()).await?;
-            }
-            // Remove is rather trivial
-            DebouncedEvent::Remove(path) => {
-                info!("Dispatching remove event: {}", stringify(&path)?);
-                socket.send(Remove::new(stringify(path.strip_prefix(dir.clone()).unwrap())?)).await?;
-                socket.expect::<Ok>().await?;
-            }
-            // Rename is rather trivial
-            DebouncedEvent::Rename(old, new) => {
-                info!("Dispatching rename event: {} -> {}", stringify(&old)?, stringify(&new)?);
-                socket.send(Rename::new(stringify(old.strip_prefix(dir.clone()).unwrap())?,
-                    stringify(new.strip_prefix(dir.clone()).unwrap())?)).await?;
-                socket.expect::<Ok>().await?;
-            }
-            // Just resynchronise the entire thing to be share
-            DebouncedEvent::Rescan => process_full_sync(socket, dir.clone(), keys.clone()).await?,
-            _ => {}
+        while let Some(event) = events.next() {
+            match event {
+                // New directories might stay empty, so create them right away
+                DebouncedEvent::Create(path) if path.is_dir() => {
+                    info!("Dispatching directory create event: {}", stringify(&path)?);
+                    sync_make_dir(&mut socket, source_dir(&dirs, &path)?.clone(), path.as_path()).await?;
+                }
+                // Create and write are basically the same
+                DebouncedEvent::Create(path) | DebouncedEvent::Write(path) => {
+                    let dir = source_dir(&dirs, &path)?;
+                    if let Some(other) = collision(&dirs, dir, path.strip_prefix(dir).unwrap()) {
+                        warn!("Not syncing {}, {} has the same path", stringify(&path)?, other.display());
+                        continue;
+                    }
+                    info!("Dispatching file update event: {}", stringify(&path)?);
+                    sync_file(&mut socket, dir.clone(), path.as_path(), keys.clone(), current.hash_algo, module_max_file_size(&current, &module)).await?;
+                }
+                // Remove is rather trivial
+                DebouncedEvent::Remove(path) => {
+                    forget_hash(&path);
+                    let mut paths = vec![wire_path(path.strip_prefix(source_dir(&dirs, &path)?).unwrap())?];
+                    while let Some(DebouncedEvent::Remove(path)) = events.next_if(|e| matches!(e, DebouncedEvent::Remove(_))) {
+                        forget_hash(&path);
+                        paths.push(wire_path(path.strip_prefix(source_dir(&dirs, &path)?).unwrap())?);
+                    }
+
+                    if paths.len() == 1 {
+                        info!("Dispatching remove event: {}", paths[0]);
+                        socket.send(Remove::new(paths.remove(0))).await?;
+                    } else {
+                        info!("Dispatching {} remove events", paths.len());
+                        socket.send(RemoveMany::new(paths)).await?;
+                    }
+                    socket.expect::<Ok>().await?;
+                }
+                // Rename is rather trivial
+                DebouncedEvent::Rename(old, new) => {
+                    forget_hash(&old);
+                    let mut olds = vec![wire_path(old.strip_prefix(source_dir(&dirs, &old)?).unwrap())?];
+                    let mut news = vec![wire_path(new.strip_prefix(source_dir(&dirs, &new)?).unwrap())?];
+                    while let Some(DebouncedEvent::Rename(old, new)) = events.next_if(|e| matches!(e, DebouncedEvent::Rename(..))) {
+                        forget_hash(&old);
+                        olds.push(wire_path(old.strip_prefix(source_dir(&dirs, &old)?).unwrap())?);
+                        news.push(wire_path(new.strip_prefix(source_dir(&dirs, &new)?).unwrap())?);
+                    }
+
+                    let renamed: HashSet<String> = news.iter().cloned().collect();
+                    if olds.len() == 1 {
+                        info!("Dispatching rename event: {} -> {}", olds[0], news[0]);
+                        socket.send(Rename::new(olds.remove(0), news.remove(0))).await?;
+                    } else {
+                        info!("Dispatching {} rename events", olds.len());
+                        socket.send(RenameMany::new(olds, news)).await?;
+                    }
+
+                    match socket.read_packet_kind().await? {
+                        PacketKind::Ok => {}
+                        // The node couldn't rename some paths, so send them again
+                        PacketKind::Want => {
+                            let want: Want = socket.expect_unchecked().await?;
+                            for path in want.paths.iter().filter(|path| renamed.contains(*path)) {
+                                info!("Node failed to rename {}, sending it again", path);
+                                let relative = local_path(path)?;
+                                if let Some(dir) = dirs.iter().find(|dir| dir.join(&relative).exists()) {
+                                    sync_path(&mut socket, dir, &dir.join(&relative), keys.clone(), current.hash_algo, module_max_file_size(&current, &module)).await?;
+                                }
+                            }
+                        }
+                        _ => return Err(Error::new(ErrorKind::InvalidData, "unexpected package")),
+                    }
+                }
+                // Just resynchronise the entire thing to be share
+                DebouncedEvent::Rescan => if !process_full_sync(&mut socket, &module, &dirs, keys.clone(), current.hash_algo, module_max_file_size(&current, &module)).await? {
+                    return Ok(());
+                },
+                // The watch may be broken, so set it up again and catch up on whatever was missed
+                DebouncedEvent::Error(e, path) => {
+                    match path {
+                        Some(path) => warn!("Watching {} failed at {}: {}, watching it again", module, stringify(&path)?, e),
+                        None => warn!("Watching {} failed: {}, watching it again", module, e),
+                    }
+                    (_watcher, rx) = rewatch_module(&dirs)?;
+                    if !process_full_sync(&mut socket, &module, &dirs, keys.clone(), current.hash_algo, module_max_file_size(&current, &module)).await? {
+                        return Ok(());
+                    }
+                    // Anything else that was reported is covered by the full sync
+                    break;
+                }
+                // Notices arrive before the file is fully written or the rename is known, so wait for the final event
+                DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => {}
+                // Permissions aren't synced
+                DebouncedEvent::Chmod(_) => {}
+            }
         }
+        // The node acknowledged every change, so it's still there
+        last_ack = Instant::now();
+    }
+}
+
+/// Watch every directory of a module, sending changes to [events]
+/// The watcher runs on a thread of its own, until it's dropped
+fn watch_module(dirs: &[PathBuf], events: mpsc::Sender<DebouncedEvent>) -> Result<RecommendedWatcher> {
+    let mut watcher = notify::watcher(events, WATCH_DELAY)
+        .map_err(|e| Error::new(ErrorKind::Other, e))?;
+    for dir in dirs {
+        watcher.watch(dir, RecursiveMode::Recursive)
+            .map_err(|e| Error::new(ErrorKind::Other, format!("can't watch {}: {}", dir.display(), e)))?;
     }
+    Ok(watcher)
+}
+
+/// Replace a broken watch of a module, returning the new watcher and where its changes arrive
+fn rewatch_module(dirs: &[PathBuf]) -> Result<(RecommendedWatcher, mpsc::Receiver<DebouncedEvent>)> {
+    let (tx, rx) = mpsc::channel();
+    Ok((watch_module(dirs, tx)?, rx))
 }
 
 /// The main root lifecycle
-pub async fn root(config: Arc<Config>, keys: Arc<LocalKeys>) -> Result<()> {
-    let mut server = Server::new(config.port).await?;
+/// If [web] is set, the port is shared with the web server and HTTP connections are handed to it
+pub async fn root(config: ConfigWatch, keys: Arc<LocalKeys>, web: Option<tokio_mpsc::Sender<TcpStream>>, mut shutdown: Shutdown) -> Result<()> {
+    // The server keeps listening where it started, even if Mirra.toml changes
+    let (bind, port) = {
+        let current = config.borrow();
+        (current.bind, current.port)
+    };
+    let mut server = Server::new(bind, port).await?;
+    if let Some(web) = web {
+        server.share_with_web(web);
+    }
+    // Every connection holds a sender, so once all of them are dropped, all connections are closed
+    let (connections, mut closed) = tokio_mpsc::channel::<()>(1);
+    // Channels of multiplexed connections are handled just like new connections
+    let (channels, mut new_channels) = tokio_mpsc::channel::<Client>(16);
+    // Limits the connections handled at once, replaced whenever `max_connections` changes
+    let mut limit: Option<(usize, Arc<Semaphore>)> = None;
+    // When each address last started a window of connections, and how many it opened since
+    let mut recent: HashMap<IpAddr, (Instant, u32)> = HashMap::new();
 
     loop {
-        // Accept a new connection
-        let mut socket = server.accept().await?;
+        // Accept a new connection, unless mirra is shutting down
+        let (mut socket, multiplexed) = tokio::select! {
+            socket = server.accept() => (socket?, false),
+            Some(channel) = new_channels.recv() => (channel, true),
+            _ = shutdown_requested(&mut shutdown) => break,
+        };
+
+        let (max_connections, max_per_minute) = {
+            let current = config.borrow();
+            (current.max_connections, current.max_connections_per_minute)
+        };
+
+        // Channels of a multiplexed connection count towards both limits like connections of their own,
+        // otherwise a single connection could open any number of them
+        if let Some(max_per_minute) = max_per_minute {
+            let now = Instant::now();
+            recent.retain(|_, (start, _)| now.duration_since(*start) < Duration::from_secs(60));
+            let (_, count) = recent.entry(socket.peer_addr().ip()).or_insert((now, 0));
+            *count += 1;
+            if *count > max_per_minute {
+                debug!("Rejecting {}, too many connections", socket.peer_addr());
+                tokio::spawn(async move { socket.send(Close::new()).await });
+                continue;
+            }
+        }
+
+        let permit = match max_connections {
+            Some(max) => {
+                if limit.as_ref().map(|(current, _)| *current) != Some(max) {
+                    limit = Some((max, Arc::new(Semaphore::new(max))));
+                }
+                match limit.as_ref().unwrap().1.clone().try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        warn!("Rejecting {}, already handling {} connections", socket.peer_addr(), max);
+                        tokio::spawn(async move { socket.send(Close::new()).await });
+                        continue;
+                    }
+                }
+            }
+            None => {
+                limit = None;
+                None
+            }
+        };
 
         // Get a new reference to config and keys
         let local_keys = keys.clone();
         let local_config = config.clone();
+        let local_shutdown = shutdown.clone();
+        let connection = connections.clone();
+        let local_channels = channels.clone();
         // Create a new task for the [process_socket] call
-        tokio::spawn(async move {
-            let r = process_socket(&mut socket, local_config, local_keys).await;
+        let peer = socket.peer_addr();
+        // Channels are counted as part of the connection they arrived over
+        let active = (!multiplexed).then(metrics::ActiveConnection::new);
+        tokio::spawn(with_connection(peer, async move {
+            let r = process_socket(socket, local_config, local_keys, local_shutdown, local_channels).await;
             if r.is_err() {
+                metrics::sync_error();
                 warn!("{}", r.err().unwrap().to_string());
             }
+            drop(active);
+            drop(connection);
+            drop(permit);
+        }));
+    }
+
+    // Let running connections finish their transfers
+    drop(connections);
+    let _ = closed.recv().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::time::timeout;
+
+    use crate::keys::LocalKeys;
+    use crate::packet::PacketKind;
+    use crate::socket::tests::connected;
+    use crate::util::{AsyncFileLock, HashAlgo, open_module_lock};
+    use crate::util::tests::{test_mirra_dir, TestDir};
+    use super::process_full_sync;
+
+    #[tokio::test]
+    async fn full_syncs_wait_for_the_module_lock() {
+        let dir = TestDir::new("root-lock");
+        let module = dir.join("module");
+        std::fs::create_dir(&module).unwrap();
+        std::fs::write(dir.join("module/a.txt"), "a").unwrap();
+        // Module locks are kept in the config dir
+        test_mirra_dir();
+
+        // Pretend to be an external writer
+        let lock = open_module_lock("locked").await.unwrap();
+        lock.lock().await.unwrap();
+
+        let private_key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 512).unwrap();
+        let keys = Arc::new(LocalKeys { public_key: rsa::RsaPublicKey::from(&private_key), private_key });
+        let (mut root, mut node) = connected();
+        let sync = tokio::spawn(async move {
+            let _ = process_full_sync(&mut root, "locked", &[module], keys, HashAlgo::Blake3, None).await;
         });
+
+        assert!(timeout(Duration::from_millis(300), node.read_packet_kind()).await.is_err(), "synced while the module was locked");
+        lock.unlock().await.unwrap();
+        let next = timeout(Duration::from_secs(5), node.read_packet_kind()).await.unwrap().unwrap();
+        assert!(next == PacketKind::BeginSync);
+
+        drop(node);
+        sync.await.unwrap();
     }
 }
@@ -4,50 +4,453 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use std::io::{Error, ErrorKind, Result};
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, IsTerminal, Result, SeekFrom};
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info};
 use num_traits::FromPrimitive;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, Interest};
 
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Semaphore};
 
-use crate::packet::{Close, Packet, PacketKind, ReadAny, WriteAny};
+use crate::delta::{Rolling, Signatures};
+use crate::metrics;
+use crate::packet::{CAPABILITIES, Capabilities, Close, ErrorMsg, Packet, PacketKind, ReadAny, WriteAny};
+use crate::util::{buffer_size, format_duration, format_size, HashAlgo, StreamHasher, TokenBucket};
+
+/// How long to wait before trying to connect again
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How often the progress of a transfer is logged when there's no terminal to draw a bar on
+const PROGRESS_LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// How often the throughput of a transfer is sampled
+const RATE_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+/// How many seconds of samples the throughput is smoothed over
+const RATE_WINDOW: f64 = 5.0;
+/// How long a connection on a shared port may take to reveal what it speaks
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many connections on a shared port may be sniffed at once, any beyond that are dropped
+const MAX_SNIFFING: usize = 256;
+/// Methods that start an HTTP request, including the space after them
+const HTTP_METHODS: &[&[u8]] = &[b"GET ", b"HEAD ", b"POST ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT ", b"TRACE "];
+
+/// How many interrupted transfers are remembered, and for how long, as most are never tried again
+const MAX_INTERRUPTED: usize = 1024;
+const INTERRUPTED_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// How often a transfer was interrupted and how fast it was going, carried over to its next attempt
+#[derive(Clone, Copy)]
+struct Interrupted {
+    attempts: u32,
+    rate: Option<f64>,
+    /// When the transfer was interrupted
+    at: Instant,
+}
+
+/// Transfers that were interrupted, by what they transferred, until they finish or expire
+fn interrupted() -> &'static Mutex<HashMap<String, Interrupted>> {
+    static INTERRUPTED: OnceLock<Mutex<HashMap<String, Interrupted>>> = OnceLock::new();
+    INTERRUPTED.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Remember that [transfer] was interrupted, forgetting expired transfers and the oldest one if there are too many
+fn remember_interrupted(transfer: String, carried: Interrupted) {
+    let mut interrupted = interrupted().lock().unwrap();
+    interrupted.retain(|_, earlier| earlier.at.elapsed() < INTERRUPTED_EXPIRY);
+    if interrupted.len() >= MAX_INTERRUPTED && !interrupted.contains_key(&transfer) {
+        if let Some(oldest) = interrupted.iter().min_by_key(|(_, earlier)| earlier.at).map(|(oldest, _)| oldest.clone()) {
+            interrupted.remove(&oldest);
+        }
+    }
+    interrupted.insert(transfer, carried);
+}
+
+/// The progress of a transfer, drawn as a bar on a terminal and logged periodically otherwise
+struct Progress {
+    bar: Option<ProgressBar>,
+    /// Whether to log the progress, because there's no terminal to draw on
+    log: bool,
+    /// What is being done, as it appears in log lines
+    action: &'static str,
+    total: u64,
+    position: u64,
+    /// What the bar shows after the throughput, like the download limit
+    note: String,
+    /// The smoothed throughput in bytes per second, none until the first sample
+    rate: Option<f64>,
+    /// When and at which position the throughput was last sampled
+    sampled: Instant,
+    sampled_position: u64,
+    logged: Instant,
+    /// What is transferred, if the transfer is continued when it's interrupted
+    transfer: Option<String>,
+    /// How many times the transfer was tried, including this one
+    attempt: u32,
+    finished: bool,
+}
+
+impl Progress {
+    /// Track a transfer of [total] bytes, [draw_bar] says whether to draw a bar on a terminal
+    fn new(action: &'static str, total: u64, draw_bar: bool, limit: Option<&TokenBucket>) -> Self {
+        // The bar is drawn to stderr, which is also where the log goes
+        let terminal = std::io::stderr().is_terminal();
+        let bar = (terminal && draw_bar).then(|| {
+            let bar = ProgressBar::new(total);
+            bar.set_style(ProgressStyle::default_bar()
+                .template("{wide_bar} {bytes}/{total_bytes} {msg}"));
+            bar
+        });
+        let note = limit.map(|limit| format!(" (limited to {}/s)", format_size(limit.rate())))
+            .unwrap_or_default();
+        if let Some(bar) = &bar {
+            bar.set_message(format!("starting{}", note));
+        }
+
+        let now = Instant::now();
+        Progress {
+            bar,
+            log: !terminal,
+            action,
+            total,
+            position: 0,
+            note,
+            rate: None,
+            sampled: now,
+            sampled_position: 0,
+            logged: now,
+            transfer: None,
+            attempt: 1,
+            finished: false,
+        }
+    }
+
+    /// Continue where earlier attempts at [transfer] left off, if it was interrupted before
+    fn continues(mut self, transfer: &str) -> Self {
+        let earlier = interrupted().lock().unwrap().get(transfer).copied().filter(|earlier| earlier.at.elapsed() < INTERRUPTED_EXPIRY);
+        if let Some(interrupted) = earlier {
+            self.attempt = interrupted.attempts + 1;
+            self.rate = interrupted.rate;
+            self.note.push_str(&format!(" (attempt {})", self.attempt));
+            if let Some(bar) = &self.bar {
+                bar.set_message(format!("{}{}", self.throughput(), self.note));
+            }
+        }
+        self.transfer = Some(transfer.to_string());
+        self
+    }
+
+    /// Continue a transfer that already got [position] bytes in
+    fn set_position(&mut self, position: u64) {
+        self.position = position;
+        self.sampled_position = position;
+        if let Some(bar) = &self.bar {
+            bar.set_position(position);
+        }
+    }
+
+    fn inc(&mut self, delta: u64) {
+        self.position += delta;
+        if let Some(bar) = &self.bar {
+            bar.inc(delta);
+        }
+        if self.sampled.elapsed() >= RATE_SAMPLE_INTERVAL {
+            self.sample();
+        }
+        if !self.log || self.logged.elapsed() < PROGRESS_LOG_INTERVAL {
+            return;
+        }
+
+        self.logged = Instant::now();
+        let percent = if self.total > 0 { self.position * 100 / self.total } else { 100 };
+        info!("{} {}% ({} of {}, {}){}", self.action, percent, format_size(self.position),
+            format_size(self.total), self.throughput(), self.note);
+    }
+
+    /// Fold the throughput since the last sample into the moving average
+    /// Older samples lose weight exponentially, so bursts even out over [RATE_WINDOW] seconds
+    fn sample(&mut self) {
+        let elapsed = self.sampled.elapsed().as_secs_f64();
+        let current = (self.position - self.sampled_position) as f64 / elapsed;
+        let weight = 1.0 - (-elapsed / RATE_WINDOW).exp();
+        self.rate = Some(match self.rate {
+            Some(rate) => rate + weight * (current - rate),
+            None => current,
+        });
+        self.sampled = Instant::now();
+        self.sampled_position = self.position;
+
+        if let Some(bar) = &self.bar {
+            bar.set_message(format!("{}{}", self.throughput(), self.note));
+        }
+    }
+
+    /// The smoothed throughput and how long the rest of the transfer will take at that rate
+    fn throughput(&self) -> String {
+        match self.rate {
+            Some(rate) if rate >= 1.0 => {
+                let remaining = self.total.saturating_sub(self.position) as f64 / rate;
+                format!("{}/s, {} left", format_size(rate as u64), format_duration(Duration::from_secs_f64(remaining)))
+            }
+            Some(_) => "stalled".to_string(),
+            None => "starting".to_string(),
+        }
+    }
+
+    fn finish(&mut self) {
+        self.finished = true;
+        if let Some(transfer) = &self.transfer {
+            interrupted().lock().unwrap().remove(transfer);
+        }
+        if let Some(bar) = &self.bar {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+impl Drop for Progress {
+    /// Transfers that end without finishing were interrupted, remember them for the next attempt
+    fn drop(&mut self) {
+        if let (Some(transfer), false) = (&self.transfer, self.finished) {
+            remember_interrupted(transfer.clone(), Interrupted { attempts: self.attempt, rate: self.rate, at: Instant::now() });
+        }
+    }
+}
 
 /// Thin layer above [tokio::net::TcpListener]
 pub struct Server {
     listener: TcpListener,
+    /// Set once the port is shared with the web server, see [Server::share_with_web]
+    shared: Option<SharedPort>,
+}
+
+/// Where the connections of a shared port go once it's known what they speak
+struct SharedPort {
+    web: mpsc::Sender<TcpStream>,
+    mirra: mpsc::Sender<Client>,
+    sniffed: mpsc::Receiver<Client>,
+    sniffing: Arc<Semaphore>,
+}
+
+/// What a connection on a shared port speaks
+enum Sniffed {
+    Mirra,
+    Http,
+}
+
+/// Peek at the first bytes of [socket] to find out what it speaks, without consuming any of them
+async fn sniff(socket: &TcpStream) -> Result<Sniffed> {
+    let mut buf = [0; 8];
+    loop {
+        let read = socket.peek(&mut buf).await?;
+        if read == 0 {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed before sending anything"));
+        }
+        // Nodes always start with a packet kind, which are all below any printable character
+        if PacketKind::from_u8(buf[0]).is_some() {
+            return Ok(Sniffed::Mirra);
+        }
+        let start = &buf[..read];
+        if HTTP_METHODS.iter().any(|method| start.starts_with(method)) {
+            return Ok(Sniffed::Http);
+        }
+        if read == buf.len() || !HTTP_METHODS.iter().any(|method| method.starts_with(start)) {
+            return Err(Error::new(ErrorKind::InvalidData, "connection speaks neither mirra nor HTTP"));
+        }
+        // Only part of the method arrived, peeking again would return the same bytes right away
+        wait_for_more(socket, read).await?;
+    }
+}
+
+/// Wait until more than [seen] bytes can be peeked from [socket]
+async fn wait_for_more(socket: &TcpStream, seen: usize) -> Result<()> {
+    let mut scratch = [MaybeUninit::uninit(); 8];
+    loop {
+        socket.readable().await?;
+        // Unchanged data counts as would block, which clears the readiness so the next wait actually waits
+        let peeked = socket.try_io(Interest::READABLE, || match SockRef::from(socket).peek(&mut scratch)? {
+            read if read == seen => Err(ErrorKind::WouldBlock.into()),
+            _ => Ok(()),
+        });
+        match peeked {
+            Ok(()) => return Ok(()),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
+        }
+    }
 }
 
 impl Server {
-    /// Bind a server to 0.0.0.0:port
-    pub async fn new(port: u16) -> Result<Self> {
+    /// Bind a server to bind:port, or to [::]:port and 0.0.0.0:port if [bind] is None
+    pub async fn new(bind: Option<IpAddr>, port: u16) -> Result<Self> {
+        let listener = if let Some(bind) = bind {
+            TcpListener::bind(SocketAddr::new(bind, port)).await?
+        } else if let Ok(listener) = Self::bind_dual_stack(port) {
+            listener
+        } else {
+            // IPv6 is likely unavailable
+            TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port)).await?
+        };
+
         Ok(Server {
-            listener: TcpListener::bind(SocketAddr::new("0.0.0.0".parse().unwrap(), port)).await?
+            listener,
+            shared: None,
         })
     }
 
+    /// Hand HTTP connections to [web] instead of accepting them, so the web server can use the same port
+    pub fn share_with_web(&mut self, web: mpsc::Sender<TcpStream>) {
+        let (mirra, sniffed) = mpsc::channel(16);
+        self.shared = Some(SharedPort { web, mirra, sniffed, sniffing: Arc::new(Semaphore::new(MAX_SNIFFING)) });
+    }
+
+    /// Bind to [::]:port, accepting IPv4 connections as well
+    fn bind_dual_stack(port: u16) -> Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        TcpListener::from_std(socket.into())
+    }
+
     /// Wait for a new connection and accept it
     pub async fn accept(&mut self) -> Result<Client> {
-        let (socket, _) = self.listener.accept().await?;
-        Ok(Client {
-            stream: socket
-        })
+        let shared = match &mut self.shared {
+            Some(shared) => shared,
+            None => {
+                let (socket, peer) = self.listener.accept().await?;
+                return Ok(Client::from_connection(Box::new(socket), peer));
+            }
+        };
+
+        loop {
+            let (socket, peer) = tokio::select! {
+                accepted = self.listener.accept() => accepted?,
+                Some(client) = shared.sniffed.recv() => return Ok(client),
+            };
+            let permit = match shared.sniffing.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    debug!("Dropping connection from {}, too many connections are still being sniffed", peer);
+                    continue;
+                }
+            };
+            // Slow connections are sniffed on their own, so they don't hold up accepting others
+            let web = shared.web.clone();
+            let mirra = shared.mirra.clone();
+            tokio::spawn(async move {
+                let _permit = permit;
+                match tokio::time::timeout(SNIFF_TIMEOUT, sniff(&socket)).await {
+                    Ok(Ok(Sniffed::Mirra)) => { let _ = mirra.send(Client::from_connection(Box::new(socket), peer)).await; }
+                    Ok(Ok(Sniffed::Http)) => { let _ = web.send(socket).await; }
+                    Ok(Err(e)) => debug!("Dropping connection from {}: {}", peer, e),
+                    Err(_) => debug!("Dropping connection from {}, it didn't send anything", peer),
+                }
+            });
+        }
     }
 }
 
-/// Thin layer above [tokio::net::TcpStream]
+/// Anything a [Client] can talk over, a TCP connection or a channel of a multiplexed one
+pub trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+pub type Stream = Box<dyn Connection>;
+
+/// Thin layer above a [Connection], usually a [tokio::net::TcpStream]
 pub struct Client {
-    pub(crate) stream: TcpStream,
+    stream: Stream,
+    peer: SocketAddr,
+    /// Paces received files, if set
+    download_limit: Option<TokenBucket>,
+    /// Optional features both sides support, none until they were negotiated
+    capabilities: HashSet<String>,
 }
 
 impl Client {
-    /// Connect to a server at ip:port
-    pub async fn new(addr: String) -> Result<Self> {
-        Ok(Client {
-            stream: TcpStream::connect(addr).await?
-        })
+    /// Connect to a server at ip:port, giving up on each attempt after [timeout]
+    /// Failed attempts are repeated [retries] times before the last error is returned
+    pub async fn connect_with(addr: String, timeout: Duration, retries: u32) -> Result<Self> {
+        let mut attempt = 0;
+        loop {
+            let res = match tokio::time::timeout(timeout, TcpStream::connect(&addr)).await {
+                Ok(res) => res,
+                Err(_) => Err(Error::new(ErrorKind::TimedOut, format!("connecting to {} timed out", addr))),
+            };
+            match res {
+                Ok(stream) => {
+                    let peer = stream.peer_addr()?;
+                    return Ok(Client::from_connection(Box::new(stream), peer));
+                }
+                Err(e) if attempt < retries => {
+                    attempt += 1;
+                    debug!("Connecting to {} failed: {}, trying again", addr, e);
+                    tokio::time::sleep(CONNECT_RETRY_DELAY).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Talk over an already established [stream] to [peer]
+    pub fn from_connection(stream: Stream, peer: SocketAddr) -> Self {
+        Client {
+            stream,
+            peer,
+            download_limit: None,
+            capabilities: HashSet::new(),
+        }
+    }
+
+    /// Give up the client, keeping the underlying connection
+    pub fn into_connection(self) -> Stream {
+        self.stream
+    }
+
+    /// Tell the remote host which [CAPABILITIES] this side supports, and learn which of them both support
+    pub async fn negotiate(&mut self) -> Result<()> {
+        self.send(Capabilities::new(CAPABILITIES.iter().map(|c| c.to_string()).collect())).await?;
+        let agreed: Capabilities = self.expect().await?;
+        // Never use anything that wasn't offered, whatever the remote host claims
+        self.capabilities = agreed.features.into_iter().filter(|c| CAPABILITIES.contains(&c.as_str())).collect();
+        debug!("Negotiated {:?}", self.capabilities);
+        Ok(())
+    }
+
+    /// Answer the remote host's [Capabilities] with the ones both sides support
+    pub async fn answer_capabilities(&mut self) -> Result<()> {
+        let offered: Capabilities = self.expect().await?;
+        self.capabilities = offered.features.into_iter().filter(|c| CAPABILITIES.contains(&c.as_str())).collect();
+        debug!("Negotiated {:?}", self.capabilities);
+        self.send(Capabilities::new(self.capabilities.iter().cloned().collect())).await?;
+        Ok(())
+    }
+
+    /// Whether both sides support the optional [feature]
+    pub fn supports(&self, feature: &str) -> bool {
+        self.capabilities.contains(feature)
+    }
+
+    /// Pick [preferred] if both sides support it, blake3 otherwise
+    pub fn hash_algo(&self, preferred: HashAlgo) -> HashAlgo {
+        if self.supports(&format!("hash:{}", preferred.name())) {
+            preferred
+        } else {
+            HashAlgo::Blake3
+        }
+    }
+
+    /// Receive files with at most [bytes_per_sec] bytes per second
+    pub fn limit_download(&mut self, bytes_per_sec: u64) {
+        self.download_limit = Some(TokenBucket::new(bytes_per_sec));
     }
 
     /// Only read a packets id
@@ -64,90 +467,343 @@ impl Client {
 
     /// Read a packet without reading its kind
     pub async fn expect_unchecked<T>(&mut self) -> Result<T>
-        where TcpStream: ReadAny<T> {
+        where Stream: ReadAny<T> {
         self.stream.read_any().await
     }
 
     /// Read a packet
     pub async fn expect<T: Packet>(&mut self) -> Result<T>
-        where TcpStream: ReadAny<T> {
+        where Stream: ReadAny<T> {
         let id = self.read_packet_kind().await?;
         if id == T::KIND {
             Ok(self.expect_unchecked().await?)
+        } else if id == PacketKind::ErrorMsg {
+            Err(self.remote_error().await)
         } else {
             Err(Error::new(ErrorKind::InvalidData, "unexpected package"))
         }
     }
 
+    /// Read an [ErrorMsg] without reading its kind, and turn it into an error
+    pub async fn remote_error(&mut self) -> Error {
+        match self.expect_unchecked::<ErrorMsg>().await {
+            Ok(error) => Error::new(ErrorKind::Other, format!("remote mirra: {}", error.message)),
+            Err(e) => e,
+        }
+    }
+
     /// Read a file, as if a file was a packet with kind [PacketKind::File], and write to [file]
-    pub async fn expect_file(&mut self, mut file: File) -> Result<usize> {
+    /// The packet starts at an offset, [file] is expected to already contain everything before it
+    /// Sparse files are sent as [PacketKind::SparseFile] instead and have their holes recreated
+    /// Progress is reported as part of [transfer], so that it continues across reconnects
+    pub async fn expect_file(&mut self, mut file: File, transfer: &str) -> Result<usize> {
         let id = self.stream.read_u8().await?;
-        if id != PacketKind::File as u8 {
+        if id == PacketKind::SparseFile as u8 {
+            return self.expect_sparse_file(file, transfer).await;
+        } else if id != PacketKind::File as u8 {
             return Err(Error::new(ErrorKind::InvalidData, "unexpected package"));
         }
 
-        // Get the size of the file
-        let mut size = self.stream.read_u64().await?;
+        // Get where the transfer starts and how much is left
+        let offset = self.stream.read_u64().await?;
+        let size = self.stream.read_u64().await?;
+
+        // Drop anything beyond the offset and continue from there
+        file.set_len(offset).await?;
+        file.seek(SeekFrom::Start(offset)).await?;
+
+        let mut progress = self.receive_progress(offset + size, transfer);
+        progress.set_position(offset);
+
+        let received = self.receive_into(&mut file, size, &mut progress).await?;
+        file.flush().await?;
+        progress.finish();
+
+        Ok(received)
+    }
+
+    /// Read the data regions of a sparse file, leaving holes everywhere else
+    async fn expect_sparse_file(&mut self, mut file: File, transfer: &str) -> Result<usize> {
+        let total = self.stream.read_u64().await?;
+        let data = self.stream.read_u64().await?;
+
+        // Setting the length of an empty file leaves it entirely as a hole
+        file.set_len(0).await?;
+        file.set_len(total).await?;
+
+        let mut progress = self.receive_progress(data, transfer);
+
+        let mut received = 0;
+        loop {
+            let offset = self.stream.read_u64().await?;
+            let size = self.stream.read_u64().await?;
+            // An empty region terminates the file
+            if size == 0 {
+                break;
+            }
+            if offset.checked_add(size).map_or(true, |end| end > total) {
+                return Err(Error::new(ErrorKind::InvalidData, "data region outside of file"));
+            }
+
+            file.seek(SeekFrom::Start(offset)).await?;
+            received += self.receive_into(&mut file, size, &mut progress).await?;
+        }
+        file.flush().await?;
+        progress.finish();
+
+        Ok(received)
+    }
 
-        // Assuming a good size of 0x1000, because that's likely to be one page in memory
-        let mut buf = vec![0; 0x1000];
+    /// Rebuild a file from a [PacketKind::Delta] against [basis], the old version of it, and write it to [file]
+    pub async fn expect_delta(&mut self, basis: &mut File, mut file: File, block_size: u32, transfer: &str) -> Result<usize> {
+        let id = self.read_packet_kind().await?;
+        if id == PacketKind::ErrorMsg {
+            return Err(self.remote_error().await);
+        } else if id != PacketKind::Delta {
+            return Err(Error::new(ErrorKind::InvalidData, "unexpected package"));
+        }
 
-        let bar = ProgressBar::new(size);
-        bar.set_style(ProgressStyle::default_bar()
-            .template("{wide_bar} {bytes_per_sec} {bytes}/{total_bytes}"));
+        let total = self.stream.read_u64().await?;
+        file.set_len(0).await?;
 
+        let mut progress = self.receive_progress(total, transfer);
+        let mut block = vec![0; block_size as usize];
+
+        let mut received = 0;
         loop {
-            // Read 0x1000 at max
-            let to_read = size.min(0x1000) as usize;
+            // Every instruction is a run of literal bytes, followed by a block to copy, if any
+            let literal = self.stream.read_u64().await?;
+            received += self.receive_into(&mut file, literal, &mut progress).await?;
+
+            let copy = self.stream.read_u64().await?;
+            // An empty instruction terminates the file
+            if literal == 0 && copy == 0 {
+                break;
+            }
+            if copy > 0 {
+                let outside = || Error::new(ErrorKind::InvalidData, "block outside of file");
+                let offset = (copy - 1).checked_mul(block_size as u64).ok_or_else(outside)?;
+                basis.seek(SeekFrom::Start(offset)).await?;
+                basis.read_exact(&mut block).await.map_err(|_| outside())?;
+                file.write_all(&block).await?;
+                progress.inc(block_size as u64);
+            }
+        }
+        file.flush().await?;
+        progress.finish();
+
+        Ok(received)
+    }
+
+    /// Track receiving [size] bytes, the bar shows the download limit if there is one
+    fn receive_progress(&self, size: u64, transfer: &str) -> Progress {
+        Progress::new("Received", size, true, self.download_limit.as_ref()).continues(transfer)
+    }
+
+    /// Copy exactly [size] bytes from the remote host to [file]
+    async fn receive_into(&mut self, file: &mut File, mut size: u64, progress: &mut Progress) -> Result<usize> {
+        let mut buf = vec![0; buffer_size()];
+
+        let mut received = 0;
+        while size > 0 {
+            // Read a buffer at max
+            let to_read = size.min(buf.len() as u64) as usize;
 
-            buf.truncate(to_read);
             // Read from remote host
-            let read = self.stream.read(buf.as_mut_slice()).await?;
+            let read = self.stream.read(&mut buf.as_mut_slice()[0..to_read]).await?;
             if read == 0 {
-                break;
+                return Err(Error::new(ErrorKind::UnexpectedEof, "connection closed during transfer"));
             }
-            bar.inc(read as u64);
+            metrics::bytes_received(read as u64);
+            progress.inc(read as u64);
             size -= read as u64;
+            received += read;
             // Write to file
-            file.write_all(&buf.as_slice()[0..to_read]).await?;
+            file.write_all(&buf.as_slice()[0..read]).await?;
+
+            // Slow down if this is faster than allowed
+            if let Some(limit) = &mut self.download_limit {
+                limit.take(read as u64).await;
+            }
         }
-        bar.finish_and_clear();
 
-        Ok(size as usize)
+        Ok(received)
     }
 
     /// Write a packet
     pub async fn send<T: Packet>(&mut self, data: T) -> Result<usize>
-        where TcpStream: WriteAny<T> {
+        where Stream: WriteAny<T> {
         self.stream.write_u8(T::KIND as u8).await?;
         Ok(self.stream.write_any(data).await? + 1)
     }
 
-    /// Write a file, as if a file was a packet with kind [PacketKind::File]
+    /// Write a file, as if a file was a packet with kind [PacketKind::File], starting at [offset]
+    /// Everything sent is added to [hasher], if there is one
     /// This assumes [file] to be locked, or not to be changed during sending
-    pub async fn send_file(&mut self, file: &mut File) -> Result<usize> {
+    pub async fn send_file(&mut self, file: &mut File, offset: u64, hasher: Option<&mut StreamHasher>) -> Result<usize> {
         // Write the packet kind
         self.stream.write_u8(PacketKind::File as u8).await?;
 
-        let size = file.metadata().await?.len();
-        // Write the size
+        let offset = offset.min(file.metadata().await?.len());
+        let size = file.metadata().await?.len() - offset;
+        // Write the offset and the remaining size
+        self.stream.write_u64(offset).await?;
         self.stream.write_u64(size).await?;
 
-        // Again, 0x1000 is likely the size of a page
-        let mut buf = vec![0; 0x1000];
+        // Several files are sent at once, so there's no bar for them
+        let mut progress = Progress::new("Sent", offset + size, false, None);
+        progress.set_position(offset);
+
+        file.seek(SeekFrom::Start(offset)).await?;
+        self.send_from(file, size, &mut progress, hasher).await?;
+
+        Ok(size as usize)
+    }
+
+    /// Write a sparse file as a [PacketKind::SparseFile], skipping its holes
+    /// The whole file, holes included, is added to [hasher], if there is one
+    /// This assumes [file] to be locked, or not to be changed during sending
+    pub async fn send_sparse_file(&mut self, file: &mut File, mut hasher: Option<&mut StreamHasher>) -> Result<usize> {
+        let total = file.metadata().await?.len();
+        let regions = data_regions(file, total).await?;
+        let data = regions.iter().map(|r| r.1).sum::<u64>();
+
+        // Write the packet kind, the size of the file and how much of it is data
+        self.stream.write_u8(PacketKind::SparseFile as u8).await?;
+        self.stream.write_u64(total).await?;
+        self.stream.write_u64(data).await?;
+
+        let mut progress = Progress::new("Sent", data, false, None);
+        // Where the last region ended
+        let mut end = 0;
+        for (offset, size) in regions {
+            if let Some(hasher) = hasher.as_deref_mut() {
+                hasher.update_zeros(offset - end);
+            }
+            self.stream.write_u64(offset).await?;
+            self.stream.write_u64(size).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+            self.send_from(file, size, &mut progress, hasher.as_deref_mut()).await?;
+            end = offset + size;
+        }
+        if let Some(hasher) = hasher {
+            hasher.update_zeros(total - end);
+        }
+
+        // Terminate with an empty region
+        self.stream.write_u64(total).await?;
+        self.stream.write_u64(0).await?;
+
+        Ok(data as usize)
+    }
+
+    /// Write [file] as a [PacketKind::Delta] against the node's [signatures], sending only blocks it doesn't have
+    /// Returns how many bytes were sent literally and how many blocks were reused
+    /// The whole file is added to [hasher], if there is one
+    /// This assumes [file] to be locked, or not to be changed during sending
+    pub async fn send_delta(&mut self, file: &mut File, signatures: &Signatures, mut hasher: Option<&mut StreamHasher>) -> Result<(u64, u64)> {
+        let block_size = signatures.block_size as usize;
+
+        self.stream.write_u8(PacketKind::Delta as u8).await?;
+        self.stream.write_u64(file.metadata().await?.len()).await?;
+        file.seek(SeekFrom::Start(0)).await?;
+
+        // Everything read but not sent yet, starting with the pending literal bytes
+        let mut data = Vec::new();
+        // Where the current window starts in [data]
+        let mut pos = 0;
+        let mut rolling: Option<Rolling> = None;
+        let mut eof = false;
+        let mut buf = vec![0; buffer_size()];
+
+        let mut literal = 0;
+        let mut reused = 0;
         loop {
-            // Read from file
-            let s = file.read(buf.as_mut_slice()).await?;
+            // Keep the window and the byte after it in memory
+            while !eof && data.len() < pos + block_size + 1 {
+                let s = file.read(&mut buf).await?;
+                if s == 0 {
+                    eof = true;
+                }
+                if let Some(hasher) = hasher.as_deref_mut() {
+                    hasher.update(&buf[..s]);
+                }
+                data.extend_from_slice(&buf[..s]);
+            }
+            if data.len() < pos + block_size {
+                break;
+            }
 
-            if s == 0 {
+            let window = &data[pos..pos + block_size];
+            let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+            if let Some(index) = signatures.find(weak, window) {
+                self.write_delta(&data[..pos], index + 1).await?;
+                literal += pos as u64;
+                reused += 1;
+                data.drain(..pos + block_size);
+                pos = 0;
+                rolling = None;
+                continue;
+            }
+
+            if data.len() == pos + block_size {
                 break;
             }
+            rolling.as_mut().unwrap().roll(data[pos], data[pos + block_size]);
+            pos += 1;
+
+            // Don't let unmatched data pile up
+            if pos >= 0x10000 {
+                self.write_delta(&data[..pos], 0).await?;
+                literal += pos as u64;
+                data.drain(..pos);
+                pos = 0;
+            }
+        }
+
+        // Whatever is left didn't match any block
+        if !data.is_empty() {
+            self.write_delta(&data, 0).await?;
+            literal += data.len() as u64;
+        }
+        self.write_delta(&[], 0).await?;
+
+        Ok((literal, reused))
+    }
+
+    /// Write a single delta instruction, [literal] bytes followed by the one-based index of a block to copy
+    async fn write_delta(&mut self, literal: &[u8], copy: u64) -> Result<()> {
+        self.stream.write_u64(literal.len() as u64).await?;
+        self.stream.write_all(literal).await?;
+        metrics::bytes_sent(literal.len() as u64);
+        self.stream.write_u64(copy).await?;
+        Ok(())
+    }
+
+    /// Copy exactly [size] bytes from [file] to the remote host
+    async fn send_from(&mut self, file: &mut File, size: u64, progress: &mut Progress, mut hasher: Option<&mut StreamHasher>) -> Result<()> {
+        let mut buf = vec![0; buffer_size()];
+        let mut left = size;
+        while left > 0 {
+            // Read from file, but never more than announced
+            let to_read = left.min(buf.len() as u64) as usize;
+            let s = file.read(&mut buf.as_mut_slice()[0..to_read]).await?;
+
+            if s == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "file shrunk during transfer"));
+            }
 
+            if let Some(hasher) = hasher.as_deref_mut() {
+                hasher.update(&buf.as_slice()[0..s]);
+            }
             // Write to remote host
             self.stream.write_all(&buf.as_slice()[0..s]).await?;
+            metrics::bytes_sent(s as u64);
+            progress.inc(s as u64);
+            left -= s as u64;
         }
 
-        Ok(size as usize)
+        Ok(())
     }
 
     /// Close the connection (from the nodes perspective)
@@ -157,8 +813,227 @@ impl Client {
         Ok(())
     }
 
-    /// Returns the local address that this stream is bound to.
+    /// Returns the remote address that this stream is connected to.
     pub fn peer_addr(&self) -> SocketAddr {
-        self.stream.peer_addr().unwrap()
+        self.peer
+    }
+}
+
+
+/// Find the (offset, length) pairs of data in a file of size [len], skipping holes
+#[cfg(target_os = "linux")]
+async fn data_regions(file: &File, len: u64) -> Result<Vec<(u64, u64)>> {
+    use std::os::unix::io::AsRawFd;
+
+    let copy = file.try_clone().await?.into_std().await;
+    match tokio::task::spawn_blocking(move || {
+        let fd = copy.as_raw_fd();
+        let mut regions = Vec::new();
+        let mut pos = 0;
+        while pos < len {
+            // Find the next data region, ENXIO means there is none left
+            let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+            if data < 0 {
+                let err = Error::last_os_error();
+                if err.raw_os_error() == Some(libc::ENXIO) {
+                    break;
+                }
+                return Err(err);
+            }
+            // Every file has an implicit hole at its end
+            let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+            if hole < 0 {
+                return Err(Error::last_os_error());
+            }
+            regions.push((data as u64, (hole - data) as u64));
+            pos = hole as u64;
+        }
+        Ok(regions)
+    }).await {
+        Ok(res) => res,
+        Err(_) => Err(Error::new(
+            ErrorKind::Other,
+            "background task failed",
+        )),
+    }
+}
+
+/// Without SEEK_DATA/SEEK_HOLE, treat the whole file as data
+#[cfg(not(target_os = "linux"))]
+async fn data_regions(_file: &File, len: u64) -> Result<Vec<(u64, u64)>> {
+    Ok(vec![(0, len)])
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::{ErrorKind, SeekFrom};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+    use tokio::fs::{File, OpenOptions};
+    use tokio::io::{AsyncSeekExt, AsyncWriteExt, duplex};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use crate::delta::{block_checksums, Signatures};
+    use crate::packet::PacketKind;
+    use crate::util::is_sparse;
+    use crate::util::tests::TestDir;
+    use super::{interrupted, Interrupted, MAX_INTERRUPTED, remember_interrupted, sniff, Client, Sniffed};
+
+    /// Two clients talking to each other in memory
+    pub fn connected() -> (Client, Client) {
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let (near, far) = duplex(0x10000);
+        (Client::from_connection(Box::new(near), peer), Client::from_connection(Box::new(far), peer))
+    }
+
+    /// A file in [dir] called [name], with [contents]
+    fn scratch(dir: &Path, name: &str, contents: &[u8]) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    async fn open_rw(path: &PathBuf) -> File {
+        OpenOptions::new().read(true).write(true).open(path).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn delta_roundtrips_with_inserted_and_removed_bytes() {
+        const BLOCK_SIZE: u32 = 2048;
+        let mut rng = StdRng::seed_from_u64(0x64656c74);
+        let old: Vec<u8> = (0..200_000).map(|_| rng.gen()).collect();
+        let mut new = old.clone();
+        new.splice(5000..5000, (0..300).map(|_| rng.gen::<u8>()));
+        new.drain(100_000..100_777);
+        new[150_000] ^= 0xFF;
+        new.extend((0..1000).map(|_| rng.gen::<u8>()));
+
+        let dir = TestDir::new("socket-delta");
+        let basis_path = scratch(&dir, "basis", &old);
+        let new_path = scratch(&dir, "new", &new);
+        let out_path = scratch(&dir, "out", &[]);
+
+        let mut basis = open_rw(&basis_path).await;
+        let signatures = Signatures::parse(BLOCK_SIZE, &block_checksums(&mut basis, BLOCK_SIZE).await.unwrap()).unwrap();
+
+        let (mut sender, mut receiver) = connected();
+        let send = async {
+            let mut file = File::open(&new_path).await.unwrap();
+            sender.send_delta(&mut file, &signatures, None).await.unwrap()
+        };
+        let receive = async {
+            let out = open_rw(&out_path).await;
+            receiver.expect_delta(&mut basis, out, BLOCK_SIZE, "delta").await.unwrap()
+        };
+        let ((literal, reused), received) = tokio::join!(send, receive);
+
+        assert_eq!(std::fs::read(&out_path).unwrap(), new);
+        assert_eq!(literal, received as u64);
+        // Only the blocks around the edits have to be sent
+        assert!(literal < 6 * BLOCK_SIZE as u64 + 1000, "sent {} bytes literally", literal);
+        assert!(reused > 90);
+    }
+
+    #[tokio::test]
+    async fn delta_rejects_blocks_outside_of_the_file() {
+        let dir = TestDir::new("socket-delta-outside");
+        let basis_path = scratch(&dir, "basis", &[0; 4096]);
+        let out_path = scratch(&dir, "out", &[]);
+        let mut basis = open_rw(&basis_path).await;
+
+        for copy in [3, u64::MAX] {
+            let (mut sender, mut receiver) = connected();
+            let mut raw = vec![PacketKind::Delta as u8];
+            raw.extend_from_slice(&4096u64.to_be_bytes());
+            raw.extend_from_slice(&0u64.to_be_bytes());
+            raw.extend_from_slice(&copy.to_be_bytes());
+            sender.stream.write_all(&raw).await.unwrap();
+
+            let out = open_rw(&out_path).await;
+            let err = receiver.expect_delta(&mut basis, out, 2048, "delta-outside").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn sparse_files_stay_sparse() {
+        const SIZE: u64 = 8 * 1024 * 1024;
+        let dir = TestDir::new("socket-sparse");
+        let source_path = scratch(&dir, "source", &[]);
+        let out_path = scratch(&dir, "out", &[]);
+        {
+            let mut source = open_rw(&source_path).await;
+            source.set_len(SIZE).await.unwrap();
+            source.write_all(&[1; 4096]).await.unwrap();
+            source.seek(SeekFrom::Start(SIZE / 2)).await.unwrap();
+            source.write_all(&[2; 4096]).await.unwrap();
+            source.flush().await.unwrap();
+        }
+        let mut source = File::open(&source_path).await.unwrap();
+        // Not every file system supports holes
+        if !is_sparse(&source).await.unwrap() {
+            return;
+        }
+
+        let (mut sender, mut receiver) = connected();
+        let send = sender.send_sparse_file(&mut source, None);
+        let receive = async {
+            let out = open_rw(&out_path).await;
+            receiver.expect_file(out, "sparse").await.unwrap()
+        };
+        let (sent, received) = tokio::join!(send, receive);
+        assert_eq!(sent.unwrap(), received);
+        assert!((received as u64) < SIZE);
+
+        assert_eq!(std::fs::read(&out_path).unwrap(), std::fs::read(&source_path).unwrap());
+        assert!(is_sparse(&File::open(&out_path).await.unwrap()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn sparse_regions_outside_of_the_file_fail() {
+        let dir = TestDir::new("socket-sparse-outside");
+        let out_path = scratch(&dir, "out", &[]);
+        for (offset, size) in [(4000, 200), (u64::MAX, 2)] {
+            let (mut sender, mut receiver) = connected();
+            let mut raw = vec![PacketKind::SparseFile as u8];
+            for field in [4096u64, size, offset, size] {
+                raw.extend_from_slice(&field.to_be_bytes());
+            }
+            sender.stream.write_all(&raw).await.unwrap();
+
+            let out = open_rw(&out_path).await;
+            let err = receiver.expect_file(out, "sparse-outside").await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+    }
+
+    #[tokio::test]
+    async fn methods_split_across_packets_are_sniffed() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let writer = async move {
+            client.write_all(b"GE").await.unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(b"T / HTTP/1.1\r\n").await.unwrap();
+            client
+        };
+        let (sniffed, _client) = tokio::join!(tokio::time::timeout(Duration::from_secs(5), sniff(&socket)), writer);
+        assert!(matches!(sniffed.unwrap().unwrap(), Sniffed::Http));
+    }
+
+    #[test]
+    fn interrupted_transfers_are_bounded() {
+        for i in 0..MAX_INTERRUPTED + 16 {
+            remember_interrupted(format!("bounded-{}", i), Interrupted { attempts: 1, rate: None, at: Instant::now() });
+        }
+        let interrupted = interrupted().lock().unwrap();
+        assert!(interrupted.len() <= MAX_INTERRUPTED);
+        assert!(interrupted.contains_key(&format!("bounded-{}", MAX_INTERRUPTED + 15)), "the newest transfer was forgotten");
     }
 }
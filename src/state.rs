@@ -0,0 +1,144 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Result};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+use tokio::fs;
+use toml::Value;
+use toml::value::Table;
+
+use crate::util::mirra_dir;
+
+const STATE_FILE: &str = "state.toml";
+
+/// When a synced module was last fully synced and last changed, in seconds since the epoch
+#[derive(Clone, Copy, Default)]
+pub struct ModuleState {
+    pub last_sync: Option<u64>,
+    pub last_change: Option<u64>,
+}
+
+impl ModuleState {
+    /// How long ago the module was last known to be up to date, if it ever was
+    pub fn age(&self) -> Option<Duration> {
+        let updated = self.last_sync.max(self.last_change)?;
+        Some(Duration::from_secs(now().saturating_sub(updated)))
+    }
+}
+
+struct State {
+    modules: HashMap<String, ModuleState>,
+    /// Whether anything changed since the state was last stored
+    changed: bool,
+}
+
+fn state_path() -> PathBuf {
+    mirra_dir().join(STATE_FILE)
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// The state of every module, loaded from `.mirra/state.toml` the first time it's needed
+fn state() -> &'static Mutex<State> {
+    static STATE: OnceLock<Mutex<State>> = OnceLock::new();
+    STATE.get_or_init(|| {
+        let modules = match load_state() {
+            Ok(modules) => modules,
+            Err(e) => {
+                warn!("Failed to load {}, starting without it: {}", state_path().display(), e);
+                HashMap::new()
+            }
+        };
+        Mutex::new(State { modules, changed: false })
+    })
+}
+
+fn load_state() -> Result<HashMap<String, ModuleState>> {
+    let content = match std::fs::read_to_string(state_path()) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e),
+    };
+    let table = match content.parse::<Value>().map_err(|e| Error::new(ErrorKind::InvalidData, e))? {
+        Value::Table(table) => table,
+        _ => return Err(Error::new(ErrorKind::InvalidData, "expected a table")),
+    };
+
+    let time = |module: &Value, key: &str| module.get(key).and_then(Value::as_integer).map(|t| t.max(0) as u64);
+    Ok(table.iter()
+        .map(|(name, module)| (name.clone(), ModuleState {
+            last_sync: time(module, "last_sync"),
+            last_change: time(module, "last_change"),
+        }))
+        .collect())
+}
+
+/// The state of [module], empty if nothing was recorded for it yet
+pub fn get(module: &str) -> ModuleState {
+    state().lock().unwrap().modules.get(module).copied().unwrap_or_default()
+}
+
+/// Note that a full sync of [module] just finished
+pub fn synced(module: &str) {
+    let mut state = state().lock().unwrap();
+    state.modules.entry(module.to_string()).or_default().last_sync = Some(now());
+    state.changed = true;
+}
+
+/// Note that a change to [module] was just received
+pub fn changed(module: &str) {
+    let mut state = state().lock().unwrap();
+    state.modules.entry(module.to_string()).or_default().last_change = Some(now());
+    state.changed = true;
+}
+
+/// Store the state of every module, if it changed since the last time
+/// Failing to store it isn't fatal, it's tried again the next time
+pub async fn save() {
+    // Every synced module saves on its own, so they could otherwise write the temporary file at once
+    static SAVING: OnceLock<tokio::sync::Mutex<()>> = OnceLock::new();
+    let _saving = SAVING.get_or_init(|| tokio::sync::Mutex::new(())).lock().await;
+
+    let content = {
+        let mut state = state().lock().unwrap();
+        if !state.changed {
+            return;
+        }
+        state.changed = false;
+
+        let mut table = Table::new();
+        for (name, module) in &state.modules {
+            let mut entry = Table::new();
+            if let Some(last_sync) = module.last_sync {
+                entry.insert("last_sync".to_string(), Value::Integer(last_sync as i64));
+            }
+            if let Some(last_change) = module.last_change {
+                entry.insert("last_change".to_string(), Value::Integer(last_change as i64));
+            }
+            table.insert(name.clone(), Value::Table(entry));
+        }
+        Value::Table(table).to_string()
+    };
+
+    // Write it next to the old one first, so it's never left half-written
+    let path = state_path();
+    let temp = path.with_extension("tmp");
+    let res = match fs::write(&temp, content).await {
+        Ok(()) => fs::rename(temp, path).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = res {
+        warn!("Failed to store {}: {}", STATE_FILE, e);
+        state().lock().unwrap().changed = true;
+    }
+}
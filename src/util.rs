@@ -4,17 +4,28 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
-use std::fmt::Debug;
+use std::collections::HashMap;
+use std::env;
+use std::fmt::{Debug, Display};
+use std::future::Future;
 use std::io::{Error, ErrorKind, Result, SeekFrom, Write};
-use std::path::Path;
+use std::net::Ipv6Addr;
+use std::path::{Component, Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use blake3::Hasher;
 use async_trait::async_trait;
 use dialoguer::Input;
+use log::{LevelFilter, warn};
+use sha2::{Digest, Sha256};
 use fs4::tokio::AsyncFileExt;
-use tokio::fs::File;
+use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio::net::lookup_host;
+use tokio::sync::watch;
 
 /// Gets an input of type [T] with a prompt
 pub fn simple_input<S: Into<String>, T>(prompt: S) -> Result<T>
@@ -37,6 +48,101 @@ pub fn simple_input_default<S: Into<String>, T>(prompt: S, default: T) -> Result
         .interact_text()
 }
 
+/// Expand a leading `~` and any `$VAR` or `${VAR}` in [path], failing on undefined variables
+pub fn expand_path(path: &str) -> Result<String> {
+    let lookup = |name: &str| env::var(name)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("{} is not set, but used in {}", name, path)));
+
+    // Only `~` and `~/...` refer to the home directory, `~user` is left alone
+    let mut res = String::with_capacity(path.len());
+    let mut rest = path;
+    if rest == "~" || rest.starts_with("~/") {
+        res.push_str(&lookup("HOME")?);
+        rest = &rest[1..];
+    }
+
+    let mut chars = rest.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            res.push(c);
+            continue;
+        }
+
+        let mut name = String::new();
+        if chars.peek() == Some(&'{') {
+            chars.next();
+            for c in chars.by_ref() {
+                if c == '}' {
+                    break;
+                }
+                name.push(c);
+            }
+        } else {
+            while let Some(c) = chars.next_if(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                name.push(c);
+            }
+        }
+
+        // A lone `$` stays as it is
+        if name.is_empty() {
+            res.push('$');
+        } else {
+            res.push_str(&lookup(&name)?);
+        }
+    }
+
+    Ok(res)
+}
+
+/// Whether [path] contains `*` or `?`, so it has to be expanded
+pub fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?'])
+}
+
+/// Whether [name] matches [pattern], where `*` matches any number of characters and `?` a single one
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..])),
+        (Some('?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Find every directory [pattern] matches, wildcards may be used in any component
+/// Hidden directories are only matched if the component starts with a dot as well
+pub fn expand_glob(pattern: &str) -> Vec<PathBuf> {
+    let mut matches = vec![PathBuf::new()];
+    for component in Path::new(pattern).components() {
+        let part = component.as_os_str().to_string_lossy();
+        if !is_glob(&part) {
+            matches = matches.into_iter().map(|m| m.join(component)).collect();
+            continue;
+        }
+
+        let part: Vec<char> = part.chars().collect();
+        let mut next = Vec::new();
+        for dir in matches {
+            let entries = match std::fs::read_dir(if dir.as_os_str().is_empty() { Path::new(".") } else { &dir }) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().chars().collect::<Vec<_>>();
+                if (name.first() != Some(&'.') || part.first() == Some(&'.')) && glob_match(&part, &name) {
+                    next.push(dir.join(entry.file_name()));
+                }
+            }
+        }
+        matches = next;
+    }
+
+    matches.retain(|m| m.is_dir());
+    matches.sort();
+    matches
+}
+
 /// Returns a path as an optional string
 pub fn stringify(path: impl AsRef<Path>) -> Result<String> {
     let str = path.as_ref().to_str();
@@ -46,24 +152,343 @@ pub fn stringify(path: impl AsRef<Path>) -> Result<String> {
     Ok(str.unwrap().to_string())
 }
 
-/// Returns the hash of a files contents
-pub async fn hash_file(file: &mut File) -> Result<String> {
-    let mut buf = vec![0; 0x1000];
-    let mut hasher = Hasher::new();
+/// Turn a path relative to a module into the form it's sent in, with `/` separators on every platform
+pub fn wire_path(path: impl AsRef<Path>) -> Result<String> {
+    let mut res = String::new();
+    for component in path.as_ref().components() {
+        if !res.is_empty() {
+            res.push('/');
+        }
+        res.push_str(stringify(component)?.as_str());
+    }
+    Ok(res)
+}
+
+/// Turn a path received from a remote mirra into one with the local separators
+/// Fails unless every part of it is a plain name, so it can't point outside of the module it's joined to
+pub fn local_path(path: &str) -> Result<PathBuf> {
+    let outside = || Error::new(ErrorKind::InvalidData, format!("{} isn't inside of the module", path));
+    if path.starts_with('/') {
+        return Err(outside());
+    }
+    let mut res = PathBuf::new();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        // Each segment has to be a single name here too, `a\b` is two of them on Windows
+        let mut components = Path::new(segment).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) if name == segment => res.push(name),
+            _ => return Err(outside()),
+        }
+    }
+    if res.as_os_str().is_empty() {
+        return Err(outside());
+    }
+    Ok(res)
+}
+
+/// Algorithms files can be hashed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashAlgo {
+    Blake3,
+    Sha256,
+}
+
+impl HashAlgo {
+    /// The name used in config files and as the prefix of hashes
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgo::Blake3 => "blake3",
+            HashAlgo::Sha256 => "sha256",
+        }
+    }
+
+    /// Get the algorithm an `algo:hex` hash was made with
+    pub fn of(hash: &str) -> Result<HashAlgo> {
+        let name = hash.split_once(':').map(|(name, _)| name).unwrap_or("");
+        name.parse()
+    }
+}
+
+impl FromStr for HashAlgo {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "blake3" => Ok(HashAlgo::Blake3),
+            "sha256" => Ok(HashAlgo::Sha256),
+            _ => Err(Error::new(ErrorKind::InvalidData, format!("unsupported hash algorithm '{}'", s))),
+        }
+    }
+}
+
+/// Hashes data piece by piece, e.g. while it's being sent
+pub struct StreamHasher {
+    algo: HashAlgo,
+    blake3: Hasher,
+    sha256: Sha256,
+}
+
+impl StreamHasher {
+    pub fn new(algo: HashAlgo) -> Self {
+        Self {
+            algo,
+            blake3: Hasher::new(),
+            sha256: Sha256::new(),
+        }
+    }
+
+    /// Add the next piece of [data]
+    pub fn update(&mut self, data: &[u8]) {
+        match self.algo {
+            HashAlgo::Blake3 => { self.blake3.update(data); }
+            HashAlgo::Sha256 => self.sha256.update(data),
+        }
+    }
+
+    /// Add [len] zero bytes, like the holes of sparse files
+    pub fn update_zeros(&mut self, mut len: u64) {
+        let zeros = [0; 0x1000];
+        while len > 0 {
+            let n = len.min(zeros.len() as u64);
+            self.update(&zeros[..n as usize]);
+            len -= n;
+        }
+    }
+
+    /// Returns the hash of everything added as `algo:hex`
+    pub fn finish(self) -> String {
+        let hex = match self.algo {
+            HashAlgo::Blake3 => self.blake3.finalize().to_string(),
+            HashAlgo::Sha256 => format!("{:x}", self.sha256.finalize()),
+        };
+        format!("{}:{}", self.algo.name(), hex)
+    }
+}
+
+/// Buffer size used unless `buffer_size` is set
+pub const DEFAULT_BUFFER_SIZE: usize = 0x10000;
+/// Smallest and largest `buffer_size` allowed
+pub const MIN_BUFFER_SIZE: usize = 0x1000;
+pub const MAX_BUFFER_SIZE: usize = 0x1000000;
+
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_BUFFER_SIZE);
+
+/// Read and write files in chunks of [size] bytes from now on
+pub fn set_buffer_size(size: usize) {
+    BUFFER_SIZE.store(size.clamp(MIN_BUFFER_SIZE, MAX_BUFFER_SIZE), Ordering::Relaxed);
+}
+
+/// How many bytes files are read and written in at once, when they are hashed or transferred
+pub fn buffer_size() -> usize {
+    BUFFER_SIZE.load(Ordering::Relaxed)
+}
+
+/// Returns the hash of a files contents as `algo:hex`
+pub async fn hash_file(file: &mut File, algo: HashAlgo) -> Result<String> {
+    let mut buf = vec![0; buffer_size()];
+    let mut hasher = StreamHasher::new(algo);
     loop {
         let s = file.read(buf.as_mut_slice()).await?;
         if s == 0 {
             break;
         }
-
-        hasher.write(&buf.as_slice()[0..s])?;
+        hasher.update(&buf.as_slice()[0..s]);
     }
     // Seek back to start to make file usable again
     // Doesn't have to save state before, because its only
     // ever called directly after opening a file
     file.seek(SeekFrom::Start(0)).await?;
 
-    Ok(hasher.finalize().to_string())
+    Ok(hasher.finish())
+}
+
+/// Files modified this recently may still change without their mtime changing, so they aren't cached
+const RACY_WINDOW: Duration = Duration::from_secs(2);
+
+/// How many hashes are cached, so the cache can't grow with every file ever hashed
+const MAX_CACHED_HASHES: usize = 0x40000;
+
+/// The hash of a file, along with the mtime and size it had when it was hashed
+type CachedHash = (SystemTime, u64, String);
+
+/// Hashes of every file hashed through [cached_hash_file]
+fn hash_cache() -> &'static Mutex<HashMap<(PathBuf, HashAlgo), CachedHash>> {
+    static CACHE: OnceLock<Mutex<HashMap<(PathBuf, HashAlgo), CachedHash>>> = OnceLock::new();
+    CACHE.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The hash [path] had when it was last hashed with [algo], if it hasn't been modified or resized since
+/// [metadata] is what [path] looks like now
+pub fn cached_hash(path: &Path, metadata: &std::fs::Metadata, algo: HashAlgo) -> Option<String> {
+    let modified = metadata.modified().ok()?;
+    match hash_cache().lock().unwrap().get(&(path.to_path_buf(), algo)) {
+        Some((cached_modified, cached_size, hash)) if *cached_modified == modified && *cached_size == metadata.len() => Some(hash.clone()),
+        _ => None,
+    }
+}
+
+/// Remember [hash] for [path] until it changes, [metadata] is what [path] looked like when it was hashed
+pub fn remember_hash(path: &Path, metadata: &std::fs::Metadata, algo: HashAlgo, hash: &str) {
+    let key = (path.to_path_buf(), algo);
+    let mut cache = hash_cache().lock().unwrap();
+    // Without an mtime there is no way to tell whether the file changed
+    let modified = match metadata.modified() {
+        Ok(modified) => modified,
+        Err(_) => {
+            cache.remove(&key);
+            return;
+        }
+    };
+    let settled = SystemTime::now().duration_since(modified).map_or(false, |age| age >= RACY_WINDOW);
+    if settled {
+        // An evicted file is only hashed again the next time it's needed
+        if cache.len() >= MAX_CACHED_HASHES && !cache.contains_key(&key) {
+            if let Some(evicted) = cache.keys().next().cloned() {
+                cache.remove(&evicted);
+            }
+        }
+        cache.insert(key, (modified, metadata.len(), hash.to_string()));
+    } else {
+        cache.remove(&key);
+    }
+}
+
+/// Drop every cached hash of [path], because it doesn't exist anymore
+pub fn forget_hash(path: &Path) {
+    let mut cache = hash_cache().lock().unwrap();
+    for algo in [HashAlgo::Blake3, HashAlgo::Sha256] {
+        cache.remove(&(path.to_path_buf(), algo));
+    }
+}
+
+/// Like [hash_file], but reuses the last hash of [path] if its mtime and size haven't changed since
+pub async fn cached_hash_file(path: &Path, file: &mut File, algo: HashAlgo) -> Result<String> {
+    let metadata = file.metadata().await?;
+    if let Some(hash) = cached_hash(path, &metadata, algo) {
+        return Ok(hash);
+    }
+
+    let hash = hash_file(file, algo).await?;
+    remember_hash(path, &metadata, algo, &hash);
+    Ok(hash)
+}
+
+/// Paces a transfer to a fixed number of bytes per second
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Allow [rate] bytes per second, with bursts of up to one second worth of bytes
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            last: Instant::now(),
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Account for [amount] transferred bytes, waiting until the transfer is back within the rate
+    pub async fn take(&mut self, amount: u64) {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + refill).min(self.rate as f64) - amount as f64;
+        self.last = now;
+
+        // Tokens can go into debt, which has to be waited off
+        if self.tokens < 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(-self.tokens / self.rate as f64)).await;
+        }
+    }
+}
+
+tokio::task_local! {
+    /// The connection the current task handles, as it appears in log lines
+    static CONNECTION: String;
+}
+
+/// How often reading a file is attempted before giving up on it
+const READ_ATTEMPTS: usize = 3;
+/// How long to wait before reading a file again after it failed
+const READ_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Run [read] on the file at [what] again if it fails, files can be unreadable for a moment
+/// Files that don't exist (anymore) aren't tried again
+pub async fn retry_read<T, F, Fut>(what: &str, mut read: F) -> Result<T>
+    where F: FnMut() -> Fut, Fut: Future<Output = Result<T>> {
+    let mut attempt = 1;
+    loop {
+        match read().await {
+            Err(e) if attempt < READ_ATTEMPTS && e.kind() != ErrorKind::NotFound => {
+                warn!("Failed to read {}: {}, trying again", what, e);
+                attempt += 1;
+                tokio::time::sleep(READ_RETRY_DELAY).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Run [task], tagging every line it logs with a new connection id and [peer]
+pub async fn with_connection<F: Future>(peer: impl Display, task: F) -> F::Output {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    CONNECTION.scope(format!("#{} {}", id, peer), task).await
+}
+
+/// Log like env_logger does by default, adding the connection a line belongs to
+/// Logs at [level] if it is set, and according to RUST_LOG otherwise, which defaults to info
+pub fn init_logging(level: Option<LevelFilter>) {
+    let mut builder = match level {
+        Some(level) => {
+            let mut builder = env_logger::Builder::new();
+            builder.filter_level(level);
+            builder
+        }
+        None => env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")),
+    };
+    builder
+        .format(|buf, record| {
+            let connection = CONNECTION.try_with(|connection| format!("[{}] ", connection)).unwrap_or_default();
+            writeln!(buf, "[{} {:<5} {}] {}{}", buf.timestamp(), buf.default_styled_level(record.level()),
+                     record.target(), connection, record.args())
+        })
+        .init();
+}
+
+/// Receiving end of the shutdown signal, which is set to true once mirra should stop
+pub type Shutdown = watch::Receiver<bool>;
+
+/// Wait until a shutdown was requested
+pub async fn shutdown_requested(shutdown: &mut Shutdown) {
+    while !*shutdown.borrow() {
+        if shutdown.changed().await.is_err() {
+            // Nobody can request a shutdown anymore
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Check whether a file has holes, i.e. takes up less space on disk than its size
+pub async fn is_sparse(file: &File) -> Result<bool> {
+    let metadata = file.metadata().await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // Block counts are always in units of 512 bytes
+        Ok(metadata.blocks() * 512 < metadata.len())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        Ok(false)
+    }
 }
 
 /// Convenience trait for locking and unlocking a file asynchronously
@@ -71,6 +496,10 @@ pub async fn hash_file(file: &mut File) -> Result<String> {
 pub trait AsyncFileLock {
     /// Lock a file
     async fn lock(&self) -> Result<()>;
+    /// Lock a file, allowing other shared locks at the same time
+    async fn lock_shared(&self) -> Result<()>;
+    /// Lock a file like [lock_shared] without waiting, returning false if it is locked exclusively
+    fn try_lock_shared(&self) -> Result<bool>;
     /// Unlock a file
     async fn unlock(&self) -> Result<()>;
 }
@@ -91,6 +520,26 @@ impl AsyncFileLock for File {
         }
     }
 
+    async fn lock_shared(&self) -> Result<()> {
+        // Local copy for the thread
+        let copy = self.try_clone().await?;
+        match tokio::task::spawn_blocking(move || AsyncFileExt::lock_shared(&copy)).await {
+            Ok(res) => res,
+            Err(_) => Err(Error::new(
+                ErrorKind::Other,
+                "background task failed",
+            )),
+        }
+    }
+
+    fn try_lock_shared(&self) -> Result<bool> {
+        match AsyncFileExt::try_lock_shared(self) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == fs4::lock_contended_error().kind() => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
     async fn unlock(&self) -> Result<()> {
         // Local copy for the thread
         let copy = self.try_clone().await?;
@@ -104,6 +553,33 @@ impl AsyncFileLock for File {
     }
 }
 
+/// Where Mirra.toml, the keys and everything else mirra keeps are, `.mirra` unless `--config-dir` says otherwise
+static MIRRA_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Keep everything in [dir] instead of `.mirra`, has to happen before anything is loaded
+pub fn set_mirra_dir(dir: PathBuf) {
+    let _ = MIRRA_DIR.set(dir);
+}
+
+/// The directory Mirra.toml, the keys and everything else mirra keeps are in
+pub fn mirra_dir() -> &'static Path {
+    MIRRA_DIR.get_or_init(|| PathBuf::from(".mirra"))
+}
+
+/// Open a module's advisory lock file, which external tools can lock exclusively to pause syncing
+pub async fn open_module_lock(module: &str) -> Result<File> {
+    let locks = mirra_dir().join("locks");
+    if !locks.exists() {
+        tokio::fs::create_dir_all(&locks).await?;
+    }
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(locks.join(module)).await
+}
+
 pub fn format_size(size: u64) -> String {
     if size < 1024 {
         size.to_string() + "B"
@@ -120,22 +596,226 @@ pub fn format_size(size: u64) -> String {
     }
 }
 
+/// Format [duration] as hours, minutes and seconds, leaving out leading zeros
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs / 60 % 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// Split [time] into its UTC year, month, day and the seconds since midnight
+fn civil_time(time: SystemTime) -> (i64, i64, i64, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since the epoch, after Howard Hinnant's days_from_civil inverse
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, rem)
+}
+
+/// Format [time] as an RFC 3339 timestamp in UTC, like `2022-04-01T12:30:00Z`
+pub fn format_time(time: SystemTime) -> String {
+    let (year, month, day, rem) = civil_time(time);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
+/// Format [time] the way access logs do, like `01/Apr/2022:12:30:00 +0000`
+pub fn format_log_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (year, month, day, rem) = civil_time(time);
+    format!("{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000", day, MONTHS[month as usize - 1], year, rem / 3600, rem % 3600 / 60, rem % 60)
+}
+
 pub struct MirraAddress {
     pub address: String,
     pub port: u16
 }
 
-pub fn parse_address(addr: String) -> MirraAddress {
-    if !addr.contains(":") {
-        MirraAddress {
-            address: addr,
-            port: 6007
+/// Parse `host`, `host:port`, `[v6]`, `[v6]:port` or a bare IPv6 literal, defaulting to port 6007
+/// Hostnames have to resolve, but are kept as they are, so they're looked up again on every connect
+pub async fn parse_address(addr: &str) -> Result<MirraAddress> {
+    let invalid = |what: String| Error::new(ErrorKind::InvalidInput, format!("invalid address {}: {}", addr, what));
+
+    let (address, port) = if let Some(bracketed) = addr.strip_prefix('[') {
+        // Bracketed IPv6 literal
+        let (address, rest) = bracketed.split_once(']').ok_or_else(|| invalid("missing ']'".to_string()))?;
+        if address.parse::<Ipv6Addr>().is_err() {
+            return Err(invalid(format!("{} is not an IPv6 address", address)));
         }
+        let port = match rest {
+            "" => None,
+            _ => Some(rest.strip_prefix(':').ok_or_else(|| invalid(format!("expected ':' after ']', found {}", rest)))?),
+        };
+        (address, port)
+    } else if addr.parse::<Ipv6Addr>().is_ok() {
+        (addr, None)
     } else {
-        let mut split = addr.split(":");
-        MirraAddress {
-            address: split.next().unwrap().to_string(),
-            port: split.next().unwrap().parse().unwrap()
+        match addr.split_once(':') {
+            Some((_, port)) if port.contains(':') => {
+                return Err(invalid("IPv6 addresses with a port have to be in brackets, like [::1]:6007".to_string()));
+            }
+            Some((host, port)) => (host, Some(port)),
+            None => (addr, None),
         }
+    };
+    if address.is_empty() {
+        return Err(invalid("missing host".to_string()));
+    }
+    let port = match port {
+        Some(port) => port.parse().ok().filter(|port| *port != 0)
+            .ok_or_else(|| invalid(format!("{} is not a valid port", port)))?,
+        None => 6007,
+    };
+
+    match lookup_host(format_address(address, port)).await.map(|mut addrs| addrs.next().is_some()) {
+        Ok(true) => Ok(MirraAddress { address: address.to_string(), port }),
+        Ok(false) => Err(Error::new(ErrorKind::NotFound, format!("{} doesn't resolve to any address", address))),
+        Err(e) => Err(Error::new(ErrorKind::NotFound, format!("can't resolve {}: {}", address, e))),
+    }
+}
+
+/// Format an address and a port so they can be connected to, putting brackets around IPv6 literals
+pub fn format_address(address: &str, port: u16) -> String {
+    if address.contains(':') {
+        format!("[{}]:{}", address, port)
+    } else {
+        format!("{}:{}", address, port)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::ops::Deref;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, SystemTime};
+
+    use super::{cached_hash, forget_hash, HashAlgo, local_path, mirra_dir, remember_hash, set_mirra_dir, wire_path};
+
+    /// Set up [mirra_dir] for tests, which all share it because it can only be set once per process
+    pub fn test_mirra_dir() -> &'static Path {
+        set_mirra_dir(std::env::temp_dir().join(format!("mirra-config-{}", std::process::id())));
+        let dir = mirra_dir();
+        assert!(dir.starts_with(std::env::temp_dir()), "mirra_dir() was used before tests set it up");
+        dir
+    }
+
+    /// An empty directory only a single test uses, removed again once it's dropped
+    pub struct TestDir(PathBuf);
+
+    impl TestDir {
+        /// Create a new directory named after [name], unique even if tests share a name
+        pub fn new(name: &str) -> Self {
+            static NEXT: AtomicU64 = AtomicU64::new(0);
+            let id = NEXT.fetch_add(1, Ordering::Relaxed);
+            let dir = std::env::temp_dir().join(format!("mirra-{}-{}-{}", name, std::process::id(), id));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+    }
+
+    impl Deref for TestDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn wire_paths_use_forward_slashes() {
+        let path: PathBuf = ["a", "b", "c.txt"].iter().collect();
+        assert_eq!(wire_path(&path).unwrap(), "a/b/c.txt");
+        assert_eq!(wire_path(PathBuf::from("a")).unwrap(), "a");
+    }
+
+    #[test]
+    fn wire_paths_roundtrip() {
+        for parts in [&["a"][..], &["a", "b"], &["a b", "c.d", "e"]] {
+            let path: PathBuf = parts.iter().collect();
+            assert_eq!(local_path(&wire_path(&path).unwrap()).unwrap(), path);
+        }
+    }
+
+    #[test]
+    fn local_path_uses_local_separators() {
+        assert_eq!(local_path("a/b").unwrap(), ["a", "b"].iter().collect::<PathBuf>());
+        assert_eq!(local_path("a/b/").unwrap(), local_path("a/b").unwrap());
+    }
+
+    #[test]
+    fn local_path_skips_empty_segments() {
+        assert_eq!(local_path("a//b").unwrap(), local_path("a/b").unwrap());
+        assert!(local_path("").is_err());
+        assert!(local_path("//").is_err());
+    }
+
+    #[test]
+    fn local_path_rejects_parent_and_current_dirs() {
+        assert!(local_path("..").is_err());
+        assert!(local_path("../a").is_err());
+        assert!(local_path("a/../../b").is_err());
+        assert!(local_path("a/./b").is_err());
+    }
+
+    #[test]
+    fn local_path_rejects_absolute_paths() {
+        assert!(local_path("/etc/passwd").is_err());
+        // Drive prefixes are only special on Windows
+        if cfg!(windows) {
+            assert!(local_path("C:/Windows").is_err());
+            assert!(local_path("C:").is_err());
+        }
+    }
+
+    #[test]
+    fn local_path_backslashes() {
+        // Backslashes are part of the name on unix, but would be separators on Windows
+        if cfg!(windows) {
+            assert!(local_path("a\\b").is_err());
+            assert!(local_path("..\\a").is_err());
+        } else {
+            assert_eq!(local_path("a\\b").unwrap(), PathBuf::from("a\\b"));
+            assert_ne!(local_path("a\\b").unwrap(), local_path("a/b").unwrap());
+            assert_eq!(local_path("..\\a").unwrap(), PathBuf::from("..\\a"));
+        }
+    }
+
+    #[test]
+    fn removed_files_are_forgotten() {
+        let dir = TestDir::new("hash-cache");
+        let file = dir.join("a");
+        std::fs::write(&file, "a").unwrap();
+        std::fs::File::options().write(true).open(&file).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60)).unwrap();
+        let metadata = std::fs::metadata(&file).unwrap();
+
+        remember_hash(&file, &metadata, HashAlgo::Blake3, "blake3:a");
+        remember_hash(&file, &metadata, HashAlgo::Sha256, "sha256:a");
+        assert_eq!(cached_hash(&file, &metadata, HashAlgo::Sha256).as_deref(), Some("sha256:a"));
+        forget_hash(&file);
+        assert_eq!(cached_hash(&file, &metadata, HashAlgo::Blake3), None);
+        assert_eq!(cached_hash(&file, &metadata, HashAlgo::Sha256), None);
     }
 }
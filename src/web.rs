@@ -4,105 +4,1400 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
 
+use std::collections::{HashMap, HashSet};
 use std::convert::Infallible;
 use std::env;
-use std::io::Result;
-use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::io::{Cursor, Result, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use blake3::Hasher;
+use httpdate::{fmt_http_date, HttpDate};
 use tokio_util::codec::{BytesCodec, FramedRead};
-use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::{Body, HeaderMap, Method, Request, Response, Server, StatusCode};
+use hyper::header::{ACCEPT_ENCODING, CONTENT_DISPOSITION, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, ETAG, HeaderName, HeaderValue, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, REFERER, RETRY_AFTER, USER_AGENT, VARY, WWW_AUTHENTICATE};
+use futures_util::{Stream, StreamExt};
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
 use hyper::service::{make_service_fn, service_fn};
 use log::warn;
+use serde::Serialize;
+use tokio::fs;
 use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, duplex, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore, watch};
+use async_compression::tokio::bufread::GzipEncoder;
+use tokio_tar::Builder;
+use mime_guess::{mime, Mime};
 
-use crate::config::Config;
-use crate::LocalKeys;
-use crate::util::format_size;
+use crate::auth::{AuthProvider, make_provider, make_share_provider};
+use crate::config::{Config, ConfigWatch, reload_failed};
+use crate::{LocalKeys, metrics, state};
+use crate::util::{AsyncFileLock, cached_hash, cached_hash_file, format_duration, format_log_time, format_size, format_time, HashAlgo, Shutdown, shutdown_requested};
 
 const STYLE: &str = include_str!("web/style.css");
 const LAYOUT: &str = include_str!("web/index.html");
+const FAVICON: &[u8] = include_bytes!("web/favicon.ico");
 
-fn make_description(name: &String, module: &Option<String>) -> String {
-    if let Some(module) = module {
+/// The page layout and stylesheet that are served
+struct Theme {
+    layout: String,
+    style: String,
+}
+
+/// Set once the web server starts
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+/// The theme loaded by [load_theme], or the embedded one
+fn theme() -> &'static Theme {
+    THEME.get_or_init(|| Theme { layout: LAYOUT.to_string(), style: STYLE.to_string() })
+}
+
+/// Find the section of [layout] that is repeated for every entry, between `$(` and `)*`
+fn repeat_section(layout: &str) -> Option<(usize, usize)> {
+    let begin = layout.find("$(")?;
+    let end = layout.find(")*")?;
+    (end > begin).then_some((begin, end))
+}
+
+/// Read the [what] at [path] if there is one, falling back to [embedded] if it can't be used
+async fn read_override(path: &Option<String>, what: &str, embedded: &str, valid: fn(&str) -> bool) -> String {
+    let path = match path {
+        Some(path) => path,
+        None => return embedded.to_string(),
+    };
+    match fs::read_to_string(path).await {
+        Ok(content) if valid(&content) => content,
+        Ok(_) => {
+            warn!("The {} {} has no $( )* section for entries, using the embedded one", what, path);
+            embedded.to_string()
+        }
+        Err(e) => {
+            warn!("Failed to read the {} {}, using the embedded one: {}", what, path, e);
+            embedded.to_string()
+        }
+    }
+}
+
+/// Load the layout and stylesheet [config] points at, the embedded ones are used for anything else
+async fn load_theme(config: &Config) -> Theme {
+    Theme {
+        layout: read_override(&config.web_template, "web template", LAYOUT, |layout| repeat_section(layout).is_some()).await,
+        style: read_override(&config.web_style, "web stylesheet", STYLE, |_| true).await,
+    }
+}
+
+const FOLDER_ICON: &str = include_str!("web/icons/folder.svg");
+const FILE_ICON: &str = include_str!("web/icons/file.svg");
+const TEXT_ICON: &str = include_str!("web/icons/text.svg");
+const IMAGE_ICON: &str = include_str!("web/icons/image.svg");
+const ARCHIVE_ICON: &str = include_str!("web/icons/archive.svg");
+
+const HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+/// Responses smaller than this aren't worth compressing
+const GZIP_THRESHOLD: u64 = 1024;
+
+/// Entries shown per listing page, unless `?per_page` says otherwise
+const DEFAULT_PER_PAGE: usize = 250;
+const MAX_PER_PAGE: usize = 5000;
+
+/// How deep and how far a module-wide search may go
+const MAX_SEARCH_DEPTH: usize = 8;
+const MAX_SEARCH_RESULTS: usize = 1000;
+
+/// How often the size and file count of every module is recomputed
+const STATS_INTERVAL: Duration = Duration::from_secs(600);
+/// How many entries are counted per module, anything bigger is shown as a lower bound
+const MAX_STATS_ENTRIES: u64 = 1_000_000;
+/// How often the gzipped copies in shares with `precompress` are brought up to date
+const PRECOMPRESS_INTERVAL: Duration = Duration::from_secs(300);
+
+/// The size and file count of a module, as of its last walk
+#[derive(Clone, Copy)]
+struct ModuleStats {
+    size: u64,
+    files: u64,
+    /// Whether the walk stopped early
+    truncated: bool,
+}
+
+impl ModuleStats {
+    /// Describe the stats like "1.5 MiB in 12 files"
+    fn describe(&self) -> String {
+        let more = if self.truncated { "+" } else { "" };
+        format!("{}{} in {}{} files", format_size(self.size), more, self.files, more)
+    }
+}
+
+/// The stats of every module listed on the web, shared by every [WebState]
+type StatsCache = Arc<Mutex<HashMap<String, ModuleStats>>>;
+
+/// Add up the files below [dirs], stopping after [MAX_STATS_ENTRIES] entries
+fn walk_module(dirs: Vec<PathBuf>) -> ModuleStats {
+    let mut stats = ModuleStats { size: 0, files: 0, truncated: false };
+    let mut seen = 0;
+    let mut stack = dirs;
+    while let Some(dir) = stack.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            seen += 1;
+            if seen > MAX_STATS_ENTRIES {
+                stats.truncated = true;
+                return stats;
+            }
+            // Symlinks aren't followed, so they can't make the walk go in circles
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => stack.push(entry.path()),
+                Ok(metadata) if metadata.is_file() => {
+                    stats.size += metadata.len();
+                    stats.files += 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    stats
+}
+
+/// Walk every module listed on the web every [STATS_INTERVAL], and whenever the config changes
+async fn refresh_stats(stats: StatsCache, mut config: ConfigWatch, mut shutdown: Shutdown) {
+    loop {
+        let current = config.borrow().clone();
+        let modules: Vec<(String, Vec<PathBuf>)> = current.shares.iter()
+            .filter(|share| share.1.web)
+            .map(|share| (share.0.clone(), share.1.paths.iter().map(PathBuf::from).collect()))
+            .chain(current.syncs.iter()
+                .filter(|sync| sync.1.web)
+                .map(|sync| (sync.0.clone(), vec![PathBuf::from(&sync.1.path)])))
+            .collect();
+
+        let walked = tokio::task::spawn_blocking(move || {
+            modules.into_iter().map(|(module, dirs)| (module, walk_module(dirs))).collect()
+        }).await;
+        if let Ok(walked) = walked {
+            *stats.lock().unwrap() = walked;
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(STATS_INTERVAL) => {}
+            changed = config.changed() => if changed.is_err() {
+                return;
+            },
+            _ = shutdown_requested(&mut shutdown) => return,
+        }
+    }
+}
+
+/// Where the gzipped copy of the file at [path] is kept
+fn gz_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".gz");
+    PathBuf::from(name)
+}
+
+/// Find the files below [dirs] that are worth compressing, but whose gzipped copy is missing or older than them
+fn stale_gz_copies(dirs: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut stale = Vec::new();
+    let mut stack = dirs;
+    while let Some(dir) = stack.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Hidden files include the copies that are still being written
+            if name.starts_with('.') || name.ends_with(".gz") {
+                continue;
+            }
+            match entry.metadata() {
+                Ok(metadata) if metadata.is_dir() => stack.push(entry.path()),
+                Ok(metadata) if metadata.is_file() && metadata.len() >= GZIP_THRESHOLD => {
+                    let path = entry.path();
+                    if !is_compressible(&mime_guess::from_path(&path).first_or_octet_stream()) {
+                        continue;
+                    }
+                    let copy = std::fs::metadata(gz_path(&path)).and_then(|copy| copy.modified());
+                    match (copy, metadata.modified()) {
+                        (Ok(copy), Ok(modified)) if copy >= modified => {}
+                        _ => stale.push(path),
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    stale
+}
+
+/// Write a gzipped copy of the file at [path] next to it
+async fn write_gz_copy(path: &Path) -> Result<()> {
+    let target = gz_path(path);
+    // Written under a hidden name first, so a half-written copy is never served
+    let temp = target.with_file_name(format!(".{}.tmp", target.file_name().unwrap().to_string_lossy()));
+    let res = async {
+        let mut encoder = GzipEncoder::new(BufReader::new(File::open(path).await?));
+        let mut copy = File::create(&temp).await?;
+        tokio::io::copy(&mut encoder, &mut copy).await?;
+        copy.flush().await
+    }.await;
+    match res {
+        Ok(()) => fs::rename(&temp, target).await,
+        Err(e) => {
+            let _ = fs::remove_file(&temp).await;
+            Err(e)
+        }
+    }
+}
+
+/// Keep gzipped copies of the compressible files in shares with `precompress`, every [PRECOMPRESS_INTERVAL] and whenever the config changes
+async fn precompress_shares(mut config: ConfigWatch, mut shutdown: Shutdown) {
+    loop {
+        let current = config.borrow().clone();
+        let dirs: Vec<PathBuf> = current.shares.values()
+            .filter(|share| share.precompress)
+            .flat_map(|share| share.paths.iter().map(PathBuf::from))
+            .collect();
+
+        if let Ok(stale) = tokio::task::spawn_blocking(move || stale_gz_copies(dirs)).await {
+            for path in stale {
+                if let Err(e) = write_gz_copy(&path).await {
+                    warn!("Failed to compress {}: {}", path.display(), e);
+                }
+            }
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(PRECOMPRESS_INTERVAL) => {}
+            changed = config.changed() => if changed.is_err() {
+                return;
+            },
+            _ = shutdown_requested(&mut shutdown) => return,
+        }
+    }
+}
+
+/// Open the gzipped copy next to the file at [path], along with its size, unless it's older than [modified]
+async fn open_gz_copy(path: &Path, modified: Option<SystemTime>) -> Option<(File, u64)> {
+    let copy = gz_path(path);
+    // Links could point outside of the module, only the file itself was checked
+    let metadata = fs::symlink_metadata(&copy).await.ok().filter(|metadata| metadata.is_file())?;
+    if metadata.modified().ok()? < modified? {
+        return None;
+    }
+    Some((File::open(copy).await.ok()?, metadata.len()))
+}
+
+/// The description [module] has in the config, if any
+fn module_description<'a>(config: &'a Config, module: &str) -> Option<&'a String> {
+    match config.shares.get(module) {
+        Some(share) => share.description.as_ref(),
+        None => config.syncs.get(module).and_then(|sync| sync.description.as_ref()),
+    }
+}
+
+fn make_description(config: &Config, module: &Option<String>) -> String {
+    let name = &config.name;
+    if let Some(description) = module.as_ref().and_then(|module| module_description(config, module)) {
+        format!("{}<br>Shared by {} via <a href=\"https://github.com/Not-Nik/mirra\">mirra</a>.", escape_html(description), name)
+    } else if let Some(module) = module {
         format!("Share {}'s {} module via <a href=\"https://github.com/Not-Nik/mirra\">mirra</a>.", name, module)
     } else {
         format!("Share any of {}'s modules via <a href=\"https://github.com/Not-Nik/mirra\">mirra</a>.", name)
     }
 }
 
-fn make_list_page(entries: Vec<(String, String, bool)>, module: Option<String>, host: Option<String>, config: Arc<Config>) -> Result<String> {
-    let repeat_begin = LAYOUT.find("$(");
-    let repeat_end = LAYOUT.find(")*");
+/// Build a breadcrumb trail with links to every parent of [path]
+fn make_breadcrumbs(path: &str, config: &Config) -> String {
+    let mut crumbs = format!("<a href=\"/\">{}</a>", config.name);
+    let mut href = "/".to_string();
+    for part in path.split('/').filter(|p| !p.is_empty()) {
+        href.push_str(part);
+        href.push('/');
+        crumbs.push_str(&format!(" / <a href=\"{}\">{}</a>", escape_html(&href), escape_html(part)));
+    }
+    crumbs
+}
 
-    if repeat_begin.is_none() || repeat_end.is_none() || repeat_end.unwrap() < repeat_begin.unwrap() {
-        // todo: proper error page
-        return Ok("error".to_string());
+/// Pick an icon for a directory entry based on its name
+fn icon_for(name: &str) -> &'static str {
+    if name.ends_with('/') || name == ".." {
+        return FOLDER_ICON;
     }
 
-    let rb = repeat_begin.unwrap();
-    let re = repeat_end.unwrap();
+    let extension = name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase()).unwrap_or_default();
+    if matches!(extension.as_str(), "zip" | "tar" | "gz" | "tgz" | "xz" | "bz2" | "zst" | "7z" | "rar" | "deb" | "rpm" | "iso") {
+        return ARCHIVE_ICON;
+    }
 
-    let mut insertion_index = rb;
+    match mime_guess::from_ext(&extension).first() {
+        Some(mime) if mime.type_() == mime::IMAGE => IMAGE_ICON,
+        Some(mime) if is_compressible(&mime) => TEXT_ICON,
+        _ => FILE_ICON,
+    }
+}
 
-    let mut stripped_layout = LAYOUT.to_string();
-    stripped_layout.replace_range(rb..re + 2, "");
+/// Escape [text] so it can be put into HTML verbatim
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
 
-    let s;
-    stripped_layout = stripped_layout.replace("$title", "mirra")
-        .replace("$name", &config.name)
-        .replace("$desc", &make_description(&config.name, &module))
-        .replace("$setup", if host.is_some() && module.is_some() {
-            s = format!("mirra sync {} {}", host.as_ref().unwrap(), module.as_ref().unwrap());
-            s.as_str()
-        } else { "" });
+/// Decode a percent-encoded query value, `+` stands for a space
+fn decode_query_value(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let hex = |i: usize| bytes.get(i + 1..i + 3)
+        .and_then(|digits| std::str::from_utf8(digits).ok())
+        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+    let mut i = 0;
+    while i < bytes.len() {
+        match (bytes[i], hex(i)) {
+            (b'+', _) => res.push(b' '),
+            (b'%', Some(byte)) => {
+                res.push(byte);
+                i += 2;
+            }
+            (b, _) => res.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+/// Percent-encode a query value, which also makes it safe to put into an HTML attribute
+fn encode_query_value(value: &str) -> String {
+    value.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+/// The `[key]=value&amp;` pairs of [query] for [keys], to carry them over into a link
+fn link_query(query: &HashMap<String, String>, keys: &[&str]) -> String {
+    keys.iter()
+        .filter_map(|key| query.get(*key).map(|value| format!("{}={}&amp;", key, encode_query_value(&decode_query_value(value)))))
+        .collect()
+}
+
+/// Get the decoded `?q=` search term, if there is one
+fn search_term(query: &HashMap<String, String>) -> Option<String> {
+    query.get("q").map(|q| decode_query_value(q)).filter(|q| !q.is_empty())
+}
+
+/// Whether `?all=1` asks for hidden entries, the ones whose name starts with a dot
+fn show_hidden(query: &HashMap<String, String>) -> bool {
+    query.get("all").map_or(false, |all| all == "1")
+}
+
+/// Whether `?hashes=1` asks for the blake3 hash of every listed file
+fn show_hashes(query: &HashMap<String, String>) -> bool {
+    query.get("hashes").map_or(false, |hashes| hashes == "1")
+}
+
+/// Check whether [name] contains [term], ignoring ASCII case
+fn matches_term(name: &str, term: &str) -> bool {
+    name.to_ascii_lowercase().contains(&term.to_ascii_lowercase())
+}
 
-    let repeat = LAYOUT.chars().skip(rb + 2).take(re - rb - 2).collect::<String>();
+/// Escape [name] and wrap every occurrence of [term] in a `<mark>`
+fn highlight(name: &str, term: &str) -> String {
+    // ASCII lowercasing keeps byte offsets intact
+    let lower = name.to_ascii_lowercase();
+    let term = term.to_ascii_lowercase();
+    let mut res = String::new();
+    let mut last = 0;
+    for (start, _) in lower.match_indices(&term) {
+        if start < last { continue; }
+        res.push_str(&escape_html(&name[last..start]));
+        res.push_str(&format!("<mark>{}</mark>", escape_html(&name[start..start + term.len()])));
+        last = start + term.len();
+    }
+    res.push_str(&escape_html(&name[last..]));
+    res
+}
 
-    for sync in entries {
-        let str = repeat
-            .replace("$path", &(sync.0 + ""))
-            .replace("$info", &sync.1)
-            .replace("$download", if sync.2 { "download" } else { "" });
-        stripped_layout.insert_str(insertion_index, &str);
-        insertion_index += str.len();
+/// Render the search form for [module], pre-filled with the current search term
+/// Searching keeps hidden entries and hashes visible if they are
+fn make_search_form(module: &str, term: &Option<String>, query: &HashMap<String, String>) -> String {
+    let all = if show_hidden(query) { "<input type=\"hidden\" name=\"all\" value=\"1\">" } else { "" };
+    let hashes = if show_hashes(query) { "<input type=\"hidden\" name=\"hashes\" value=\"1\">" } else { "" };
+    format!("<form class=\"search\" method=\"get\">\
+        <input type=\"search\" name=\"q\" value=\"{}\" placeholder=\"Filter this directory\"> {}{}\
+        <button type=\"submit\">Filter</button> \
+        <button type=\"submit\" formaction=\"/search/{}/\">Search module</button> \
+        {}{}</form>", escape_html(term.as_deref().unwrap_or("")), all, hashes, module,
+        make_toggle(query, "all", "hidden-toggle", "hidden files"),
+        make_toggle(query, "hashes", "hashes-toggle", "hashes"))
+}
+
+/// Link to the same page with `?[key]=1` switched on or off, keeping the search, sorting and other toggles
+/// The link is labelled "Show [what]" or "Hide [what]"
+fn make_toggle(query: &HashMap<String, String>, key: &str, class: &str, what: &str) -> String {
+    let others: Vec<&str> = ["q", "sort", "order", "all", "hashes"].into_iter().filter(|other| *other != key).collect();
+    let href = format!("?{}", link_query(query, &others));
+    if query.get(key).map_or(false, |value| value == "1") {
+        format!("<a class=\"{}\" href=\"{}\">Hide {}</a>", class, href.trim_end_matches("&amp;"), what)
+    } else {
+        format!("<a class=\"{}\" href=\"{}{}=1\">Show {}</a>", class, href, key, what)
     }
+}
 
-    Ok(stripped_layout)
+/// A single row of a listing page
+struct PageEntry {
+    /// Link target of the row, as it is on disk
+    path: String,
+    /// Displayed name, already HTML
+    label: String,
+    info: String,
+    /// Formatted modification time, or `-` if unknown
+    modified: String,
+    download: bool,
 }
 
-async fn list_directory(path: PathBuf, module: String, host: Option<String>, config: Arc<Config>) -> Result<String> {
-    let mut list = tokio::fs::read_dir(path).await?;
-    let mut entries: Vec<(String, String, bool)> = [("..".to_string(), "-".to_string(), false)].into();
-    loop {
-        // Get next directory entry
-        let entry = list.next_entry().await?;
-        if entry.is_none() { break; }
-        if let Some(entry) = entry {
-            let name = entry.file_name().into_string();
-            if let Ok(mut name) = name {
+impl PageEntry {
+    fn new(path: String, info: String, download: bool) -> Self {
+        PageEntry { label: escape_html(&path), path, info, modified: "-".to_string(), download }
+    }
+}
+
+/// A listing page split around its repeated section, so rows can be rendered one at a time
+struct ListPage {
+    head: String,
+    repeat: &'static str,
+    tail: String,
+}
+
+impl ListPage {
+    fn new(path: &str, search: &str, pages: &str, module: Option<String>, host: Option<String>, config: Arc<Config>) -> Self {
+        let layout = &theme().layout;
+        let (rb, re) = match repeat_section(layout) {
+            Some(section) => section,
+            // todo: proper error page
+            None => return ListPage { head: "error".to_string(), repeat: "", tail: String::new() },
+        };
+
+        let setup = match (&host, &module) {
+            (Some(host), Some(module)) => format!("mirra sync {} {}", host, module),
+            _ => String::new(),
+        };
+        let fill = |part: &str| part.replace("$title", "mirra")
+            .replace("$name", &config.name)
+            .replace("$breadcrumbs", &make_breadcrumbs(path, &config))
+            .replace("$search", search)
+            .replace("$pages", pages)
+            .replace("$desc", &make_description(&config, &module))
+            .replace("$setup", &setup);
+
+        // Fill the parts around the repeated section separately, so entries can't shift or contain placeholders
+        ListPage {
+            head: fill(&layout[..rb]),
+            repeat: &layout[rb + 2..re],
+            tail: fill(&layout[re + 2..]),
+        }
+    }
+
+    /// Render the row for [entry]
+    fn row(&self, entry: &PageEntry) -> String {
+        self.repeat
+            .replace("$icon", icon_for(&entry.path).trim_end())
+            .replace("$path", &escape_html(&entry.path))
+            .replace("$label", &entry.label)
+            .replace("$info", &entry.info)
+            .replace("$mtime", &entry.modified)
+            .replace("$download", if entry.download { "download" } else { "" })
+    }
+}
+
+fn make_list_page(entries: Vec<PageEntry>, path: &str, search: &str, pages: &str, module: Option<String>, host: Option<String>, config: Arc<Config>) -> Result<String> {
+    let list = ListPage::new(path, search, pages, module, host, config);
+    let mut page = list.head.clone();
+    for entry in entries {
+        page.push_str(&list.row(&entry));
+    }
+    page.push_str(&list.tail);
+    Ok(page)
+}
+
+/// Answer with [list], rendering a row for each of [rows] only once it's about to be sent
+fn stream_list_page<I: Iterator<Item = PageEntry> + Send + 'static>(list: ListPage, rows: I, gzip: bool) -> Response<Body> {
+    // The page is written into one end of a pipe while the response streams the other
+    let (mut writer, reader) = duplex(64 * 1024);
+    tokio::spawn(async move {
+        // This only fails if the client went away
+        let _ = async {
+            writer.write_all(list.head.as_bytes()).await?;
+            for row in rows {
+                writer.write_all(list.row(&row).as_bytes()).await?;
+            }
+            writer.write_all(list.tail.as_bytes()).await?;
+            writer.shutdown().await
+        }.await;
+    });
+
+    let builder = Response::builder()
+        .header(CONTENT_TYPE, HTML_CONTENT_TYPE)
+        .header(VARY, "accept-encoding");
+    if gzip {
+        let stream = FramedRead::new(GzipEncoder::new(BufReader::new(reader)), BytesCodec::new());
+        builder.header(CONTENT_ENCODING, "gzip").body(Body::wrap_stream(stream)).unwrap()
+    } else {
+        builder.body(Body::wrap_stream(FramedRead::new(reader, BytesCodec::new()))).unwrap()
+    }
+}
+
+/// A single directory entry, as collected by [list_directory]
+struct ListEntry {
+    name: String,
+    /// Where the entry is on disk
+    path: PathBuf,
+    is_dir: bool,
+    size: Option<u64>,
+    modified: Option<SystemTime>,
+    /// How the entry resolves, if it is a symlink
+    link: Option<Link>,
+    /// The blake3 hash of a file, only looked up by [add_hashes]
+    hash: Option<String>,
+}
+
+/// Where a symlink in a module points to
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Link {
+    /// Something inside of the module, which is served like the link's target
+    Inside,
+    /// Nothing, or something outside of the module, which isn't served
+    Broken,
+}
+
+/// Whether [path] resolves to somewhere inside of one of [roots], following symlinks
+fn inside_module(path: &Path, roots: &[PathBuf]) -> bool {
+    match std::fs::canonicalize(path) {
+        Ok(real) => roots.iter()
+            .filter_map(|root| std::fs::canonicalize(root).ok())
+            .any(|root| real.starts_with(root)),
+        Err(_) => false,
+    }
+}
+
+/// Parse a query string like `a=b&c=d` into a map
+fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    let mut res = HashMap::new();
+    if let Some(query) = query {
+        for pair in query.split('&') {
+            let mut split = pair.splitn(2, '=');
+            let key = split.next().unwrap();
+            if !key.is_empty() {
+                res.insert(key.to_string(), split.next().unwrap_or("").to_string());
+            }
+        }
+    }
+    res
+}
+
+/// Sort directory entries by `?sort=name|size|date&order=asc|desc`, directories always go first
+fn sort_entries(entries: &mut [ListEntry], query: &HashMap<String, String>) {
+    let sort = query.get("sort").map(String::as_str).unwrap_or("name");
+    let descending = query.get("order").map(String::as_str) == Some("desc");
+
+    entries.sort_by(|a, b| {
+        let by_name = || a.name.to_lowercase().cmp(&b.name.to_lowercase()).then_with(|| a.name.cmp(&b.name));
+        let ord = match sort {
+            "size" => a.size.cmp(&b.size).then_with(by_name),
+            "date" => a.modified.cmp(&b.modified).then_with(by_name),
+            _ => by_name(),
+        };
+        // Directories first, regardless of the order
+        b.is_dir.cmp(&a.is_dir).then(if descending { ord.reverse() } else { ord })
+    });
+}
+
+/// Get the requested `?page=N&per_page=M`, pages are counted from 1
+fn parse_page(query: &HashMap<String, String>) -> (usize, usize) {
+    let page = query.get("page").and_then(|p| p.parse().ok()).filter(|p| *p > 0).unwrap_or(1);
+    let per_page = query.get("per_page").and_then(|p| p.parse().ok()).filter(|p| *p > 0).unwrap_or(DEFAULT_PER_PAGE);
+    (page, per_page.min(MAX_PER_PAGE))
+}
+
+/// Build a query string for [page] that keeps the sorting of [query]
+fn page_query(query: &HashMap<String, String>, page: usize, per_page: usize) -> String {
+    let mut res = format!("?{}page={}", link_query(query, &["q", "sort", "order", "all", "hashes"]), page);
+    if per_page != DEFAULT_PER_PAGE {
+        res.push_str(&format!("&amp;per_page={}", per_page));
+    }
+    res
+}
+
+/// Render links to the previous and next page, or nothing if everything fits on one page
+fn make_page_nav(query: &HashMap<String, String>, page: usize, pages: usize, per_page: usize) -> String {
+    if pages <= 1 {
+        return String::new();
+    }
+    let mut res = String::new();
+    if page > 1 {
+        res.push_str(&format!("<a href=\"{}\">&laquo; prev</a> ", page_query(query, page - 1, per_page)));
+    }
+    res.push_str(&format!("page {} of {}", page, pages));
+    if page < pages {
+        res.push_str(&format!(" <a href=\"{}\">next &raquo;</a>", page_query(query, page + 1, per_page)));
+    }
+    res
+}
+
+/// Collect the entries of the directories at [paths] together, filtered and sorted according to [query]
+/// Hidden entries are left out, unless `?all=1` asks for them, symlinks are checked against the module's [roots]
+async fn collect_entries(paths: Vec<PathBuf>, roots: &[PathBuf], query: &HashMap<String, String>) -> Result<Vec<ListEntry>> {
+    let mut collected = Vec::new();
+    let mut names = HashSet::new();
+    let hidden = show_hidden(query);
+    for path in paths {
+        let mut list = tokio::fs::read_dir(path).await?;
+        loop {
+            // Get next directory entry
+            let entry = list.next_entry().await?;
+            if entry.is_none() { break; }
+            if let Some(entry) = entry {
+                let name = entry.file_name().into_string();
+                if let Ok(mut name) = name {
+                    if name.starts_with('.') && !hidden {
+                        continue;
+                    }
+                    // Links that can't be followed are listed as they are, instead of failing the whole listing
+                    let link = match entry.file_type().await {
+                        Ok(file_type) if file_type.is_symlink() => Some(if inside_module(&entry.path(), roots) {
+                            Link::Inside
+                        } else {
+                            Link::Broken
+                        }),
+                        _ => None,
+                    };
+                    let is_dir = link != Some(Link::Broken) && entry.path().is_dir();
+                    if is_dir {
+                        name.push('/');
+                    }
+                    // Directories that are in several of the module's paths are only listed once
+                    if !names.insert(name.clone()) {
+                        continue;
+                    }
+                    let metadata = match link {
+                        Some(Link::Broken) => entry.metadata().await.ok(),
+                        _ => fs::metadata(entry.path()).await.ok(),
+                    };
+                    collected.push(ListEntry {
+                        name,
+                        path: entry.path(),
+                        is_dir,
+                        size: metadata.as_ref().filter(|_| !is_dir && link != Some(Link::Broken)).map(|m| m.len()),
+                        modified: metadata.and_then(|m| m.modified().ok()),
+                        link,
+                        hash: None,
+                    });
+                }
+            }
+        }
+    }
+
+    if let Some(term) = search_term(query) {
+        collected.retain(|entry| matches_term(&entry.name, &term));
+    }
+
+    // Sort only after all metadata was collected
+    sort_entries(&mut collected, query);
+    Ok(collected)
+}
+
+/// Look up the blake3 hash of every file in [entries] that is served
+/// Files that aren't in the hash cache are hashed with one of [module]'s download slots, or turned away like a download
+async fn add_hashes(entries: &mut [ListEntry], state: &WebState, module: &str) -> std::result::Result<(), Response<Body>> {
+    let mut uncached = Vec::new();
+    for entry in entries.iter_mut().filter(|entry| !entry.is_dir && entry.link != Some(Link::Broken)) {
+        match cached_file_hash(&entry.path, HashAlgo::Blake3) {
+            Some(hash) => entry.hash = Some(hash),
+            None => uncached.push(entry),
+        }
+    }
+    if uncached.is_empty() {
+        return Ok(());
+    }
+
+    let _permit = acquire_download(state, module)?;
+    for entry in uncached {
+        match file_hash(&entry.path, HashAlgo::Blake3).await {
+            Ok(hash) => entry.hash = Some(hash),
+            Err(e) => warn!("Failed to hash {}: {}", entry.path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+/// Strip the algorithm in front of [hash], leaving plain hex
+fn plain_hex(hash: String) -> String {
+    hash.split_once(':').map_or(hash.clone(), |(_, hex)| hex.to_string())
+}
+
+/// The hash of the file at [path] as plain hex, without the algorithm in front
+async fn file_hash(path: &Path, algo: HashAlgo) -> Result<String> {
+    let mut file = File::open(path).await?;
+    Ok(plain_hex(cached_hash_file(path, &mut file, algo).await?))
+}
+
+/// The hash of the file at [path] as plain hex, if it is cached and the file didn't change since
+fn cached_file_hash(path: &Path, algo: HashAlgo) -> Option<String> {
+    let metadata = std::fs::metadata(path).ok()?;
+    cached_hash(path, &metadata, algo).map(plain_hex)
+}
+
+/// Like [file_hash], but files that aren't in the hash cache take one of [module]'s download slots while they're hashed
+/// Answers with the response turning the client away if there is no slot
+async fn slotted_file_hash(path: &Path, algo: HashAlgo, state: &WebState, module: &str) -> Result<std::result::Result<String, Response<Body>>> {
+    if let Some(hash) = cached_file_hash(path, algo) {
+        return Ok(Ok(hash));
+    }
+    let _permit = match acquire_download(state, module) {
+        Ok(permit) => permit,
+        Err(response) => return Ok(Err(response)),
+    };
+    file_hash(path, algo).await.map(Ok)
+}
+
+/// Checksum files that are answered for every file, unless there is a real one, and how they are hashed
+const SIDECARS: [(&str, HashAlgo); 2] = [(".b3sum", HashAlgo::Blake3), (".sha256", HashAlgo::Sha256)];
+
+/// Answer a request for `<file>.b3sum` or `<file>.sha256` in the format `b3sum` and `sha256sum` print
+/// Returns None if [path] isn't a sidecar of a file that is served
+async fn sidecar_response(path: &str, module: &str, state: &WebState) -> Result<Option<Response<Body>>> {
+    let (file, algo) = match SIDECARS.iter().find_map(|(ext, algo)| path.strip_suffix(ext).map(|file| (file, *algo))) {
+        Some(sidecar) => sidecar,
+        None => return Ok(None),
+    };
+    let target = match resolve_path(file, &state.config).and_then(|resolved| resolved.existing()).filter(|target| target.is_file()) {
+        Some(target) => target,
+        None => return Ok(None),
+    };
+    let name = target.file_name().unwrap().to_string_lossy().to_string();
+    let hash = match slotted_file_hash(&target, algo, state, module).await? {
+        Ok(hash) => hash,
+        Err(response) => return Ok(Some(response)),
+    };
+    Ok(Some(text_response(format!("{}  {}\n", hash, name), "text/plain; charset=utf-8", false)))
+}
+
+/// Answer with one page of a directory listing, tagged with a weak ETag over the listed entries
+/// The page is streamed, unless the client already has it
+async fn list_directory(paths: Vec<PathBuf>, roots: &[PathBuf], request_path: &str, module: String, query: HashMap<String, String>, host: Option<String>, state: &WebState, headers: &HeaderMap) -> Result<Response<Body>> {
+    let config = state.config.clone();
+    let mut collected = collect_entries(paths, roots, &query).await?;
+
+    let (page, per_page) = parse_page(&query);
+    let pages = ((collected.len() + per_page - 1) / per_page).max(1);
+    let page = page.min(pages);
+    let mut collected: Vec<_> = collected.drain(..).skip((page - 1) * per_page).take(per_page).collect();
+    // Only the files on this page are hashed
+    if show_hashes(&query) {
+        if let Err(response) = add_hashes(&mut collected, state, &module).await {
+            return Ok(response);
+        }
+    }
+
+    // The listing only changes if an entry on this page does
+    let mut hasher = Hasher::new();
+    let term = search_term(&query);
+    hasher.update(format!("{}/{}/{}\0{}\0{}\0{}\0", page, pages, per_page, term.as_deref().unwrap_or(""), show_hidden(&query), show_hashes(&query)).as_bytes());
+    for entry in &collected {
+        let modified = entry.modified.and_then(|m| m.duration_since(UNIX_EPOCH).ok()).unwrap_or_default();
+        hasher.update(format!("{}\0{:?}\0{}\0{:?}\0{:?}\0", entry.name, entry.size, modified.as_nanos(), entry.link, entry.hash).as_bytes());
+    }
+    let etag = format!("W/\"{}\"", hasher.finalize());
+    if not_modified(headers, &etag, None) {
+        return Ok(not_modified_response(&etag, None));
+    }
+
+    let nav = make_page_nav(&query, page, pages, per_page);
+    let search = make_search_form(&module, &term, &query)
+        + "<span class=\"archive\">Download as <a href=\"?download=tar\">.tar</a> <a href=\"?download=tar.gz\">.tar.gz</a></span>";
+    let list = ListPage::new(request_path, &search, &nav, Some(module), host, config);
+
+    let rows = collected.into_iter().map(move |entry| {
+        let mut page_entry = PageEntry::new(entry.name, if let Some(size) = entry.size {
+            format_size(size)
+        } else {
+            "-".to_string()
+        }, !entry.is_dir);
+        if let Some(modified) = entry.modified {
+            page_entry.modified = format_time(modified);
+        }
+        if let Some(hash) = &entry.hash {
+            page_entry.info.push_str(&format!(" <code class=\"hash\">{}</code>", hash));
+        }
+        if let Some(term) = &term {
+            page_entry.label = highlight(&page_entry.path, term);
+        }
+        match entry.link {
+            Some(Link::Inside) => page_entry.label.push_str(" <span class=\"link\">(link)</span>"),
+            Some(Link::Broken) => {
+                page_entry.label.push_str(" <span class=\"link\">(broken link)</span>");
+                page_entry.download = false;
+            }
+            None => {}
+        }
+        page_entry
+    });
+    let rows = std::iter::once(PageEntry::new("..".to_string(), "-".to_string(), false)).chain(rows);
+
+    let mut response = stream_list_page(list, rows, accepts_gzip(headers));
+    response.headers_mut().insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+    Ok(response)
+}
+
+/// Walk [module_dirs] up to [MAX_SEARCH_DEPTH] levels deep, rendering every entry whose name contains the `?q=` term
+async fn search_module(module_dirs: Vec<PathBuf>, module: String, query: HashMap<String, String>, host: Option<String>, config: Arc<Config>) -> Result<String> {
+    let term = search_term(&query);
+    let hidden = show_hidden(&query);
+    let mut results = Vec::new();
+    let mut found = HashSet::new();
+
+    if let Some(term) = &term {
+        let mut pending: Vec<_> = module_dirs.into_iter().map(|dir| (dir, String::new(), 0)).collect();
+        'walk: while let Some((dir, relative, depth)) = pending.pop() {
+            // A directory that can't be read shouldn't hide the results from all the others
+            let mut list = match tokio::fs::read_dir(&dir).await {
+                Ok(list) => list,
+                Err(e) => {
+                    warn!("Can't search {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            loop {
+                let entry = match list.next_entry().await {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => break,
+                    Err(e) => {
+                        warn!("Can't search {}: {}", dir.display(), e);
+                        break;
+                    }
+                };
+                let name = match entry.file_name().into_string() {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                // Hidden directories aren't searched either
+                if name.starts_with('.') && !hidden {
+                    continue;
+                }
                 let is_dir = entry.path().is_dir();
-                if is_dir {
-                    name.push('/');
+                // Linked directories are found, but not searched, so the walk can't leave the module or go in circles
+                let is_link = entry.file_type().await.map_or(true, |file_type| file_type.is_symlink());
+                let relative = format!("{}{}{}", relative, name, if is_dir { "/" } else { "" });
+
+                // Directories that are in several of the module's paths are found in each of them
+                if matches_term(&name, term) && found.insert(relative.clone()) {
+                    let metadata = entry.metadata().await.ok();
+                    let size = metadata.as_ref().filter(|_| !is_dir).map(|m| m.len());
+                    results.push(PageEntry {
+                        path: format!("/{}/{}", module, relative),
+                        label: highlight(&relative, term),
+                        info: size.map(format_size).unwrap_or_else(|| "-".to_string()),
+                        modified: metadata.and_then(|m| m.modified().ok()).map(format_time).unwrap_or_else(|| "-".to_string()),
+                        download: !is_dir,
+                    });
+                    if results.len() >= MAX_SEARCH_RESULTS {
+                        break 'walk;
+                    }
                 }
-                let metadata = entry.metadata().await;
-                entries.push((name, if !is_dir && metadata.is_ok() {
-                    format_size(metadata.unwrap().len())
+                if is_dir && !is_link && depth + 1 < MAX_SEARCH_DEPTH {
+                    pending.push((entry.path(), relative, depth + 1));
+                }
+            }
+        }
+    }
+
+    results.sort_by(|a, b| a.path.cmp(&b.path));
+    let search = make_search_form(&module, &term, &query);
+    let request_path = format!("/{}/", module);
+    make_list_page(results, &request_path, &search, "", Some(module), host, config)
+}
+
+/// A module, as listed by `/api/modules`
+#[derive(Serialize)]
+struct ApiModule {
+    name: String,
+    is_remote: bool,
+    description: Option<String>,
+}
+
+/// A directory entry, as listed by `/api/list/<module>/<path>`
+#[derive(Serialize)]
+struct ApiEntry {
+    name: String,
+    size: Option<u64>,
+    is_dir: bool,
+    is_link: bool,
+    /// Whether the entry is a link that points nowhere or outside of the module, and isn't served
+    broken_link: bool,
+    /// The blake3 hash of a file, only with `?hashes=1`
+    hash: Option<String>,
+}
+
+/// The state of the instance, as reported by `/health`
+#[derive(Serialize)]
+struct Health {
+    uptime_secs: u64,
+    shares: usize,
+    syncs: usize,
+    /// Whether the last change to Mirra.toml was loaded
+    config_loaded: bool,
+}
+
+/// Answer with every metric, in the Prometheus text format
+fn metrics_response() -> Response<Body> {
+    text_response(metrics::render(), "text/plain; version=0.0.4", false)
+}
+
+/// Serve only `/metrics` on [addr], until mirra shuts down
+async fn serve_metrics(addr: SocketAddr, mut shutdown: Shutdown) {
+    let make_service = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            Ok::<_, Infallible>(if req.uri().path() == "/metrics" {
+                metrics_response()
+            } else {
+                Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap()
+            })
+        }))
+    });
+
+    let server = match Server::try_bind(&addr) {
+        Ok(server) => server.serve(make_service)
+            .with_graceful_shutdown(async move { shutdown_requested(&mut shutdown).await }),
+        Err(e) => {
+            warn!("Can't serve metrics on {}: {}", addr, e);
+            return;
+        }
+    };
+    if let Err(e) = server.await {
+        warn!("{}", e);
+    }
+}
+
+/// Build a JSON response from anything serializable
+fn json_response<T: Serialize>(value: &T, gzip: bool) -> Response<Body> {
+    text_response(serde_json::to_string(value).unwrap(), JSON_CONTENT_TYPE, gzip)
+}
+
+/// Answer requests below `/api/`, [path] being everything after that
+async fn handle_api(path: &str, query: HashMap<String, String>, headers: &HeaderMap, state: &WebState) -> Result<Response<Body>> {
+    let config = state.config.clone();
+    let gzip = accepts_gzip(headers);
+    if path == "modules" {
+        let mut modules = Vec::new();
+        for share in config.shares.iter().filter(|share| share.1.web) {
+            modules.push(ApiModule { name: share.0.clone(), is_remote: false, description: share.1.description.clone() });
+        }
+        for sync in config.syncs.iter().filter(|sync| sync.1.web) {
+            modules.push(ApiModule { name: sync.0.clone(), is_remote: true, description: sync.1.description.clone() });
+        }
+        modules.sort_by(|a, b| a.name.cmp(&b.name));
+        return Ok(json_response(&modules, gzip));
+    }
+
+    if let Some(path) = path.strip_prefix("list/") {
+        if let Some(resolved) = resolve_path(path, &config) {
+            if let Some(auth) = state.share_auth.get(&resolved.module) {
+                if !auth.authorize(headers) {
+                    return Ok(unauthorized(auth.as_ref()));
+                }
+            }
+            let dirs = resolved.dirs();
+            if !dirs.is_empty() {
+                let mut entries = collect_entries(dirs, &resolved.roots, &query).await?;
+                if show_hashes(&query) {
+                    // The list isn't paginated, but only the files on `?page=` are hashed
+                    let (page, per_page) = parse_page(&query);
+                    let start = (page - 1).saturating_mul(per_page).min(entries.len());
+                    let end = (start + per_page).min(entries.len());
+                    if let Err(response) = add_hashes(&mut entries[start..end], state, &resolved.module).await {
+                        return Ok(response);
+                    }
+                }
+                let entries = entries.into_iter()
+                    .map(|entry| ApiEntry {
+                        name: entry.name.trim_end_matches('/').to_string(),
+                        size: entry.size,
+                        is_dir: entry.is_dir,
+                        is_link: entry.link.is_some(),
+                        broken_link: entry.link == Some(Link::Broken),
+                        hash: entry.hash,
+                    })
+                    .collect::<Vec<_>>();
+                return Ok(json_response(&entries, gzip));
+            }
+        }
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .header(CONTENT_TYPE, JSON_CONTENT_TYPE)
+        .body(Body::from("{}")).unwrap())
+}
+
+/// Where a request path points to on disk
+struct ResolvedPath {
+    module: String,
+    /// Where the path would be in each of the module's paths
+    paths: Vec<PathBuf>,
+    /// The module's paths, nothing outside of them is served
+    roots: Vec<PathBuf>,
+    /// Where the file would be on the CDN, if the share has one
+    cdn: Option<String>,
+}
+
+impl ResolvedPath {
+    /// The first of [self.paths] that exists inside of the module, which decides whether the path is a file or a directory
+    fn existing(&self) -> Option<PathBuf> {
+        self.paths.iter().find(|path| path.exists() && inside_module(path, &self.roots)).cloned()
+    }
+
+    /// Every one of [self.paths] that is a directory inside of the module, their contents are shown together
+    fn dirs(&self) -> Vec<PathBuf> {
+        self.paths.iter().filter(|path| path.is_dir() && inside_module(path, &self.roots)).cloned().collect()
+    }
+}
+
+/// Find the module [path] (without its leading slash) belongs to and where it is on disk
+fn resolve_path(path: &str, config: &Config) -> Option<ResolvedPath> {
+    // Only whole segments match, otherwise `pub` would also serve `public`
+    let in_module = |name: &str| path == name || path.strip_prefix(name).map_or(false, |rest| rest.starts_with('/'));
+    // Modules hidden from the web are treated as if they didn't exist
+    for share in config.shares.iter().filter(|share| share.1.web) {
+        if in_module(share.0) {
+            let rest = &path[share.0.len()..];
+            let cdn = share.1.cdn_base.as_ref()
+                .map(|base| format!("{}{}", base.trim_end_matches('/'), rest));
+            return Some(ResolvedPath {
+                module: share.0.to_string(),
+                paths: share.1.paths.iter()
+                    .map(|dir| env::current_dir().unwrap().join(format!("{}{}", dir, rest)))
+                    .collect(),
+                roots: share.1.paths.iter().map(|dir| env::current_dir().unwrap().join(dir)).collect(),
+                cdn,
+            });
+        }
+    }
+
+    for sync in config.syncs.iter().filter(|sync| sync.1.web) {
+        if in_module(sync.0) {
+            let rest = &path[sync.0.len()..];
+            return Some(ResolvedPath {
+                module: sync.0.to_string(),
+                paths: vec![env::current_dir().unwrap().join(format!("{}{}", sync.1.path, rest))],
+                roots: vec![env::current_dir().unwrap().join(&sync.1.path)],
+                cdn: None,
+            });
+        }
+    }
+
+    None
+}
+
+/// The module a request for [path] is about, if it is about one
+fn requested_module(path: &str, config: &Config) -> Option<String> {
+    let rest = match path.strip_prefix("/api/") {
+        Some(api_path) => api_path.strip_prefix("list/")?,
+        None => ["/search/", "/hash/", "/"].iter().find_map(|prefix| path.strip_prefix(prefix))?,
+    };
+    resolve_path(rest.trim_end_matches('/'), config).map(|resolved| resolved.module)
+}
+
+/// Check whether the client sent `Accept-Encoding: gzip`
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    let accept = headers.get(ACCEPT_ENCODING).and_then(|a| a.to_str().ok());
+    accept.map_or(false, |accept| accept.split(',').any(|encoding| {
+        let mut parts = encoding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        // `q=0` explicitly forbids an encoding
+        (name == "gzip" || name == "*") && !parts.any(|p| p == "q=0" || p == "q=0.0")
+    }))
+}
+
+/// Whether files of type [mime] are worth compressing
+fn is_compressible(mime: &Mime) -> bool {
+    mime.type_() == mime::TEXT ||
+        matches!(mime.subtype().as_str(), "json" | "xml" | "javascript" | "svg")
+}
+
+/// Build a response with a text [body], gzip-compressed if the client allows it and it's worth it
+fn text_response(body: String, content_type: &str, gzip: bool) -> Response<Body> {
+    let builder = Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(VARY, "accept-encoding");
+    if gzip && body.len() >= GZIP_THRESHOLD as usize {
+        let encoder = GzipEncoder::new(Cursor::new(body.into_bytes()));
+        builder.header(CONTENT_ENCODING, "gzip")
+            .body(Body::wrap_stream(FramedRead::new(encoder, BytesCodec::new()))).unwrap()
+    } else {
+        builder.body(Body::from(body)).unwrap()
+    }
+}
+
+/// Check a request's `If-None-Match` and `If-Modified-Since` headers against a response's [etag] and [modified] time
+fn not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(none_match) = headers.get(IF_NONE_MATCH).and_then(|h| h.to_str().ok()) {
+        // GET requests use the weak comparison, ignoring the `W/` prefix
+        let etag = etag.trim_start_matches("W/");
+        return none_match.split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    }
+
+    // If-Modified-Since is ignored when If-None-Match is present
+    let since = headers.get(IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.parse::<HttpDate>().ok());
+    if let (Some(since), Some(modified)) = (since, modified) {
+        // HTTP dates are only precise to the second, which [HttpDate] truncates to as well
+        return HttpDate::from(modified) <= since;
+    }
+
+    false
+}
+
+/// Build a `304 Not Modified` response
+fn not_modified_response(etag: &str, modified: Option<SystemTime>) -> Response<Body> {
+    let mut builder = Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(ETAG, etag);
+    if let Some(modified) = modified {
+        builder = builder.header(LAST_MODIFIED, fmt_http_date(modified));
+    }
+    builder.body(Body::empty()).unwrap()
+}
+
+/// Keep [permit] alive for as long as [stream] is
+fn hold_permit<S: Stream>(stream: S, permit: Option<OwnedSemaphorePermit>) -> impl Stream<Item = S::Item> {
+    stream.map(move |chunk| {
+        // The permit is released once the stream is dropped
+        let _permit = &permit;
+        chunk
+    })
+}
+
+/// Add the directories at [paths] to [builder] as one directory called [name], merged the way they are listed
+/// Entries that are in several of them are taken from the first, hidden ones only if [hidden] is set
+async fn append_merged(builder: &mut Builder<DuplexStream>, name: &str, paths: &[PathBuf], hidden: bool) -> Result<()> {
+    builder.append_dir(name, &paths[0]).await?;
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        let mut seen = HashSet::new();
+        for path in paths {
+            // Not every path has every directory
+            let dir = path.join(&relative);
+            if !dir.is_dir() {
+                continue;
+            }
+            let mut list = tokio::fs::read_dir(&dir).await?;
+            while let Some(entry) = list.next_entry().await? {
+                let file_name = entry.file_name();
+                if (file_name.to_string_lossy().starts_with('.') && !hidden) || !seen.insert(file_name.clone()) {
+                    continue;
+                }
+                let relative = relative.join(&file_name);
+                let archived = Path::new(name).join(&relative);
+                // Links are archived as links, they could point out of the module
+                if entry.file_type().await?.is_dir() {
+                    builder.append_dir(&archived, entry.path()).await?;
+                    pending.push(relative);
                 } else {
-                    "-".to_string()
-                }, !is_dir));
+                    builder.append_path_with_name(entry.path(), &archived).await?;
+                }
             }
         }
     }
-    make_list_page(entries, Some(module), host, config)
+    Ok(())
 }
 
-async fn handle(req: Request<Body>, config: Arc<Config>) -> Result<Response<Body>> {
+/// Stream the directories at [paths] as one tar archive named after the first one, gzipped if [gzip] is set
+fn archive_response(paths: Vec<PathBuf>, gzip: bool, hidden: bool, permit: Option<OwnedSemaphorePermit>) -> Response<Body> {
+    let name = paths[0].file_name().and_then(|name| name.to_str()).unwrap_or("download").to_string();
+    let filename = format!("{}.tar{}", name.replace('"', "_"), if gzip { ".gz" } else { "" });
+
+    // The archive is written into one end of a pipe while the response streams the other
+    let (writer, reader) = duplex(64 * 1024);
+    tokio::spawn(async move {
+        let mut builder = Builder::new(writer);
+        builder.follow_symlinks(false);
+        let res = async {
+            append_merged(&mut builder, &name, &paths, hidden).await?;
+            builder.finish().await
+        }.await;
+        if let Err(e) = res {
+            warn!("Failed to archive {}: {}", paths[0].display(), e);
+        }
+    });
+
+    let builder = Response::builder()
+        .header(CONTENT_TYPE, if gzip { "application/gzip" } else { "application/x-tar" })
+        .header(CONTENT_DISPOSITION, HeaderValue::from_str(&format!("attachment; filename=\"{}\"", filename))
+            .unwrap_or(HeaderValue::from_static("attachment")));
+    if gzip {
+        let stream = FramedRead::new(GzipEncoder::new(BufReader::new(reader)), BytesCodec::new());
+        builder.body(Body::wrap_stream(hold_permit(stream, permit))).unwrap()
+    } else {
+        let stream = FramedRead::new(reader, BytesCodec::new());
+        builder.body(Body::wrap_stream(hold_permit(stream, permit))).unwrap()
+    }
+}
+
+/// Take one of [module]'s download slots, or answer with 503 if they're all in use
+fn acquire_download(state: &WebState, module: &str) -> std::result::Result<Option<OwnedSemaphorePermit>, Response<Body>> {
+    match state.downloads.get(module) {
+        Some(limit) => match limit.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => Err(busy()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Answer with `503 Service Unavailable`, asking the client to try again in a few seconds
+fn busy() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(RETRY_AFTER, "5")
+        .body(Body::empty()).unwrap()
+}
+
+/// Per-share semaphores limiting the number of concurrent downloads
+type DownloadLimits = HashMap<String, Arc<Semaphore>>;
+
+/// Everything requests share
+struct WebState {
+    config: Arc<Config>,
+    downloads: DownloadLimits,
+    auth: Box<dyn AuthProvider>,
+    /// Authentication for individual shares, which replaces [auth] for them
+    share_auth: HashMap<String, Box<dyn AuthProvider>>,
+    stats: StatsCache,
+    /// When the web server started
+    started: Instant,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl WebState {
+    /// Build the state for [config], taking over [previous]'s download limits where they didn't change
+    fn new(config: Arc<Config>, previous: Option<&WebState>) -> Self {
+        // Create a semaphore for every share that limits its downloads
+        let downloads = config.shares.iter()
+            .filter_map(|share| share.1.max_downloads.map(|max| {
+                // Reusing the semaphore keeps counting downloads that are already running
+                let unchanged = previous
+                    .filter(|previous| previous.config.shares.get(share.0).and_then(|s| s.max_downloads) == Some(max))
+                    .and_then(|previous| previous.downloads.get(share.0));
+                let limit = unchanged.cloned().unwrap_or_else(|| Arc::new(Semaphore::new(max)));
+                (share.0.clone(), limit)
+            }))
+            .collect();
+
+        WebState {
+            downloads,
+            auth: make_provider(&config.web_auth),
+            share_auth: config.shares.iter()
+                .filter(|share| share.1.auth.is_some())
+                .map(|share| (share.0.clone(), make_share_provider(&share.1.auth, &config.web_auth)))
+                .collect(),
+            stats: previous.map(|previous| previous.stats.clone()).unwrap_or_default(),
+            started: previous.map(|previous| previous.started).unwrap_or_else(Instant::now),
+            // Keep the log open unless it moved
+            access_log: match previous.filter(|previous| previous.config.access_log == config.access_log) {
+                Some(previous) => previous.access_log.clone(),
+                None => config.access_log.as_deref().and_then(AccessLog::open).map(Arc::new),
+            },
+            config,
+        }
+    }
+}
+
+/// Where the web server logs requests, in the combined log format
+enum AccessLog {
+    Stdout,
+    File(Mutex<std::fs::File>),
+}
+
+impl AccessLog {
+    /// Open [target] for appending, unless it is `stdout`
+    fn open(target: &str) -> Option<Self> {
+        if target == "stdout" {
+            return Some(AccessLog::Stdout);
+        }
+        match std::fs::OpenOptions::new().create(true).append(true).open(target) {
+            Ok(file) => Some(AccessLog::File(Mutex::new(file))),
+            Err(e) => {
+                warn!("Couldn't open the access log {}: {}", target, e);
+                None
+            }
+        }
+    }
+
+    fn write(&self, line: &str) {
+        let res = match self {
+            AccessLog::Stdout => writeln!(std::io::stdout().lock(), "{}", line),
+            AccessLog::File(file) => writeln!(file.lock().unwrap(), "{}", line),
+        };
+        if let Err(e) = res {
+            warn!("Couldn't write to the access log: {}", e);
+        }
+    }
+}
+
+/// Get header [name] for the access log, quotes escaped and `-` if it isn't there
+fn log_header(headers: &HeaderMap, name: HeaderName) -> String {
+    headers.get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.replace('"', "\\\""))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Answer with `401 Unauthorized`, asking for the credentials [auth] accepts
+fn unauthorized(auth: &dyn AuthProvider) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(WWW_AUTHENTICATE, auth.challenge())
+        .body(Body::empty()).unwrap()
+}
+
+/// Answer [req] from [remote], noting it in the access log if there is one
+async fn handle(req: Request<Body>, state: Arc<WebState>, remote: SocketAddr) -> Result<Response<Body>> {
+    let log = match state.access_log.clone() {
+        Some(log) => log,
+        None => return respond(req, state).await,
+    };
+
+    let request_line = format!("{} {} {:?}", req.method(), req.uri(), req.version()).replace('"', "\\\"");
+    let referer = log_header(req.headers(), REFERER);
+    let agent = log_header(req.headers(), USER_AGENT);
+
+    let response = respond(req, state).await;
+    let (status, bytes) = match &response {
+        Ok(response) => (
+            response.status().as_u16(),
+            response.headers().get(CONTENT_LENGTH).and_then(|len| len.to_str().ok()).unwrap_or("-").to_string(),
+        ),
+        Err(_) => (500, "-".to_string()),
+    };
+    log.write(&format!("{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"", remote.ip(), format_log_time(SystemTime::now()), request_line, status, bytes, referer, agent));
+    response
+}
+
+async fn respond(req: Request<Body>, state: Arc<WebState>) -> Result<Response<Body>> {
     if req.method() != &Method::GET {
         return Ok(Response::builder().status(StatusCode::METHOD_NOT_ALLOWED).body(Body::empty()).unwrap());
     }
 
+    let config = state.config.clone();
     let headers = req.headers();
     let host_header = headers.get("Host");
     let host = if let Some(host_header) = host_header {
@@ -122,97 +1417,499 @@ async fn handle(req: Request<Body>, config: Arc<Config>) -> Result<Response<Body
 
     let uri = req.uri();
     let path = uri.path();
+    let gzip = accepts_gzip(headers);
+
+    // Load balancers probe this without credentials, so it only reports counts and no module names
+    if path == "/health" {
+        return Ok(json_response(&Health {
+            uptime_secs: state.started.elapsed().as_secs(),
+            shares: config.shares.len(),
+            syncs: config.syncs.len(),
+            config_loaded: !reload_failed(),
+        }, false));
+    }
+
+    // Private shares take their own credentials as well as the global ones, a request can only carry one set
+    let auth = requested_module(path, &config)
+        .and_then(|module| state.share_auth.get(&module))
+        .unwrap_or(&state.auth);
+    // The stylesheet is needed to render the error page, and browsers ask for the favicon regardless
+    if path != "/style.css" && path != "/favicon.ico" && !auth.authorize(headers) {
+        return Ok(unauthorized(auth.as_ref()));
+    }
 
     if path == "/" {
         let mut modules = Vec::new();
+        let stats = state.stats.lock().unwrap().clone();
+        // Modules that haven't been walked yet just go without
+        let with_stats = |module: &String, info: String| match stats.get(module) {
+            Some(stats) => format!("{}, {}", info, stats.describe()),
+            None => info,
+        };
 
-        for share in &config.shares {
-            modules.push((share.0.clone() + "/", "root is local".to_string(), false));
+        for share in config.shares.iter().filter(|share| share.1.web) {
+            let info = match &share.1.description {
+                Some(description) => escape_html(description),
+                None => "root is local".to_string(),
+            };
+            modules.push(PageEntry::new(share.0.clone() + "/", with_stats(share.0, info), false));
         }
 
-        for sync in &config.syncs {
-            modules.push((sync.0.clone() + "/", format!("root is <a href=\"//{}\">remote</a>", sync.1.address), false));
+        for sync in config.syncs.iter().filter(|sync| sync.1.web) {
+            let mut info = match &sync.1.description {
+                Some(description) => escape_html(description),
+                None => format!("root is <a href=\"//{}\">remote</a>", sync.1.address),
+            };
+            if let Some(age) = state::get(sync.0).age() {
+                info.push_str(&format!(", updated {} ago", format_duration(age)));
+            }
+            modules.push(PageEntry::new(sync.0.clone() + "/", with_stats(sync.0, info), false));
         }
 
-        Ok(Response::new(Body::from(make_list_page(modules, None, host, config)?)))
+        Ok(text_response(make_list_page(modules, path, "", "", None, host, config)?, HTML_CONTENT_TYPE, gzip))
+    } else if let Some(api_path) = path.strip_prefix("/api/") {
+        handle_api(api_path, parse_query(uri.query()), headers, &state).await
+    } else if let Some(search_path) = path.strip_prefix("/search/") {
+        let module = search_path.trim_end_matches('/');
+        match resolve_path(module, &config).filter(|resolved| resolved.module == module && !resolved.dirs().is_empty()) {
+            Some(resolved) => {
+                if let Some(auth) = state.share_auth.get(&resolved.module) {
+                    if !auth.authorize(headers) {
+                        return Ok(unauthorized(auth.as_ref()));
+                    }
+                }
+                let page = search_module(resolved.dirs(), resolved.module, parse_query(uri.query()), host, config).await?;
+                Ok(text_response(page, HTML_CONTENT_TYPE, gzip))
+            }
+            None => Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap()),
+        }
+    } else if let Some(hash_path) = path.strip_prefix("/hash/") {
+        match resolve_path(hash_path, &config).and_then(|resolved| resolved.existing().filter(|file| file.is_file()).map(|file| (resolved.module, file))) {
+            Some((module, file)) => {
+                if let Some(auth) = state.share_auth.get(&module) {
+                    if !auth.authorize(headers) {
+                        return Ok(unauthorized(auth.as_ref()));
+                    }
+                }
+                match slotted_file_hash(&file, HashAlgo::Blake3, &state, &module).await? {
+                    Ok(hash) => Ok(text_response(format!("{}\n", hash), "text/plain; charset=utf-8", false)),
+                    Err(response) => Ok(response),
+                }
+            }
+            None => Ok(Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty()).unwrap()),
+        }
     } else if path == "/style.css" {
-        Ok(Response::new(STYLE.into()))
+        Ok(text_response(theme().style.clone(), "text/css; charset=utf-8", gzip))
+    } else if path == "/favicon.ico" {
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, "image/x-icon")
+            .body(Body::from(FAVICON)).unwrap())
     } else {
-        let mut s_path = path.chars().skip(1).collect::<String>();
-        let mut dir: Option<PathBuf> = None;
-        let mut init = false;
-        let mut module: Option<String> = None;
-        for share in &config.shares {
-            if s_path.starts_with(share.0) {
-                module = Some(share.0.to_string());
-                s_path.replace_range(0..share.0.len(), &share.1.path);
-                dir = Some(env::current_dir().unwrap().join(&s_path));
-                init = true;
-                break;
+        let resolved = resolve_path(&path[1..], &config);
+        let (module, dir, dirs, roots, cdn) = match resolved {
+            Some(resolved) => (Some(resolved.module.clone()), resolved.existing(), resolved.dirs(), resolved.roots, resolved.cdn),
+            None => (None, None, Vec::new(), Vec::new(), None),
+        };
+        let init = module.is_some();
+
+        // Private shares need their own credentials, or the global ones
+        if let Some(auth) = module.as_ref().and_then(|module| state.share_auth.get(module)) {
+            if !auth.authorize(headers) {
+                return Ok(unauthorized(auth.as_ref()));
             }
         }
 
-        if !init {
-            for sync in &config.syncs {
-                if s_path.starts_with(sync.0) {
-                    module = Some(sync.0.to_string());
-                    dir = Some(env::current_dir().unwrap().join(&s_path));
-                    init = true;
-                    break;
-                }
+        // Checksum files are made up for every file, unless there is a real one
+        if init && dir.is_none() {
+            if let Some(response) = sidecar_response(&path[1..], module.as_ref().unwrap(), &state).await? {
+                return Ok(response);
             }
         }
 
-        if !init || !dir.as_ref().unwrap().exists() {
+        if !init || dir.is_none() {
             Ok(Response::new(Body::from("Empty")))
         } else {
+            let query = parse_query(uri.query());
             if dir.as_ref().unwrap().is_dir() {
                 if !path.ends_with("/") {
                     Ok(Response::builder()
                         .status(StatusCode::PERMANENT_REDIRECT)
                         .header("Location", path.to_string() + "/")
                         .body(Body::empty()).unwrap())
+                } else if let Some(format) = query.get("download").filter(|f| *f == "tar" || *f == "tar.gz") {
+                    // Archives count as a single download
+                    match acquire_download(&state, module.as_ref().unwrap()) {
+                        Ok(permit) => Ok(archive_response(dirs, format == "tar.gz", show_hidden(&query), permit)),
+                        Err(response) => Ok(response),
+                    }
                 } else {
-                    Ok(Response::new(Body::from(list_directory(dir.unwrap(), module.unwrap(), host, config).await?)))
+                    list_directory(dirs, &roots, path, module.unwrap(), query, host, &state, headers).await
                 }
+            } else if let Some(cdn) = cdn {
+                // Let the CDN serve the file instead
+                Ok(Response::builder()
+                    .status(StatusCode::TEMPORARY_REDIRECT)
+                    .header("Location", cdn)
+                    .body(Body::empty()).unwrap())
             } else {
-                let file = File::open(dir.unwrap()).await.unwrap();
-                let stream = FramedRead::new(file, BytesCodec::new());
-                let body = Body::wrap_stream(stream);
-                Ok(Response::new(body))
+                // Guess the MIME type from the file extension, so browsers can display the file
+                let mime = mime_guess::from_path(dir.as_ref().unwrap()).first_or_octet_stream();
+                let file_path = dir.unwrap();
+                // Hold a permit for as long as the download runs, or turn the client away
+                // It's taken before hashing, which is as expensive as the download for files that changed
+                let permit = match acquire_download(&state, module.as_ref().unwrap()) {
+                    Ok(permit) => permit,
+                    Err(response) => return Ok(response),
+                };
+                let mut file = File::open(&file_path).await.unwrap();
+                // Never serve a file halfway through being written, the lock is held until the file is closed
+                if !file.try_lock_shared()? {
+                    return Ok(busy());
+                }
+                let metadata = file.metadata().await?;
+                let size = metadata.len();
+                let modified = metadata.modified().ok();
+
+                // Serve an up to date gzipped copy next to the file if there is one
+                let precompressed = if gzip { open_gz_copy(&file_path, modified).await } else { None };
+                // Otherwise compress text files on the fly, everything else is unlikely to get any smaller
+                let compress = precompressed.is_none() && gzip && is_compressible(&mime) && size >= GZIP_THRESHOLD;
+
+                // The compressed representation isn't byte-for-byte the file, so its ETag is weak
+                let hash = cached_hash_file(&file_path, &mut file, config.hash_algo).await?;
+                let etag = if compress || precompressed.is_some() { format!("W/\"{}\"", hash) } else { format!("\"{}\"", hash) };
+                if not_modified(headers, &etag, modified) {
+                    return Ok(not_modified_response(&etag, modified));
+                }
+
+                let mut builder = Response::builder()
+                    .header(CONTENT_TYPE, mime.as_ref())
+                    .header(VARY, "accept-encoding")
+                    .header(ETAG, &etag);
+                if let Some(modified) = modified {
+                    builder = builder.header(LAST_MODIFIED, fmt_http_date(modified));
+                }
+                if let Some((copy, copy_size)) = precompressed {
+                    let stream = FramedRead::new(copy.take(copy_size), BytesCodec::new());
+                    Ok(builder
+                        .header(CONTENT_ENCODING, "gzip")
+                        .header(CONTENT_LENGTH, copy_size)
+                        .body(Body::wrap_stream(hold_permit(stream, permit))).unwrap())
+                } else if compress {
+                    let stream = FramedRead::new(GzipEncoder::new(BufReader::new(file)), BytesCodec::new());
+                    Ok(builder
+                        .header(CONTENT_ENCODING, "gzip")
+                        .body(Body::wrap_stream(hold_permit(stream, permit))).unwrap())
+                } else {
+                    // Never send more than announced, in case the file grows in the meantime
+                    let stream = FramedRead::new(file.take(size), BytesCodec::new());
+                    Ok(builder
+                        .header(CONTENT_LENGTH, size)
+                        .body(Body::wrap_stream(hold_permit(stream, permit))).unwrap())
+                }
             }
         }
     }
 }
 
-pub async fn web(config: Arc<Config>, keys: Arc<LocalKeys>) -> Result<()> {
+/// Serve the web interface on port 80, or on the connections in [shared] if the sync server's port is shared
+pub async fn web(mut config: ConfigWatch, keys: Arc<LocalKeys>, shared: Option<mpsc::Receiver<TcpStream>>, mut shutdown: Shutdown) -> Result<()> {
 
     // Construct our SocketAddr to listen on...
-    let addr = SocketAddr::from(([0, 0, 0, 0], 80));
+    let bind = config.borrow().web_bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let addr = SocketAddr::new(bind, 80);
 
-    // And a MakeService to handle each connection...
-    let make_service = make_service_fn(move |_conn| {
-        // yay moving a non-Copy object into two nested async closures
-        let local_config = config.clone();
-        //let local_keys = keys.clone();
-        async move {
-            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
-                let ll_config = local_config.clone();
-                //let ll_keys = local_keys.clone();
-                async move {
-                    handle(req, ll_config.clone()).await
-                }
-            }))
+    // Operators can replace the embedded layout and stylesheet, this is only read once
+    let current = config.borrow().clone();
+    let _ = THEME.set(load_theme(&current).await);
+
+    // Scrapers don't log in, so metrics are kept off the web server where they'd leak every module's name
+    let metrics_bind = current.metrics_bind.unwrap_or(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 9107));
+    tokio::spawn(serve_metrics(metrics_bind, shutdown.clone()));
+
+    let initial = Arc::new(WebState::new(config.borrow().clone(), None));
+    let (state_tx, state) = watch::channel(initial.clone());
+    tokio::spawn(refresh_stats(initial.stats.clone(), config.clone(), shutdown.clone()));
+    tokio::spawn(precompress_shares(config.clone(), shutdown.clone()));
+
+    // Rebuild the state whenever the config changes, requests that are already running keep the old one
+    tokio::spawn(async move {
+        let mut previous = initial;
+        while config.changed().await.is_ok() {
+            let current = config.borrow().clone();
+            previous = Arc::new(WebState::new(current, Some(&previous)));
+            if state_tx.send(previous.clone()).is_err() {
+                break;
+            }
         }
     });
 
-    // Then bind and serve...
-    let server = Server::bind(&addr).serve(make_service);
+    // A service to handle each connection...
+    let service_for = move |remote: SocketAddr| {
+        // yay moving a non-Copy object into two nested async closures
+        let local_state = state.clone();
+        //let local_keys = keys.clone();
+        service_fn(move |req: Request<Body>| {
+            let ll_state = local_state.borrow().clone();
+            //let ll_keys = local_keys.clone();
+            async move {
+                handle(req, ll_state, remote).await
+            }
+        })
+    };
+    let graceful = async move { shutdown_requested(&mut shutdown).await };
 
-    // And run forever...
-    if let Err(e) = server.await {
+    // Then bind or take connections from the sync server, and run forever...
+    let res = if let Some(shared) = shared {
+        let incoming = futures_util::stream::unfold(shared, |mut shared| async move {
+            shared.recv().await.map(|socket| (Ok::<_, Infallible>(socket), shared))
+        });
+        let make_service = make_service_fn(move |socket: &TcpStream| {
+            let remote = socket.peer_addr().unwrap_or_else(|_| SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0));
+            let service = service_for(remote);
+            async move { Ok::<_, Infallible>(service) }
+        });
+        Server::builder(accept::from_stream(incoming)).serve(make_service).with_graceful_shutdown(graceful).await
+    } else {
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let service = service_for(conn.remote_addr());
+            async move { Ok::<_, Infallible>(service) }
+        });
+        Server::bind(&addr).serve(make_service).with_graceful_shutdown(graceful).await
+    };
+    if let Err(e) = res {
         warn!("{}", e);
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime};
+
+    use futures_util::StreamExt;
+    use hyper::{Body, Request, Response, StatusCode};
+    use hyper::header::{AUTHORIZATION, LOCATION, RETRY_AFTER};
+    use tokio::io::AsyncReadExt;
+    use tokio_tar::Archive;
+
+    use crate::config::{Config, parse_config};
+    use crate::util::cached_hash;
+    use crate::util::tests::TestDir;
+    use super::{DEFAULT_PER_PAGE, make_breadcrumbs, make_toggle, page_query, parse_query, requested_module, resolve_path, respond, search_term, WebState};
+
+    /// Parse [raw] as a Mirra.toml, which has to be valid
+    async fn config(raw: &str) -> Arc<Config> {
+        let mut errors = Vec::new();
+        let config = parse_config(raw, &mut errors).await;
+        assert!(errors.is_empty(), "{:?}", errors);
+        Arc::new(config)
+    }
+
+    /// A directory for [test], with [files] in it
+    fn module_dir(test: &str, files: &[&str]) -> TestDir {
+        let dir = TestDir::new(&format!("web-{}", test));
+        for file in files {
+            std::fs::write(dir.join(file), file).unwrap();
+        }
+        dir
+    }
+
+    /// Ask the web server for [path], with [authorization] if it is set
+    async fn get(state: &Arc<WebState>, path: &str, authorization: Option<String>) -> Response<Body> {
+        let mut request = Request::get(path);
+        if let Some(authorization) = authorization {
+            request = request.header(AUTHORIZATION, authorization);
+        }
+        respond(request.body(Body::empty()).unwrap(), state.clone()).await.unwrap()
+    }
+
+    fn basic(user: &str, password: &str) -> Option<String> {
+        Some(format!("Basic {}", base64::encode(format!("{}:{}", user, password))))
+    }
+
+    #[tokio::test]
+    async fn modules_only_match_whole_segments() {
+        let config = config("[pub]\npath = \"/srv/pub\"\n").await;
+        assert_eq!(resolve_path("pub", &config).unwrap().module, "pub");
+        assert_eq!(resolve_path("pub/a.txt", &config).unwrap().module, "pub");
+        assert!(resolve_path("public", &config).is_none());
+        assert!(resolve_path("public/a.txt", &config).is_none());
+        assert!(resolve_path("pu", &config).is_none());
+
+        assert_eq!(requested_module("/api/list/pub/a", &config).as_deref(), Some("pub"));
+        assert_eq!(requested_module("/search/pub/", &config).as_deref(), Some("pub"));
+        assert_eq!(requested_module("/public/a.txt", &config), None);
+        assert_eq!(requested_module("/", &config), None);
+    }
+
+    #[tokio::test]
+    async fn private_shares_accept_either_credentials() {
+        let dir = module_dir("auth", &["a.txt"]);
+        let config = config(&format!(
+            "web_auth = {{ kind = \"basic\", user = \"admin\", password = \"a\" }}\n\
+             [private]\npath = \"{}\"\nauth = {{ kind = \"basic\", user = \"me\", password = \"b\" }}\n",
+            dir.display())).await;
+        let state = Arc::new(WebState::new(config, None));
+
+        assert_eq!(get(&state, "/private/a.txt", basic("me", "b")).await.status(), StatusCode::OK);
+        assert_eq!(get(&state, "/private/a.txt", basic("admin", "a")).await.status(), StatusCode::OK);
+        assert_eq!(get(&state, "/private/a.txt", basic("me", "a")).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get(&state, "/private/a.txt", None).await.status(), StatusCode::UNAUTHORIZED);
+        // The share's credentials only open the share
+        assert_eq!(get(&state, "/", basic("me", "b")).await.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(get(&state, "/", basic("admin", "a")).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn cdn_shares_redirect_file_downloads() {
+        let dir = module_dir("cdn", &["a.txt"]);
+        std::fs::create_dir(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub/b.txt"), "b").unwrap();
+        let config = config(&format!("[pub]\npath = \"{}\"\ncdn_base = \"https://cdn.example.com/pub/\"\n", dir.display())).await;
+        let state = Arc::new(WebState::new(config, None));
+
+        let response = get(&state, "/pub/sub/b.txt", None).await;
+        assert_eq!(response.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(response.headers()[LOCATION], "https://cdn.example.com/pub/sub/b.txt");
+        // Listings are still served here
+        assert_eq!(get(&state, "/pub/", None).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn downloads_beyond_the_limit_are_turned_away() {
+        let dir = module_dir("limit", &["a.txt"]);
+        let config = config(&format!("[pub]\npath = \"{}\"\nmax_downloads = 2\n", dir.display())).await;
+        let state = Arc::new(WebState::new(config, None));
+
+        let first = get(&state, "/pub/a.txt", None).await;
+        let second = get(&state, "/pub/a.txt", None).await;
+        assert_eq!((first.status(), second.status()), (StatusCode::OK, StatusCode::OK));
+
+        let third = get(&state, "/pub/a.txt", None).await;
+        assert_eq!(third.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(third.headers()[RETRY_AFTER], "5");
+
+        // A download's slot is free again once its response is gone
+        drop(first);
+        assert_eq!(get(&state, "/pub/a.txt", None).await.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn links_keep_query_values_encoded() {
+        let query = parse_query(Some("q=a%22%3E%3Cscript%3E+b&sort=size\"><b&page=3"));
+        let next = page_query(&query, 4, DEFAULT_PER_PAGE);
+        assert_eq!(next, "?q=a%22%3E%3Cscript%3E%20b&amp;sort=size%22%3E%3Cb&amp;page=4");
+        let toggle = make_toggle(&query, "all", "hidden-toggle", "hidden files");
+        assert!(toggle.contains("href=\"?q=a%22%3E%3Cscript%3E%20b&amp;sort=size%22%3E%3Cb&amp;all=1\""), "{}", toggle);
+        // Carried over values still decode to what was searched for
+        assert_eq!(search_term(&parse_query(Some(&next[1..].replace("&amp;", "&")))).as_deref(), Some("a\"><script> b"));
+    }
+
+    #[tokio::test]
+    async fn archives_merge_paths_like_the_listing() {
+        let first = module_dir("archive-first", &["a.txt", ".hidden"]);
+        let second = module_dir("archive-second", &["a.txt", "b.txt"]);
+        std::fs::create_dir_all(first.join("sub")).unwrap();
+        std::fs::write(first.join("sub/x.txt"), "x").unwrap();
+        std::fs::create_dir_all(second.join("sub")).unwrap();
+        std::fs::write(second.join("sub/y.txt"), "y").unwrap();
+        std::fs::create_dir_all(second.join("only/deeper")).unwrap();
+        std::fs::write(second.join("a.txt"), "second").unwrap();
+        let config = config(&format!("[pub]\npath = [\"{}\", \"{}\"]\n", first.display(), second.display())).await;
+        let state = Arc::new(WebState::new(config, None));
+
+        let response = get(&state, "/pub/?download=tar", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let raw = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let mut names = Vec::new();
+        let mut entries = Archive::new(&raw[..]).entries().unwrap();
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().trim_end_matches('/').to_string();
+            if name.ends_with("a.txt") {
+                let mut content = String::new();
+                entry.read_to_string(&mut content).await.unwrap();
+                assert_eq!(content, "a.txt", "the first path's copy wins");
+            }
+            names.push(name);
+        }
+        let root = first.file_name().unwrap().to_string_lossy().to_string();
+        let mut expected: Vec<String> = ["", "/a.txt", "/b.txt", "/sub", "/sub/x.txt", "/sub/y.txt", "/only", "/only/deeper"]
+            .iter().map(|name| format!("{}{}", root, name)).collect();
+        names.sort();
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[tokio::test]
+    async fn downloads_are_only_hashed_once_they_have_a_slot() {
+        let dir = module_dir("hash-limit", &["a.txt", "b.txt"]);
+        let b = dir.join("b.txt");
+        // Files that were just written aren't cached, their hash could still change unnoticed
+        std::fs::File::options().write(true).open(&b).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+        let config = config(&format!("[pub]\npath = \"{}\"\nmax_downloads = 1\n", dir.display())).await;
+        let hash_algo = config.hash_algo;
+        let state = Arc::new(WebState::new(config, None));
+        let cached = || cached_hash(&b, &std::fs::metadata(&b).unwrap(), hash_algo);
+
+        let running = get(&state, "/pub/a.txt", None).await;
+        assert_eq!(get(&state, "/pub/b.txt", None).await.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(cached(), None);
+
+        drop(running);
+        assert_eq!(get(&state, "/pub/b.txt", None).await.status(), StatusCode::OK);
+        assert!(cached().is_some());
+    }
+
+    #[tokio::test]
+    async fn hashes_take_a_download_slot_unless_cached() {
+        let dir = module_dir("hash-slots", &["a.txt", "b.txt"]);
+        let b = dir.join("b.txt");
+        std::fs::File::options().write(true).open(&b).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600)).unwrap();
+        let config = config(&format!("[pub]\npath = \"{}\"\nmax_downloads = 1\n", dir.display())).await;
+        let state = Arc::new(WebState::new(config, None));
+        let hashing = ["/hash/pub/b.txt", "/pub/b.txt.b3sum", "/pub/?hashes=1", "/api/list/pub/?hashes=1"];
+
+        let running = get(&state, "/pub/a.txt", None).await;
+        for path in hashing {
+            assert_eq!(get(&state, path, None).await.status(), StatusCode::SERVICE_UNAVAILABLE, "{}", path);
+        }
+        assert_eq!(get(&state, "/api/list/pub/", None).await.status(), StatusCode::OK);
+
+        drop(running);
+        for path in hashing {
+            assert_eq!(get(&state, path, None).await.status(), StatusCode::OK, "{}", path);
+        }
+
+        // b.txt is cached now, so it doesn't need a slot anymore
+        let _running = get(&state, "/pub/a.txt", None).await;
+        assert_eq!(get(&state, "/hash/pub/b.txt", None).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn listings_escape_file_names() {
+        let dir = module_dir("escape", &["<img src=x onerror=alert(1)>.txt", "a\"b.txt"]);
+        let config = config(&format!("[pub]\npath = \"{}\"\n", dir.display())).await;
+        let state = Arc::new(WebState::new(config, None));
+
+        for path in ["/pub/", "/search/pub/?q=.txt"] {
+            let response = get(&state, path, None).await;
+            let body = String::from_utf8(hyper::body::to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap();
+            assert!(!body.contains("<img") && !body.contains("a\"b"), "{}", body);
+            assert!(body.contains("&lt;img src=x onerror=alert(1)&gt;.txt"), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn breadcrumbs_escape_path_segments() {
+        let config = config("").await;
+        let crumbs = make_breadcrumbs("/pub/<script>a\"/", &config);
+        assert!(crumbs.ends_with(" / <a href=\"/pub/&lt;script&gt;a&quot;/\">&lt;script&gt;a&quot;</a>"), "{}", crumbs);
+    }
+}
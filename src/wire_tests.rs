@@ -0,0 +1,225 @@
+// mirra (c) Nikolas Wipper 2022
+
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/. */
+
+//! Round trips every packet through an in-memory connection, to catch encoding bugs before they reach a peer
+
+use std::collections::HashSet;
+use std::io::{Cursor, ErrorKind};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+use crate::packet::*;
+
+/// How many random cases the fuzz-style tests try
+const CASES: usize = 200;
+
+/// Write [packet] into one end of an in-memory connection, returning the bytes that came out of the other end
+/// The connection buffers much less than most packets, so writing has to cope with partial writes
+async fn encode<T: Send>(packet: T) -> Vec<u8>
+    where DuplexStream: WriteAny<T> {
+    let (mut writer, mut reader) = duplex(7);
+    let write = async move {
+        let written = writer.write_any(packet).await.unwrap();
+        writer.shutdown().await.unwrap();
+        written
+    };
+    let read = async move {
+        let mut raw = Vec::new();
+        reader.read_to_end(&mut raw).await.unwrap();
+        raw
+    };
+    let (written, raw) = tokio::join!(write, read);
+    assert_eq!(written, raw.len(), "write_any reported a different size than it wrote");
+    raw
+}
+
+/// Read a [T] from [raw], which has to contain exactly one
+async fn decode<T>(raw: &[u8]) -> T
+    where Cursor<Vec<u8>>: ReadAny<T> {
+    let mut cursor = Cursor::new(raw.to_vec());
+    let packet = cursor.read_any().await.unwrap();
+    assert_eq!(cursor.position() as usize, raw.len(), "read_any left bytes behind");
+    packet
+}
+
+/// Send [packet] through an in-memory connection and read it back
+async fn roundtrip<T: Send>(packet: T) -> T
+    where DuplexStream: WriteAny<T>, Cursor<Vec<u8>>: ReadAny<T> {
+    decode(&encode(packet).await).await
+}
+
+/// Try to read a [T] from [raw], failing with the kind of error it ran into
+async fn try_decode<T>(raw: &[u8]) -> Result<T, ErrorKind>
+    where Cursor<Vec<u8>>: ReadAny<T> {
+    Cursor::new(raw.to_vec()).read_any().await.map_err(|e| e.kind())
+}
+
+fn random_string(rng: &mut StdRng) -> String {
+    let len = rng.gen_range(0..64);
+    (0..len).map(|_| rng.gen::<char>()).collect()
+}
+
+fn random_strings(rng: &mut StdRng) -> Vec<String> {
+    let len = rng.gen_range(0..16);
+    (0..len).map(|_| random_string(rng)).collect()
+}
+
+#[tokio::test]
+async fn empty_packets_have_no_body() {
+    assert!(encode(Ok::new()).await.is_empty());
+    assert!(encode(Close::new()).await.is_empty());
+    assert!(encode(NotFound::new()).await.is_empty());
+    assert!(encode(Heartbeat::new()).await.is_empty());
+    assert!(encode(BeginSync::new()).await.is_empty());
+    assert!(encode(EndSync::new()).await.is_empty());
+    assert!(encode(Skip::new()).await.is_empty());
+    assert!(encode(Multiplex::new()).await.is_empty());
+    assert!(encode(RequestSync::new()).await.is_empty());
+}
+
+#[tokio::test]
+async fn single_field_packets_roundtrip() {
+    assert_eq!(roundtrip(Handshake::new("music".to_string())).await.module, "music");
+    assert_eq!(roundtrip(Remove::new("a/b.txt".to_string())).await.path, "a/b.txt");
+    assert_eq!(roundtrip(MakeDir::new("a/b".to_string())).await.path, "a/b");
+    assert_eq!(roundtrip(Resume::new(u64::MAX - 1)).await.offset, u64::MAX - 1);
+    assert_eq!(roundtrip(ErrorMsg::new("no such module".to_string())).await.message, "no such module");
+    assert_eq!(roundtrip(RequestFile::new("c.txt".to_string())).await.path, "c.txt");
+    assert_eq!(roundtrip(Push::new("photos".to_string())).await.module, "photos");
+    assert_eq!(roundtrip(Want::new(vec!["x".to_string(), "y".to_string()])).await.paths, ["x", "y"]);
+    assert_eq!(roundtrip(RemoveMany::new(vec!["x".to_string()])).await.paths, ["x"]);
+    assert_eq!(roundtrip(Origin::new("fingerprint".to_string())).await.fingerprint, "fingerprint");
+
+    let features: Vec<String> = CAPABILITIES.iter().map(|f| f.to_string()).collect();
+    assert_eq!(roundtrip(Capabilities::new(features.clone())).await.features, features);
+}
+
+#[tokio::test]
+async fn multi_field_packets_keep_their_field_order() {
+    let header = roundtrip(FileHeader::new("a.txt".to_string(), 1234, "blake3:ab".to_string(), "cert".to_string())).await;
+    assert_eq!(header.path, "a.txt");
+    assert_eq!(header.size, 1234);
+    assert_eq!(header.hash, "blake3:ab");
+    assert_eq!(header.cert, "cert");
+
+    let rename = roundtrip(Rename::new("old".to_string(), "new".to_string())).await;
+    assert_eq!((rename.old.as_str(), rename.new.as_str()), ("old", "new"));
+
+    let rename = roundtrip(RenameMany::new(vec!["a".to_string(), "b".to_string()], vec!["c".to_string()])).await;
+    assert_eq!((rename.old, rename.new), (vec!["a".to_string(), "b".to_string()], vec!["c".to_string()]));
+
+    let manifest = roundtrip(Manifest::new(vec!["a".to_string()], vec!["sha256:00".to_string()])).await;
+    assert_eq!((manifest.paths, manifest.hashes), (vec!["a".to_string()], vec!["sha256:00".to_string()]));
+
+    let fetch = roundtrip(Fetch::new("music".to_string(), vec!["a".to_string(), String::new()])).await;
+    assert_eq!(fetch.module, "music");
+    assert_eq!(fetch.paths, ["a", ""]);
+
+    let hash = roundtrip(FileHash::new("blake3:cd".to_string(), "cert".to_string())).await;
+    assert_eq!((hash.hash.as_str(), hash.cert.as_str()), ("blake3:cd", "cert"));
+
+    let checksums = roundtrip(BlockChecksums::new(4096, vec![1, 2, 3, 255])).await;
+    assert_eq!(checksums.block_size, 4096);
+    assert_eq!(checksums.checksums, [1, 2, 3, 255]);
+}
+
+#[tokio::test]
+async fn file_header_wire_format() {
+    // Peers that don't negotiate any capabilities still expect exactly these fields
+    let raw = encode(FileHeader::new("ab".to_string(), 5, "h".to_string(), String::new())).await;
+    let expected = [
+        0, 0, 0, 2, b'a', b'b',
+        0, 0, 0, 0, 0, 0, 0, 5,
+        0, 0, 0, 1, b'h',
+        0, 0, 0, 0,
+    ];
+    assert_eq!(raw, expected);
+}
+
+#[test]
+fn packet_kinds_are_unique() {
+    let kinds = [
+        Ok::KIND as u8, Close::KIND as u8, Handshake::KIND as u8, NotFound::KIND as u8,
+        Heartbeat::KIND as u8, BeginSync::KIND as u8, EndSync::KIND as u8, FileHeader::KIND as u8,
+        Remove::KIND as u8, Rename::KIND as u8, Skip::KIND as u8, MakeDir::KIND as u8,
+        Resume::KIND as u8, ErrorMsg::KIND as u8, Manifest::KIND as u8, Want::KIND as u8,
+        Multiplex::KIND as u8, RequestSync::KIND as u8, RequestFile::KIND as u8, Fetch::KIND as u8,
+        Capabilities::KIND as u8, Push::KIND as u8, FileHash::KIND as u8, RemoveMany::KIND as u8,
+        RenameMany::KIND as u8, BlockChecksums::KIND as u8, Origin::KIND as u8,
+    ];
+    let unique: HashSet<_> = kinds.iter().collect();
+    assert_eq!(unique.len(), kinds.len());
+}
+
+#[tokio::test]
+async fn random_strings_roundtrip() {
+    let mut rng = StdRng::seed_from_u64(0x6d69727261);
+    for _ in 0..CASES {
+        let message = random_string(&mut rng);
+        assert_eq!(roundtrip(ErrorMsg::new(message.clone())).await.message, message);
+
+        let paths = random_strings(&mut rng);
+        let hashes = random_strings(&mut rng);
+        let manifest = roundtrip(Manifest::new(paths.clone(), hashes.clone())).await;
+        assert_eq!(manifest.paths, paths);
+        assert_eq!(manifest.hashes, hashes);
+
+        let len = rng.gen_range(0..512);
+        let checksums: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        assert_eq!(roundtrip(BlockChecksums::new(rng.gen(), checksums.clone())).await.checksums, checksums);
+    }
+}
+
+#[tokio::test]
+async fn large_string_roundtrips() {
+    let path = "ä".repeat(1 << 19);
+    assert_eq!(roundtrip(Remove::new(path.clone())).await.path, path);
+}
+
+#[tokio::test]
+async fn truncated_packets_fail() {
+    let mut rng = StdRng::seed_from_u64(0x74727563);
+    for _ in 0..CASES / 10 {
+        let raw = encode(Fetch::new(random_string(&mut rng), random_strings(&mut rng))).await;
+        for end in 0..raw.len() {
+            assert_eq!(try_decode::<Fetch>(&raw[..end]).await.err(), Some(ErrorKind::UnexpectedEof));
+        }
+    }
+}
+
+#[tokio::test]
+async fn oversized_lengths_fail_without_allocating() {
+    // Each of these claims about 4 GiB of data, but only a few bytes follow
+    let raw = [0xFF, 0xFF, 0xFF, 0xFF, b'a', b'b'];
+    assert_eq!(try_decode::<ErrorMsg>(&raw).await.err(), Some(ErrorKind::UnexpectedEof));
+    assert_eq!(try_decode::<Vec<u8>>(&raw).await.err(), Some(ErrorKind::UnexpectedEof));
+    assert_eq!(try_decode::<Want>(&raw).await.err(), Some(ErrorKind::UnexpectedEof));
+}
+
+#[tokio::test]
+async fn invalid_utf8_fails() {
+    let raw = [0, 0, 0, 2, 0xC3, 0x28];
+    assert_eq!(try_decode::<ErrorMsg>(&raw).await.err(), Some(ErrorKind::InvalidData));
+}
+
+#[tokio::test]
+async fn random_bytes_never_panic() {
+    let mut rng = StdRng::seed_from_u64(0x6e6f6973);
+    for _ in 0..CASES {
+        let len = rng.gen_range(0..64);
+        let mut raw: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+        // Keep the announced lengths small now and then, so some of them actually decode
+        if raw.len() >= 4 && rng.gen() {
+            raw[..3].fill(0);
+        }
+        let _ = try_decode::<FileHeader>(&raw).await;
+        let _ = try_decode::<Manifest>(&raw).await;
+        let _ = try_decode::<RenameMany>(&raw).await;
+        let _ = try_decode::<BlockChecksums>(&raw).await;
+    }
+}